@@ -0,0 +1,184 @@
+//! Who may use the bot
+//!
+//! Users and chats listed in `DENIED_USER_IDS` and `DENIED_CHAT_IDS` are never answered.
+//! When `ALLOWED_USER_IDS` or `ALLOWED_CHAT_IDS` is set, only the listed users, and everyone
+//! in the listed chats, are answered; otherwise everyone is. The bot administrators in
+//! `BOT_ADMIN_IDS` are always answered and may change the settings of any chat, like the
+//! chat's own administrators, which are looked up with `getChatAdministrators`. Refused
+//! messages get a polite reply unless `ACCESS_DENIED_REPLIES` is `false`.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use teloxide::prelude::*;
+use teloxide::types::{Chat, User};
+
+/// How long the administrators of a chat are remembered before being looked up again
+const CHAT_ADMINS_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The administrators of a chat, as last looked up
+#[derive(Debug)]
+struct ChatAdmins {
+    fetched: Instant,
+    user_ids: HashSet<UserId>,
+}
+
+/// What a user may do in a chat, for limits that differ between them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// One of the bot's administrators
+    BotAdmin,
+    /// An administrator of the group the message was sent in
+    ChatAdmin,
+    /// Anyone else, including everyone in private chats
+    Member,
+}
+
+/// The users and chats the bot serves, and who administers it
+#[derive(Debug)]
+pub struct AccessPolicy {
+    allowed_users: HashSet<UserId>,
+    allowed_chats: HashSet<ChatId>,
+    denied_users: HashSet<UserId>,
+    denied_chats: HashSet<ChatId>,
+    bot_admins: HashSet<UserId>,
+    /// Whether refused messages get a reply instead of being ignored
+    pub reply_when_denied: bool,
+    chat_admins: Mutex<HashMap<ChatId, ChatAdmins>>,
+}
+
+impl AccessPolicy {
+    /// Read the policy from `ALLOWED_USER_IDS`, `ALLOWED_CHAT_IDS`, `DENIED_USER_IDS`,
+    /// `DENIED_CHAT_IDS`, `BOT_ADMIN_IDS` and `ACCESS_DENIED_REPLIES`
+    pub fn from_env() -> Self {
+        Self {
+            allowed_users: id_list("ALLOWED_USER_IDS")
+                .into_iter()
+                .map(UserId)
+                .collect(),
+            allowed_chats: id_list("ALLOWED_CHAT_IDS")
+                .into_iter()
+                .map(ChatId)
+                .collect(),
+            denied_users: id_list("DENIED_USER_IDS").into_iter().map(UserId).collect(),
+            denied_chats: id_list("DENIED_CHAT_IDS").into_iter().map(ChatId).collect(),
+            bot_admins: id_list("BOT_ADMIN_IDS").into_iter().map(UserId).collect(),
+            reply_when_denied: env::var("ACCESS_DENIED_REPLIES")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(true),
+            chat_admins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if a user is one of the bot's administrators
+    pub fn is_bot_admin(&self, user_id: UserId) -> bool {
+        self.bot_admins.contains(&user_id)
+    }
+
+    /// Check if the bot serves a user in a chat
+    ///
+    /// Messages without a sender, such as those of anonymous administrators, are judged by
+    /// their chat alone.
+    pub fn allows(&self, chat: &Chat, user: Option<&User>) -> bool {
+        let user_id = user.map(|user| user.id);
+        if user_id.is_some_and(|user_id| self.is_bot_admin(user_id)) {
+            return true;
+        }
+        if self.denied_chats.contains(&chat.id)
+            || user_id.is_some_and(|user_id| self.denied_users.contains(&user_id))
+        {
+            return false;
+        }
+        if self.allowed_users.is_empty() && self.allowed_chats.is_empty() {
+            return true;
+        }
+        self.allowed_chats.contains(&chat.id)
+            || user_id.is_some_and(|user_id| self.allowed_users.contains(&user_id))
+    }
+
+    /// Find the role of a message's sender
+    pub async fn role(&self, bot: &Bot, msg: &Message) -> ResponseResult<Role> {
+        // Anonymous administrators send their messages on behalf of the chat itself.
+        if msg
+            .sender_chat
+            .as_ref()
+            .is_some_and(|chat| chat.id == msg.chat.id)
+        {
+            return Ok(Role::ChatAdmin);
+        }
+        match &msg.from {
+            Some(user) => self.user_role(bot, &msg.chat, user.id).await,
+            None => Ok(Role::Member),
+        }
+    }
+
+    /// Find out a user's role in a chat
+    pub async fn user_role(&self, bot: &Bot, chat: &Chat, user_id: UserId) -> ResponseResult<Role> {
+        if self.is_bot_admin(user_id) {
+            Ok(Role::BotAdmin)
+        } else if !chat.is_private() && self.is_chat_admin(bot, chat, user_id).await? {
+            Ok(Role::ChatAdmin)
+        } else {
+            Ok(Role::Member)
+        }
+    }
+
+    /// Check if a user may change the settings of a chat: in private chats everyone, in
+    /// groups the chat's administrators and the bot's
+    pub async fn is_chat_admin(
+        &self,
+        bot: &Bot,
+        chat: &Chat,
+        user_id: UserId,
+    ) -> ResponseResult<bool> {
+        if chat.is_private() || self.is_bot_admin(user_id) {
+            return Ok(true);
+        }
+
+        if let Some(admins) = self
+            .chat_admins
+            .lock()
+            .expect("chat admins lock poisoned")
+            .get(&chat.id)
+            .filter(|admins| admins.fetched.elapsed() < CHAT_ADMINS_TTL)
+        {
+            return Ok(admins.user_ids.contains(&user_id));
+        }
+
+        let user_ids: HashSet<UserId> = bot
+            .get_chat_administrators(chat.id)
+            .await?
+            .into_iter()
+            .map(|member| member.user.id)
+            .collect();
+        let is_admin = user_ids.contains(&user_id);
+        self.chat_admins
+            .lock()
+            .expect("chat admins lock poisoned")
+            .insert(
+                chat.id,
+                ChatAdmins {
+                    fetched: Instant::now(),
+                    user_ids,
+                },
+            );
+        Ok(is_admin)
+    }
+}
+
+/// Read a comma-separated list of Telegram IDs from an environment variable
+fn id_list<T: std::str::FromStr>(name: &str) -> Vec<T> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .unwrap_or_else(|_| panic!("Invalid {}: `{}` is not an ID", name, id))
+        })
+        .collect()
+}
@@ -0,0 +1,415 @@
+//! Fitting conversations into the model's context window
+//!
+//! Tokens are counted with the model's tiktoken encoding where it is known, by its name or
+//! its `tokenizer` in `MODELS_FILE`, and estimated from the text's length otherwise. Before a
+//! request is sent, its oldest turns are dropped until it fits the model's `context_window`
+//! minus `CONTEXT_RESERVED_TOKENS` kept free for the answer.
+//!
+//! With `CONTEXT_SUMMARY` on, turns that fall out of the context are not lost: after the
+//! answer, the model compresses them, together with the previous summary, into a rolling
+//! summary of the conversation, which is sent along with the system prompt from then on.
+
+use std::env;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use teloxide::types::MessageId;
+use tiktoken_rs::CoreBPE;
+use tiktoken_rs::tokenizer::{self, Tokenizer};
+
+use crate::history::{self, ConversationKey, Turn};
+use crate::llm::{
+    self, ChatMessage, ChatRequest, LlmError, RetryPolicy, Role, StreamEvent, ToolChoice, Usage,
+};
+use crate::models::{ModelInfo, ModelRegistry};
+use crate::storage::{Storage, StorageResult};
+
+/// Tokens every message costs on top of its content, for the role and separators
+const MESSAGE_OVERHEAD: usize = 4;
+
+/// Tokens counted for an attached image, a typical cost at high detail
+const IMAGE_TOKENS: usize = 1000;
+
+/// The prefix of the chat settings holding the summaries of conversations
+const SUMMARY_SETTING_PREFIX: &str = "summary";
+
+/// Instructions for summarizing a conversation
+const SUMMARY_PROMPT: &str = "Summarize the conversation below for your own later reference. \
+    Keep names, facts, decisions, open questions and the user's preferences; leave out \
+    pleasantries. If there is an earlier summary, merge it in. Reply with the summary only, \
+    in the language of the conversation, at most 300 words.";
+
+/// How requests are fitted into the context window
+#[derive(Clone, Copy, Debug)]
+pub struct ContextConfig {
+    /// Tokens kept free for the answer
+    pub reserved_tokens: usize,
+    /// Whether turns dropped from the context are summarized
+    pub summarize: bool,
+}
+
+impl ContextConfig {
+    /// Read the configuration from `CONTEXT_RESERVED_TOKENS` and `CONTEXT_SUMMARY`
+    pub fn from_env() -> Self {
+        Self {
+            reserved_tokens: env::var("CONTEXT_RESERVED_TOKENS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1024),
+            summarize: env::var("CONTEXT_SUMMARY")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Counts the tokens of texts the way a model does, or close to it
+#[derive(Clone, Copy)]
+pub enum TokenCounter {
+    /// A tiktoken encoding
+    Tiktoken(&'static CoreBPE),
+    /// An estimate from the number of characters
+    Estimate { chars_per_token: f64 },
+}
+
+impl TokenCounter {
+    /// The counter for a model: its configured tokenizer, the encoding tiktoken knows for
+    /// its name, or an estimate
+    pub fn for_model(model: &ModelInfo) -> Self {
+        let configured = model.tokenizer.as_deref().and_then(|name| match name {
+            "o200k_base" => Some(Tokenizer::O200kBase),
+            "cl100k_base" => Some(Tokenizer::Cl100kBase),
+            "p50k_base" => Some(Tokenizer::P50kBase),
+            "r50k_base" => Some(Tokenizer::R50kBase),
+            _ => None,
+        });
+        let estimate = Self::Estimate {
+            chars_per_token: model.chars_per_token.unwrap_or(4.0),
+        };
+        if model.tokenizer.as_deref() == Some("estimate") {
+            return estimate;
+        }
+
+        match configured.or_else(|| tokenizer::get_tokenizer(&model.name)) {
+            Some(Tokenizer::O200kBase) => Self::Tiktoken(tiktoken_rs::o200k_base_singleton()),
+            Some(Tokenizer::Cl100kBase) => Self::Tiktoken(tiktoken_rs::cl100k_base_singleton()),
+            Some(Tokenizer::P50kBase) => Self::Tiktoken(tiktoken_rs::p50k_base_singleton()),
+            Some(Tokenizer::P50kEdit) => Self::Tiktoken(tiktoken_rs::p50k_edit_singleton()),
+            Some(Tokenizer::R50kBase | Tokenizer::Gpt2) => {
+                Self::Tiktoken(tiktoken_rs::r50k_base_singleton())
+            }
+            None => estimate,
+        }
+    }
+
+    /// Count the tokens of a text
+    pub fn count(&self, text: &str) -> usize {
+        match self {
+            Self::Tiktoken(bpe) => bpe.encode_with_special_tokens(text).len(),
+            Self::Estimate { chars_per_token } => {
+                (text.chars().count() as f64 / chars_per_token).ceil() as usize
+            }
+        }
+    }
+
+    /// Count the tokens of a message, with its images, tool calls and tool results
+    pub fn count_message(&self, message: &ChatMessage) -> usize {
+        let tool_calls: usize = message
+            .tool_calls
+            .iter()
+            .map(|call| self.count(&call.name) + self.count(&call.arguments.to_string()))
+            .sum();
+        let tool_results: usize = message
+            .tool_results
+            .iter()
+            .map(|result| MESSAGE_OVERHEAD + self.count(&result.content))
+            .sum();
+        MESSAGE_OVERHEAD
+            + self.count(&message.content)
+            + message.images.len() * IMAGE_TOKENS
+            + tool_calls
+            + tool_results
+    }
+
+    /// Count the tokens of a whole request, tools included
+    pub fn count_request(&self, request: &ChatRequest) -> usize {
+        let system = request
+            .system
+            .as_deref()
+            .map_or(0, |system| MESSAGE_OVERHEAD + self.count(system));
+        let tools: usize = request
+            .tools
+            .iter()
+            .map(|tool| {
+                self.count(&tool.name)
+                    + self.count(&tool.description)
+                    + self.count(&tool.parameters.to_string())
+            })
+            .sum();
+        let messages: usize = request
+            .messages
+            .iter()
+            .map(|message| self.count_message(message))
+            .sum();
+        system + tools + messages
+    }
+}
+
+/// Drop the oldest messages of a request until it fits the model's context window with
+/// `reserved_tokens` to spare, returning how many were dropped
+///
+/// The newest message is always kept, and the request never starts with an answer. Models
+/// without a known context window are left alone.
+pub fn fit_request(request: &mut ChatRequest, model: &ModelInfo, reserved_tokens: usize) -> usize {
+    let Some(context_window) = model.context_window else {
+        return 0;
+    };
+    let budget = (context_window as usize).saturating_sub(reserved_tokens);
+    let counter = TokenCounter::for_model(model);
+
+    let mut total = counter.count_request(request);
+    let mut dropped = 0;
+    while dropped + 1 < request.messages.len()
+        && (total > budget || request.messages[dropped].role != Role::User)
+    {
+        total -= counter.count_message(&request.messages[dropped]);
+        dropped += 1;
+    }
+    request.messages.drain(..dropped);
+
+    if dropped > 0 {
+        log::info!(
+            "Dropped {} messages to fit the context window of {} ({} of {} tokens)",
+            dropped,
+            model.name,
+            total,
+            budget
+        );
+    }
+    dropped
+}
+
+/// The summary of the turns of a conversation that no longer fit its context
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    /// The summary text
+    pub text: String,
+    /// The newest message the summary covers
+    pub covered_until: i32,
+}
+
+impl Summary {
+    /// Count the turns of a conversation, oldest first, that the summary covers
+    pub fn covered(&self, turns: &[Turn]) -> usize {
+        let covered_until = MessageId(self.covered_until);
+        let before = history::turns_before(turns, covered_until);
+        match turns.get(before) {
+            Some(turn) if turn.message_id == covered_until => before + 1,
+            _ => before,
+        }
+    }
+
+    /// Add the summary to a system prompt
+    pub fn extend_system_prompt(&self, system: Option<String>) -> String {
+        let summary = format!("Summary of the earlier conversation:\n{}", self.text);
+        match system {
+            Some(system) => format!("{}\n\n{}", system, summary),
+            None => summary,
+        }
+    }
+}
+
+/// The setting key of a conversation's summary
+fn summary_key(key: ConversationKey) -> String {
+    match key.thread_id {
+        Some(thread_id) => format!("{}:{}", SUMMARY_SETTING_PREFIX, thread_id),
+        None => SUMMARY_SETTING_PREFIX.to_string(),
+    }
+}
+
+/// Load the summary of a conversation, if there is one
+pub fn load_summary(storage: &dyn Storage, key: ConversationKey) -> StorageResult<Option<Summary>> {
+    let value = storage.chat_setting(key.chat_id, &summary_key(key))?;
+    Ok(value.and_then(|value| match serde_json::from_str(&value) {
+        Ok(summary) => Some(summary),
+        Err(error) => {
+            log::warn!("Ignoring invalid conversation summary: {}", error);
+            None
+        }
+    }))
+}
+
+/// Store the summary of a conversation
+pub fn save_summary(
+    storage: &dyn Storage,
+    key: ConversationKey,
+    summary: &Summary,
+) -> StorageResult<()> {
+    let value = serde_json::to_string(summary).expect("summaries serialize");
+    storage.set_chat_setting(key.chat_id, &summary_key(key), &value)
+}
+
+/// Forget the summary of a conversation
+pub fn clear_summary(storage: &dyn Storage, key: ConversationKey) -> StorageResult<()> {
+    storage.delete_chat_setting(key.chat_id, &summary_key(key))
+}
+
+/// Let the model merge turns, oldest first, into the previous summary of a conversation
+///
+/// Returns the new summary and the tokens spent on it.
+pub async fn summarize(
+    models: &ModelRegistry,
+    model: &ModelInfo,
+    previous: Option<&Summary>,
+    turns: &[Turn],
+    retry_policy: RetryPolicy,
+) -> Result<(Summary, Option<Usage>), LlmError> {
+    let last = turns.last().expect("there are turns to summarize");
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Earlier summary:\n{}\n\n", previous.text));
+    }
+    transcript.push_str("Conversation:\n");
+    for turn in turns {
+        let speaker = match turn.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        };
+        transcript.push_str(&format!("{}: {}\n", speaker, turn.content));
+    }
+
+    let request = ChatRequest {
+        model: model.name.clone(),
+        system: Some(SUMMARY_PROMPT.to_string()),
+        messages: vec![ChatMessage::text(Role::User, transcript)],
+        tools: Vec::new(),
+        tool_choice: ToolChoice::None,
+    };
+    let provider = models.provider(model);
+    let mut stream = llm::stream_with_retries(&*provider, &request, retry_policy).await?;
+
+    let mut text = String::new();
+    let mut usage = None;
+    while let Some(event) = stream.next().await {
+        match event? {
+            StreamEvent::Delta(delta) => text.push_str(&delta),
+            StreamEvent::Usage(total) => usage = Some(total),
+            StreamEvent::ToolCall(_) | StreamEvent::Truncated => {}
+        }
+    }
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(LlmError::EmptyResponse);
+    }
+
+    let summary = Summary {
+        text: text.to_string(),
+        covered_until: last.message_id.0,
+    };
+    Ok((summary, usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::llm::ToolSpec;
+
+    /// A model counting one token per character, so every message of `MESSAGE` costs 10
+    fn model(context_window: Option<u32>) -> ModelInfo {
+        ModelInfo {
+            name: "test".to_string(),
+            alias: None,
+            provider: None,
+            context_window,
+            prompt_price: None,
+            completion_price: None,
+            vision: false,
+            tokenizer: Some("estimate".to_string()),
+            chars_per_token: Some(1.0),
+        }
+    }
+
+    const MESSAGE: &str = "xxxxxx";
+
+    /// A request with messages of the given roles, oldest first
+    fn request_of(roles: &[Role]) -> ChatRequest {
+        ChatRequest {
+            model: "test".to_string(),
+            system: None,
+            messages: roles
+                .iter()
+                .map(|&role| ChatMessage::text(role, MESSAGE))
+                .collect(),
+            tools: Vec::new(),
+            tool_choice: ToolChoice::Auto,
+        }
+    }
+
+    const EXCHANGES: &[Role] = &[
+        Role::User,
+        Role::Assistant,
+        Role::User,
+        Role::Assistant,
+        Role::User,
+    ];
+
+    #[test]
+    fn keeps_requests_that_fit() {
+        let mut request = request_of(EXCHANGES);
+        assert_eq!(fit_request(&mut request, &model(Some(60)), 10), 0);
+        assert_eq!(request.messages.len(), 5);
+    }
+
+    #[test]
+    fn drops_the_oldest_exchanges() {
+        let mut request = request_of(EXCHANGES);
+        // 50 tokens in 35: the answer to the prompt dropped goes too.
+        assert_eq!(fit_request(&mut request, &model(Some(45)), 10), 2);
+        assert_eq!(request.messages.len(), 3);
+        assert_eq!(request.messages[0].role, Role::User);
+    }
+
+    #[test]
+    fn never_drops_the_newest_message() {
+        let mut request = request_of(EXCHANGES);
+        assert_eq!(fit_request(&mut request, &model(Some(5)), 0), 4);
+        assert_eq!(request.messages.len(), 1);
+
+        // Not even when the reserved tokens exceed the window
+        let mut request = request_of(&[Role::User]);
+        assert_eq!(fit_request(&mut request, &model(Some(5)), 100), 0);
+        assert_eq!(request.messages.len(), 1);
+    }
+
+    #[test]
+    fn never_starts_with_an_answer() {
+        let mut request = request_of(&[Role::Assistant, Role::User, Role::Assistant, Role::User]);
+        assert_eq!(fit_request(&mut request, &model(Some(1000)), 0), 1);
+        assert_eq!(request.messages[0].role, Role::User);
+    }
+
+    #[test]
+    fn counts_the_system_prompt_and_tools() {
+        let mut with_system = request_of(EXCHANGES);
+        with_system.system = Some("x".repeat(16));
+        assert_eq!(fit_request(&mut with_system, &model(Some(60)), 10), 2);
+
+        let mut with_tools = request_of(EXCHANGES);
+        with_tools.tools.push(ToolSpec {
+            name: "tool".to_string(),
+            description: "x".repeat(10),
+            parameters: json!({}),
+        });
+        assert_eq!(fit_request(&mut with_tools, &model(Some(60)), 10), 2);
+    }
+
+    #[test]
+    fn leaves_models_without_a_context_window_alone() {
+        let mut request = request_of(EXCHANGES);
+        assert_eq!(fit_request(&mut request, &model(None), 1_000_000), 0);
+        assert_eq!(request.messages.len(), 5);
+    }
+}
@@ -0,0 +1,116 @@
+//! Buttons under answers
+//!
+//! While an answer streams in, its reply carries a "Stop" button that ends it where it is.
+//! Finished answers carry "Regenerate", answering the question anew in place of the answer,
+//! and "Continue" when the answer was cut off at the token limit or stopped, asking the
+//! model for the rest. The buttons answer to whoever asked and to chat administrators.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use teloxide::types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageId};
+use tokio::sync::Notify;
+
+/// Prefix of the callback data of the answer buttons
+pub const CALLBACK_PREFIX: &str = "answer:";
+
+/// What a button under an answer does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// End an answer that is streaming in
+    Stop,
+    /// Answer the question again
+    Regenerate,
+    /// Go on with an incomplete answer
+    Continue,
+}
+
+impl Action {
+    /// Read the action from the callback data of a button
+    pub fn parse(data: &str) -> Option<Self> {
+        match data.strip_prefix(CALLBACK_PREFIX)? {
+            "stop" => Some(Self::Stop),
+            "regenerate" => Some(Self::Regenerate),
+            "continue" => Some(Self::Continue),
+            _ => None,
+        }
+    }
+
+    fn button(self) -> InlineKeyboardButton {
+        let (label, name) = match self {
+            Self::Stop => ("⏹ Stop", "stop"),
+            Self::Regenerate => ("🔄 Regenerate", "regenerate"),
+            Self::Continue => ("➡️ Continue", "continue"),
+        };
+        InlineKeyboardButton::callback(label, format!("{}{}", CALLBACK_PREFIX, name))
+    }
+}
+
+/// The buttons of an answer that is streaming in
+pub fn streaming_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[Action::Stop.button()]])
+}
+
+/// The buttons of a finished answer, offering to continue it when it is incomplete
+pub fn answer_keyboard(incomplete: bool) -> InlineKeyboardMarkup {
+    let mut row = vec![Action::Regenerate.button()];
+    if incomplete {
+        row.push(Action::Continue.button());
+    }
+    InlineKeyboardMarkup::new([row])
+}
+
+/// The answers streaming in, by their reply, so they can be stopped
+#[derive(Debug, Default)]
+pub struct ActiveAnswers {
+    answers: Mutex<HashMap<(ChatId, MessageId), Arc<Notify>>>,
+}
+
+/// The stop signal of an answer streaming in, forgotten when dropped
+#[derive(Debug)]
+pub struct StopSignal {
+    answers: Arc<ActiveAnswers>,
+    key: (ChatId, MessageId),
+    notify: Arc<Notify>,
+}
+
+impl ActiveAnswers {
+    /// Register an answer streaming into a reply
+    pub fn start(self: &Arc<Self>, chat_id: ChatId, reply_id: MessageId) -> StopSignal {
+        let notify = Arc::new(Notify::new());
+        self.answers
+            .lock()
+            .unwrap()
+            .insert((chat_id, reply_id), Arc::clone(&notify));
+        StopSignal {
+            answers: Arc::clone(self),
+            key: (chat_id, reply_id),
+            notify,
+        }
+    }
+
+    /// Stop the answer streaming into a reply, returning whether there is one
+    pub fn stop(&self, chat_id: ChatId, reply_id: MessageId) -> bool {
+        match self.answers.lock().unwrap().get(&(chat_id, reply_id)) {
+            Some(notify) => {
+                // A stop while no part is being received is kept for the next one.
+                notify.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl StopSignal {
+    /// Wait until the answer is stopped
+    pub async fn stopped(&self) {
+        self.notify.notified().await;
+    }
+}
+
+impl Drop for StopSignal {
+    fn drop(&mut self) {
+        self.answers.answers.lock().unwrap().remove(&self.key);
+    }
+}
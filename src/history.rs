@@ -0,0 +1,256 @@
+//! Per-chat conversation memory
+//!
+//! Builds the context sent along with each request from the stored turns of a conversation,
+//! so that follow-up questions reach the model together with the exchange they refer to.
+//! Turns are also looked up by message id, so a reply chain can be walked back to its root.
+
+use std::env;
+use std::sync::Arc;
+
+use teloxide::types::{ChatId, Message, MessageId, ThreadId};
+
+use crate::context_window::TokenCounter;
+use crate::llm::Role;
+use crate::storage::{Storage, StorageResult};
+
+/// How many turns beyond the window are looked at for turns that fell out of it
+const DROPPED_LOOKBACK: usize = 10;
+
+/// Identifies a conversation: a chat, narrowed to a forum topic when there is one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConversationKey {
+    /// The chat the conversation happens in
+    pub chat_id: ChatId,
+    /// The forum topic, for supergroups with topics enabled
+    pub thread_id: Option<ThreadId>,
+}
+
+impl ConversationKey {
+    /// Build the conversation key for an incoming message
+    pub fn from_message(msg: &Message) -> Self {
+        // Outside forums `thread_id` also marks plain reply threads, which must share the chat context.
+        let thread_id = if msg.is_topic_message {
+            msg.thread_id
+        } else {
+            None
+        };
+
+        Self {
+            chat_id: msg.chat.id,
+            thread_id,
+        }
+    }
+}
+
+/// A single message in a conversation
+#[derive(Clone, Debug)]
+pub struct Turn {
+    /// Who wrote the message
+    pub role: Role,
+    /// The message text as sent to or received from the model
+    pub content: String,
+    /// The Telegram message carrying this turn
+    pub message_id: MessageId,
+    /// The message this one replies to, if any
+    pub reply_to: Option<MessageId>,
+}
+
+/// Limits on how much history is sent along with each request
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryWindow {
+    /// Maximum number of prior turns (user and assistant messages) per request
+    pub max_turns: usize,
+    /// Approximate token budget for the prior turns of a request
+    pub max_tokens: usize,
+}
+
+impl HistoryWindow {
+    /// Read the window from `HISTORY_MAX_TURNS` and `HISTORY_MAX_TOKENS`
+    pub fn from_env() -> Self {
+        let max_turns = env::var("HISTORY_MAX_TURNS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(20);
+        let max_tokens = env::var("HISTORY_MAX_TOKENS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(4000);
+
+        Self {
+            max_turns,
+            max_tokens,
+        }
+    }
+}
+
+/// Conversation memory on top of the persistent storage
+#[derive(Debug)]
+pub struct ConversationHistory {
+    window: HistoryWindow,
+    storage: Arc<dyn Storage>,
+}
+
+impl ConversationHistory {
+    /// Create the conversation memory with the given window
+    pub fn new(window: HistoryWindow, storage: Arc<dyn Storage>) -> Self {
+        Self { window, storage }
+    }
+
+    /// Get the prior turns of a conversation that fit into the configured window, oldest first
+    ///
+    /// Tokens are counted with the counter of the model the turns are sent to.
+    pub fn context(&self, key: ConversationKey, counter: &TokenCounter) -> Vec<Turn> {
+        match self.storage.recent_messages(key, self.window.max_turns) {
+            Ok(turns) => self.fit_window(turns.into_iter(), counter),
+            Err(error) => {
+                log::error!("Failed to load conversation history: {}", error);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Get the reply chain ending at `parent` (inclusive) that fits into the window, oldest first
+    ///
+    /// The chain is followed for as long as the parent messages are known, so it is empty when
+    /// `parent` itself has never been seen.
+    pub fn reply_chain(
+        &self,
+        chat_id: ChatId,
+        parent: MessageId,
+        counter: &TokenCounter,
+    ) -> Vec<Turn> {
+        let lookup = |message_id| match self.storage.message(chat_id, message_id) {
+            Ok(turn) => turn,
+            Err(error) => {
+                log::error!("Failed to load reply chain: {}", error);
+                None
+            }
+        };
+        let chain = std::iter::successors(lookup(parent), |turn| turn.reply_to.and_then(lookup));
+
+        self.fit_window(chain, counter)
+    }
+
+    /// Get the turns of a conversation that fell out of the window before `context`, oldest
+    /// first
+    ///
+    /// Only a few turns beyond the window are looked at. Those are the turns dropped since the
+    /// previous request, as each request adds just a prompt and an answer.
+    pub fn dropped(&self, key: ConversationKey, context: &[Turn]) -> Vec<Turn> {
+        if self.window.max_turns == 0 {
+            return Vec::new();
+        }
+        match self
+            .storage
+            .recent_messages(key, self.window.max_turns + DROPPED_LOOKBACK)
+        {
+            Ok(mut turns) => {
+                turns.reverse();
+                if let Some(oldest) = context.first() {
+                    turns.truncate(turns_before(&turns, oldest.message_id));
+                }
+                turns
+            }
+            Err(error) => {
+                log::error!("Failed to load conversation history: {}", error);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Replace an answer by a new one to the same prompt, in the old answer's place
+    pub fn replace_answer(&self, key: ConversationKey, previous: MessageId, answer: Turn) {
+        let result = match self.storage.replace_message(key.chat_id, previous, &answer) {
+            Ok(true) => Ok(()),
+            // The old answer is gone, so the new one goes last.
+            Ok(false) => self.storage.save_message(key, &answer),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            log::error!("Failed to save conversation turn: {}", error);
+        }
+    }
+
+    /// Forget all turns of a conversation, returning how many there were
+    pub fn reset(&self, key: ConversationKey) -> StorageResult<usize> {
+        self.storage.delete_messages(key)
+    }
+
+    /// Forget the exchange a message belongs to: a prompt and the answer to it
+    ///
+    /// Returns how many turns were forgotten, none when the message was never seen.
+    pub fn forget_exchange(&self, chat_id: ChatId, message_id: MessageId) -> StorageResult<usize> {
+        let Some(turn) = self.storage.message(chat_id, message_id)? else {
+            return Ok(0);
+        };
+        let (prompt, answer) = match turn.role {
+            Role::User => {
+                let answer = self.storage.answer(chat_id, turn.message_id)?;
+                (
+                    Some(turn.message_id),
+                    answer.map(|answer| answer.message_id),
+                )
+            }
+            Role::Assistant => (turn.reply_to, Some(turn.message_id)),
+        };
+
+        let mut forgotten = 0;
+        for message_id in prompt.into_iter().chain(answer) {
+            if self.storage.delete_message(chat_id, message_id)? {
+                forgotten += 1;
+            }
+        }
+        Ok(forgotten)
+    }
+
+    /// Keep the newest turns (given newest first) that fit the window, returned oldest first
+    fn fit_window(
+        &self,
+        newest_first: impl Iterator<Item = Turn>,
+        counter: &TokenCounter,
+    ) -> Vec<Turn> {
+        // Walk backwards from the newest turn until either budget is exhausted.
+        let mut tokens = 0;
+        let mut context: Vec<Turn> = newest_first
+            .take(self.window.max_turns)
+            .take_while(|turn| {
+                tokens += counter.count(&turn.content);
+                tokens <= self.window.max_tokens
+            })
+            .collect();
+        context.reverse();
+
+        // Never open the context with a dangling assistant answer.
+        let first_user = context
+            .iter()
+            .position(|turn| turn.role == Role::User)
+            .unwrap_or(context.len());
+        context.drain(..first_user);
+
+        context
+    }
+
+    /// Record a completed exchange
+    pub fn record_exchange(&self, key: ConversationKey, prompt: Turn, answer: Turn) {
+        for turn in [prompt, answer] {
+            if let Err(error) = self.storage.save_message(key, &turn) {
+                log::error!("Failed to save conversation turn: {}", error);
+            }
+        }
+    }
+}
+
+/// Count the turns of a conversation, oldest first, that come before the turn of a message
+///
+/// Turns are in the order they were saved, which message ids don't follow once an older
+/// answer is regenerated. Message ids are only compared when the message is not among the
+/// turns.
+pub fn turns_before(turns: &[Turn], message_id: MessageId) -> usize {
+    match turns.iter().position(|turn| turn.message_id == message_id) {
+        Some(index) => index,
+        None => turns
+            .iter()
+            .take_while(|turn| turn.message_id.0 < message_id.0)
+            .count(),
+    }
+}
@@ -0,0 +1,255 @@
+//! Translations of the bot's replies
+//!
+//! Replies explaining failures, refusals and limits are shown in the language of the user's
+//! Telegram client when a translation exists, and in English otherwise.
+
+use teloxide::types::User;
+
+use crate::llm::LlmErrorKind;
+use crate::rate_limit::Exceeded;
+use crate::storage::UsagePeriod;
+use crate::usage::BudgetExceeded;
+
+/// A language replies are translated into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    English,
+    German,
+    Spanish,
+    French,
+    Russian,
+}
+
+impl Language {
+    /// The language of a user's Telegram client, English if unknown or not translated
+    pub fn of_user(user: Option<&User>) -> Self {
+        let code = user
+            .and_then(|user| user.language_code.as_deref())
+            .unwrap_or_default();
+        // Codes are IETF language tags such as `en` or `pt-br`; only the language matters.
+        let language = code.split(['-', '_']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "de" => Self::German,
+            "es" => Self::Spanish,
+            "fr" => Self::French,
+            "ru" => Self::Russian,
+            _ => Self::English,
+        }
+    }
+}
+
+/// The reply to a request the LLM failed to answer
+pub fn llm_error_reply(kind: LlmErrorKind, language: Language) -> &'static str {
+    use LlmErrorKind::*;
+
+    match language {
+        Language::English => match kind {
+            RateLimited => {
+                "I'm receiving too many requests right now. Please try again in a minute."
+            }
+            ContextLengthExceeded => {
+                "This conversation is too long for the model. Please shorten your message or start a new conversation."
+            }
+            ContentFiltered => "I can't answer this because the model's content filter blocked it.",
+            EmptyResponse => {
+                "The model returned an empty answer. Please try rephrasing your message."
+            }
+            Network => "I couldn't reach the language model. Please try again later.",
+            Timeout => "The language model took too long to answer. Please try again.",
+            Api | InvalidResponse => "Sorry, I encountered an error while processing your request.",
+        },
+        Language::German => match kind {
+            RateLimited => {
+                "Ich erhalte gerade zu viele Anfragen. Bitte versuche es in einer Minute erneut."
+            }
+            ContextLengthExceeded => {
+                "Diese Unterhaltung ist zu lang für das Modell. Bitte kürze deine Nachricht oder beginne eine neue Unterhaltung."
+            }
+            ContentFiltered => {
+                "Darauf kann ich nicht antworten, da der Inhaltsfilter des Modells die Anfrage blockiert hat."
+            }
+            EmptyResponse => {
+                "Das Modell hat eine leere Antwort geliefert. Bitte formuliere deine Nachricht um."
+            }
+            Network => {
+                "Ich konnte das Sprachmodell nicht erreichen. Bitte versuche es später erneut."
+            }
+            Timeout => {
+                "Das Sprachmodell hat zu lange für eine Antwort gebraucht. Bitte versuche es erneut."
+            }
+            Api | InvalidResponse => {
+                "Entschuldigung, bei der Bearbeitung deiner Anfrage ist ein Fehler aufgetreten."
+            }
+        },
+        Language::Spanish => match kind {
+            RateLimited => {
+                "Estoy recibiendo demasiadas solicitudes en este momento. Inténtalo de nuevo en un minuto."
+            }
+            ContextLengthExceeded => {
+                "Esta conversación es demasiado larga para el modelo. Acorta tu mensaje o empieza una conversación nueva."
+            }
+            ContentFiltered => {
+                "No puedo responder a esto porque el filtro de contenido del modelo lo ha bloqueado."
+            }
+            EmptyResponse => {
+                "El modelo ha devuelto una respuesta vacía. Intenta reformular tu mensaje."
+            }
+            Network => {
+                "No he podido contactar con el modelo de lenguaje. Inténtalo de nuevo más tarde."
+            }
+            Timeout => {
+                "El modelo de lenguaje ha tardado demasiado en responder. Inténtalo de nuevo."
+            }
+            Api | InvalidResponse => {
+                "Lo siento, se ha producido un error al procesar tu solicitud."
+            }
+        },
+        Language::French => match kind {
+            RateLimited => "Je reçois trop de demandes en ce moment. Réessaie dans une minute.",
+            ContextLengthExceeded => {
+                "Cette conversation est trop longue pour le modèle. Raccourcis ton message ou commence une nouvelle conversation."
+            }
+            ContentFiltered => {
+                "Je ne peux pas répondre, car le filtre de contenu du modèle a bloqué la demande."
+            }
+            EmptyResponse => {
+                "Le modèle a renvoyé une réponse vide. Essaie de reformuler ton message."
+            }
+            Network => "Je n'ai pas pu joindre le modèle de langage. Réessaie plus tard.",
+            Timeout => "Le modèle de langage a mis trop de temps à répondre. Réessaie.",
+            Api | InvalidResponse => {
+                "Désolé, une erreur s'est produite lors du traitement de ta demande."
+            }
+        },
+        Language::Russian => match kind {
+            RateLimited => "Сейчас слишком много запросов. Попробуйте ещё раз через минуту.",
+            ContextLengthExceeded => {
+                "Этот разговор слишком длинный для модели. Сократите сообщение или начните новый разговор."
+            }
+            ContentFiltered => "Не могу ответить: фильтр содержимого модели заблокировал запрос.",
+            EmptyResponse => "Модель вернула пустой ответ. Попробуйте переформулировать сообщение.",
+            Network => "Не удалось связаться с языковой моделью. Попробуйте позже.",
+            Timeout => "Языковая модель слишком долго отвечала. Попробуйте ещё раз.",
+            Api | InvalidResponse => "Извините, при обработке запроса произошла ошибка.",
+        },
+    }
+}
+
+/// The reply to a message from a user or chat the bot does not serve
+pub fn access_denied_reply(language: Language) -> &'static str {
+    match language {
+        Language::English => "Sorry, I'm not available to you here.",
+        Language::German => "Entschuldigung, hier stehe ich dir leider nicht zur Verfügung.",
+        Language::Spanish => "Lo siento, aquí no estoy disponible para ti.",
+        Language::French => "Désolé, je ne suis pas disponible pour toi ici.",
+        Language::Russian => "Извините, здесь я вам недоступен.",
+    }
+}
+
+/// The reply to a request turned down by the rate limits
+pub fn rate_limit_reply(exceeded: Exceeded, language: Language) -> String {
+    match exceeded {
+        Exceeded::Rate(wait) => {
+            // Round up, so that trying again after the given time works
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            match language {
+                Language::English => format!("Slow down, try again in {}s.", seconds),
+                Language::German => {
+                    format!("Nicht so schnell, versuche es in {}s erneut.", seconds)
+                }
+                Language::Spanish => {
+                    format!("Más despacio, inténtalo de nuevo en {}s.", seconds)
+                }
+                Language::French => format!("Doucement, réessaie dans {}s.", seconds),
+                Language::Russian => {
+                    format!("Не так быстро, попробуйте ещё раз через {} с.", seconds)
+                }
+            }
+        }
+        Exceeded::Concurrency => match language {
+            Language::English => {
+                "Slow down, I'm still answering your earlier messages. Try again once they're done."
+            }
+            Language::German => {
+                "Nicht so schnell, ich beantworte noch deine vorherigen Nachrichten. Versuche es danach erneut."
+            }
+            Language::Spanish => {
+                "Más despacio, todavía estoy respondiendo a tus mensajes anteriores. Inténtalo de nuevo cuando termine."
+            }
+            Language::French => {
+                "Doucement, je réponds encore à tes messages précédents. Réessaie quand j'aurai fini."
+            }
+            Language::Russian => {
+                "Не так быстро, я ещё отвечаю на ваши предыдущие сообщения. Попробуйте ещё раз, когда я закончу."
+            }
+        }
+        .to_string(),
+    }
+}
+
+/// The reply to a request turned down because a budget is used up
+pub fn budget_exceeded_reply(exceeded: BudgetExceeded, language: Language) -> &'static str {
+    let daily = exceeded.period == UsagePeriod::Today;
+    match (language, exceeded.user, daily) {
+        (Language::English, true, true) => {
+            "You've used up your daily budget. It renews at midnight UTC."
+        }
+        (Language::English, true, false) => {
+            "You've used up your monthly budget. It renews at the start of next month."
+        }
+        (Language::English, false, true) => {
+            "This chat has used up its daily budget. It renews at midnight UTC."
+        }
+        (Language::English, false, false) => {
+            "This chat has used up its monthly budget. It renews at the start of next month."
+        }
+        (Language::German, true, true) => {
+            "Dein Tagesbudget ist aufgebraucht. Es erneuert sich um Mitternacht UTC."
+        }
+        (Language::German, true, false) => {
+            "Dein Monatsbudget ist aufgebraucht. Es erneuert sich zu Beginn des nächsten Monats."
+        }
+        (Language::German, false, true) => {
+            "Das Tagesbudget dieses Chats ist aufgebraucht. Es erneuert sich um Mitternacht UTC."
+        }
+        (Language::German, false, false) => {
+            "Das Monatsbudget dieses Chats ist aufgebraucht. Es erneuert sich zu Beginn des nächsten Monats."
+        }
+        (Language::Spanish, true, true) => {
+            "Has agotado tu presupuesto diario. Se renueva a medianoche UTC."
+        }
+        (Language::Spanish, true, false) => {
+            "Has agotado tu presupuesto mensual. Se renueva a principios del mes que viene."
+        }
+        (Language::Spanish, false, true) => {
+            "Este chat ha agotado su presupuesto diario. Se renueva a medianoche UTC."
+        }
+        (Language::Spanish, false, false) => {
+            "Este chat ha agotado su presupuesto mensual. Se renueva a principios del mes que viene."
+        }
+        (Language::French, true, true) => {
+            "Tu as épuisé ton budget journalier. Il se renouvelle à minuit UTC."
+        }
+        (Language::French, true, false) => {
+            "Tu as épuisé ton budget mensuel. Il se renouvelle au début du mois prochain."
+        }
+        (Language::French, false, true) => {
+            "Ce chat a épuisé son budget journalier. Il se renouvelle à minuit UTC."
+        }
+        (Language::French, false, false) => {
+            "Ce chat a épuisé son budget mensuel. Il se renouvelle au début du mois prochain."
+        }
+        (Language::Russian, true, true) => {
+            "Ваш дневной бюджет исчерпан. Он обновится в полночь по UTC."
+        }
+        (Language::Russian, true, false) => {
+            "Ваш месячный бюджет исчерпан. Он обновится в начале следующего месяца."
+        }
+        (Language::Russian, false, true) => {
+            "Дневной бюджет этого чата исчерпан. Он обновится в полночь по UTC."
+        }
+        (Language::Russian, false, false) => {
+            "Месячный бюджет этого чата исчерпан. Он обновится в начале следующего месяца."
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Image generation
+//!
+//! `/imagine <prompt>` generates an image through an OpenAI-compatible
+//! `/images/generations` endpoint. The prompt may start with `size=<width>x<height>` and
+//! `quality=<quality>` options. Every user can generate a limited number of images per
+//! day, and the chat model can first rewrite short prompts into more detailed ones.
+
+use std::env;
+use std::time::Duration;
+
+use base64::Engine;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::llm::{
+    self, ChatMessage, ChatRequest, LlmError, RetryPolicy, Role, StreamEvent, ToolChoice, Usage,
+};
+use crate::models::{ModelInfo, ModelRegistry};
+
+/// The name image generation errors are reported under
+const PROVIDER: &str = "Images";
+
+/// How long generating an image may take
+const TIMEOUT: Duration = Duration::from_secs(180);
+
+/// The qualities the images endpoint knows, across DALL·E and GPT image models
+const QUALITIES: &[&str] = &["auto", "standard", "hd", "low", "medium", "high"];
+
+/// Instructions for rewriting a prompt with the chat model
+const REWRITE_PROMPT: &str = "Rewrite the user's request into a detailed prompt for an image \
+    generation model, describing subject, style, composition and lighting. Keep the user's \
+    intent and language. Reply with the prompt only.";
+
+/// A client of an image generation API
+#[derive(Clone, Debug)]
+pub struct ImageGenerator {
+    http: reqwest::Client,
+    api_base: String,
+    api_key: Option<String>,
+    model: String,
+    /// The size of images requested without a `size` option
+    default_size: String,
+    /// The quality of images requested without a `quality` option, the API's default if not set
+    default_quality: Option<String>,
+    /// How many images a user may generate per day, unlimited if not set
+    pub daily_quota: Option<u32>,
+    /// Whether prompts are rewritten by the chat model before generating
+    pub rewrite_prompts: bool,
+}
+
+/// An image request parsed from the arguments of `/imagine`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImagineRequest {
+    pub prompt: String,
+    pub size: Option<String>,
+    pub quality: Option<String>,
+}
+
+/// A generated image
+#[derive(Debug)]
+pub enum GeneratedImage {
+    /// The image itself
+    Data(Vec<u8>),
+    /// A URL the image can be fetched from
+    Url(reqwest::Url),
+}
+
+/// The response of the images endpoint
+#[derive(Debug, Deserialize)]
+struct ImagesResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    #[serde(default)]
+    b64_json: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+}
+
+impl ImageGenerator {
+    /// Create the client from `IMAGE_MODEL`, `IMAGE_API_BASE`, `IMAGE_API_KEY`,
+    /// `IMAGE_SIZE`, `IMAGE_QUALITY`, `IMAGE_DAILY_QUOTA` and `IMAGE_PROMPT_REWRITE`
+    ///
+    /// Returns `None` if no image model is configured. The API base and key default to
+    /// `OPENAI_API_BASE` and `OPENAI_API_KEY`.
+    pub fn from_env() -> Option<Self> {
+        let model = env::var("IMAGE_MODEL").ok()?;
+        let api_base = env::var("IMAGE_API_BASE")
+            .or_else(|_| env::var("OPENAI_API_BASE"))
+            .expect("IMAGE_API_BASE must be set");
+        let api_key = env::var("IMAGE_API_KEY")
+            .or_else(|_| env::var("OPENAI_API_KEY"))
+            .ok();
+
+        let default_size = env::var("IMAGE_SIZE").unwrap_or_else(|_| "1024x1024".to_string());
+        assert!(is_valid_size(&default_size), "Invalid IMAGE_SIZE");
+        let default_quality = env::var("IMAGE_QUALITY").ok();
+        if let Some(quality) = &default_quality {
+            assert!(
+                QUALITIES.contains(&quality.as_str()),
+                "Invalid IMAGE_QUALITY"
+            );
+        }
+
+        Some(Self {
+            http: reqwest::Client::new(),
+            api_base,
+            api_key,
+            model,
+            default_size,
+            default_quality,
+            daily_quota: env::var("IMAGE_DAILY_QUOTA")
+                .ok()
+                .and_then(|value| value.parse().ok()),
+            rewrite_prompts: env::var("IMAGE_PROMPT_REWRITE")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(false),
+        })
+    }
+
+    /// Generate an image
+    pub async fn generate(&self, request: &ImagineRequest) -> Result<GeneratedImage, LlmError> {
+        let mut body = json!({
+            "model": self.model,
+            "prompt": request.prompt,
+            "n": 1,
+            "size": request.size.as_ref().unwrap_or(&self.default_size),
+        });
+        if let Some(quality) = request.quality.as_ref().or(self.default_quality.as_ref()) {
+            body["quality"] = json!(quality);
+        }
+        log::debug!("Image request: {}", body);
+
+        let mut http_request = self
+            .http
+            .post(format!("{}/images/generations", self.api_base))
+            .timeout(TIMEOUT)
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            http_request = http_request.bearer_auth(api_key);
+        }
+        let response = http_request.send().await.map_err(|e| {
+            if e.is_timeout() {
+                LlmError::Timeout
+            } else {
+                LlmError::network(PROVIDER, e)
+            }
+        })?;
+
+        let response: ImagesResponse = llm::check_response(PROVIDER, response)
+            .await?
+            .json()
+            .await
+            .map_err(|e| LlmError::invalid_response(PROVIDER, e))?;
+        // Depending on the model, images come inline or as a link.
+        match response.data.into_iter().next() {
+            Some(ImageData {
+                b64_json: Some(data),
+                ..
+            }) => base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .map(GeneratedImage::Data)
+                .map_err(|e| LlmError::invalid_response(PROVIDER, e)),
+            Some(ImageData { url: Some(url), .. }) => url
+                .parse()
+                .map(GeneratedImage::Url)
+                .map_err(|e| LlmError::invalid_response(PROVIDER, e)),
+            _ => Err(LlmError::EmptyResponse),
+        }
+    }
+}
+
+impl ImagineRequest {
+    /// Parse the arguments of `/imagine`: options first, then the prompt
+    pub fn parse(arguments: &str) -> Result<Self, String> {
+        let mut request = Self {
+            prompt: String::new(),
+            size: None,
+            quality: None,
+        };
+
+        let mut rest = arguments.trim_start();
+        while let Some((option, value)) = rest
+            .split_whitespace()
+            .next()
+            .and_then(|word| word.split_once('='))
+        {
+            let value = value.to_ascii_lowercase();
+            match option.to_ascii_lowercase().as_str() {
+                "size" if is_valid_size(&value) => request.size = Some(value),
+                "size" => return Err(format!("Invalid size `{}`, use e.g. 1024x1024.", value)),
+                "quality" if QUALITIES.contains(&value.as_str()) => request.quality = Some(value),
+                "quality" => {
+                    return Err(format!(
+                        "Invalid quality `{}`, use one of {}.",
+                        value,
+                        QUALITIES.join(", ")
+                    ));
+                }
+                // Not an option, so the prompt starts here
+                _ => break,
+            }
+            let word_len = rest.split_whitespace().next().unwrap_or_default().len();
+            rest = rest[word_len..].trim_start();
+        }
+
+        request.prompt = rest.trim().to_string();
+        if request.prompt.is_empty() {
+            return Err(
+                "Tell me what to draw: /imagine [size=1024x1024] [quality=hd] <prompt>".to_string(),
+            );
+        }
+        Ok(request)
+    }
+}
+
+/// Check if a size is `auto` or of the form `<width>x<height>`
+fn is_valid_size(size: &str) -> bool {
+    size == "auto"
+        || size.split_once('x').is_some_and(|(width, height)| {
+            width.parse::<u32>().is_ok() && height.parse::<u32>().is_ok()
+        })
+}
+
+/// Let the chat model turn a prompt into a more detailed one, returning it with the tokens
+/// spent on it
+pub async fn rewrite_prompt(
+    models: &ModelRegistry,
+    model: &ModelInfo,
+    prompt: &str,
+    retry_policy: RetryPolicy,
+) -> Result<(String, Option<Usage>), LlmError> {
+    let request = ChatRequest {
+        model: model.name.clone(),
+        system: Some(REWRITE_PROMPT.to_string()),
+        messages: vec![ChatMessage::text(Role::User, prompt)],
+        tools: Vec::new(),
+        tool_choice: ToolChoice::None,
+    };
+    let provider = models.provider(model);
+    let mut stream = llm::stream_with_retries(&*provider, &request, retry_policy).await?;
+
+    let mut rewritten = String::new();
+    let mut usage = None;
+    while let Some(event) = stream.next().await {
+        match event? {
+            StreamEvent::Delta(delta) => rewritten.push_str(&delta),
+            StreamEvent::Usage(total) => usage = Some(total),
+            StreamEvent::ToolCall(_) | StreamEvent::Truncated => {}
+        }
+    }
+    let rewritten = rewritten.trim();
+    if rewritten.is_empty() {
+        return Err(LlmError::EmptyResponse);
+    }
+    Ok((rewritten.to_string(), usage))
+}
@@ -0,0 +1,386 @@
+//! Anthropic Messages API
+
+use std::env;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::{
+    Capabilities, ChatRequest, ChatResponse, ChatStream, LlmError, LlmProvider, Role, StreamEvent,
+    ToolCall, ToolCallParts, ToolChoice, Usage, check_response, server_sent_events,
+};
+
+/// The API version sent with every request
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// A provider for Anthropic's Messages API
+#[derive(Debug)]
+pub struct AnthropicProvider {
+    http: reqwest::Client,
+    api_key: String,
+    api_base: String,
+    /// Upper limit of generated tokens, which the API requires with every request
+    max_tokens: u32,
+}
+
+impl AnthropicProvider {
+    /// Create the provider from `ANTHROPIC_API_KEY`, `ANTHROPIC_API_BASE` and `ANTHROPIC_MAX_TOKENS`
+    pub fn from_env() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_key: env::var("ANTHROPIC_API_KEY").expect("ANTHROPIC_API_KEY must be set"),
+            api_base: env::var("ANTHROPIC_API_BASE")
+                .unwrap_or_else(|_| "https://api.anthropic.com".to_string()),
+            max_tokens: env::var("ANTHROPIC_MAX_TOKENS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(4096),
+        }
+    }
+
+    /// Send a request to the Messages API
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, LlmError> {
+        let messages: Vec<Value> = request
+            .messages
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                };
+                if message.images.is_empty()
+                    && message.tool_calls.is_empty()
+                    && message.tool_results.is_empty()
+                {
+                    return json!({ "role": role, "content": message.content });
+                }
+
+                // Tool results have to come first in their message.
+                let results = message.tool_results.iter().map(|result| {
+                    json!({
+                        "type": "tool_result",
+                        "tool_use_id": result.call_id,
+                        "content": result.content,
+                        "is_error": result.is_error,
+                    })
+                });
+                let images = message.images.iter().map(|image| {
+                    json!({
+                        "type": "image",
+                        "source": {
+                            "type": "base64",
+                            "media_type": image.mime_type,
+                            "data": image.base64(),
+                        },
+                    })
+                });
+                let text = (!message.content.is_empty())
+                    .then(|| json!({ "type": "text", "text": message.content }));
+                let calls = message.tool_calls.iter().map(|call| {
+                    json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": call.arguments_object(),
+                    })
+                });
+                let content: Vec<Value> = results.chain(images).chain(text).chain(calls).collect();
+                json!({ "role": role, "content": content })
+            })
+            .collect();
+        let mut body = json!({
+            "model": request.model,
+            "max_tokens": self.max_tokens,
+            "messages": messages,
+            "stream": stream,
+        });
+        if let Some(system) = &request.system {
+            body["system"] = json!(system);
+        }
+        if !request.tools.is_empty() {
+            let tools: Vec<Value> = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters,
+                    })
+                })
+                .collect();
+            body["tools"] = json!(tools);
+            body["tool_choice"] = match request.tool_choice {
+                ToolChoice::Auto => json!({ "type": "auto" }),
+                ToolChoice::None => json!({ "type": "none" }),
+            };
+        }
+        log::debug!("LLM request: {:?}", request);
+
+        let response = self
+            .http
+            .post(format!(
+                "{}/v1/messages",
+                self.api_base.trim_end_matches('/')
+            ))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| LlmError::network("Anthropic", e))?;
+        check_response("Anthropic", response).await
+    }
+}
+
+/// A complete response of the Messages API
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    #[serde(default)]
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+/// A block of a response's content
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Token counts as reported by Anthropic
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+/// An event of a streamed response
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MessagesEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    ContentBlockStart {
+        index: u32,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: u32,
+        delta: ContentDelta,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: AnthropicUsage,
+    },
+    Error {
+        error: ApiError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The changes to the message sent at the end of a stream
+#[derive(Debug, Deserialize)]
+struct MessageDelta {
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+/// The stop reason of answers the model declined to give
+const REFUSAL_STOP_REASON: &str = "refusal";
+
+/// The stop reason of answers cut off at the token limit
+const MAX_TOKENS_STOP_REASON: &str = "max_tokens";
+
+/// The message metadata sent at the start of a stream
+#[derive(Debug, Deserialize)]
+struct MessageStart {
+    usage: AnthropicUsage,
+}
+
+/// An increment of a content block
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// An error reported in the middle of a stream
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+impl From<ApiError> for LlmError {
+    fn from(error: ApiError) -> Self {
+        // Map the error type to the status the API uses for it outside of streams.
+        let status = match error.kind.as_str() {
+            "invalid_request_error" => 400,
+            "authentication_error" => 401,
+            "permission_error" => 403,
+            "not_found_error" => 404,
+            "request_too_large" => 413,
+            "rate_limit_error" => 429,
+            "overloaded_error" => 529,
+            _ => 500,
+        };
+        Self::from_response("Anthropic", status, error.message, None)
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            streaming: true,
+            tools: true,
+        }
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let response: MessagesResponse = self
+            .send(request, false)
+            .await?
+            .json()
+            .await
+            .map_err(|e| LlmError::invalid_response("Anthropic", e))?;
+        log::debug!("LLM response: {:?}", response);
+        if response.stop_reason.as_deref() == Some(REFUSAL_STOP_REASON) {
+            return Err(LlmError::ContentFiltered {
+                provider: "Anthropic",
+            });
+        }
+
+        let mut content = String::new();
+        let mut tool_calls = Vec::new();
+        for block in response.content {
+            match block {
+                ContentBlock::Text { text } => content.push_str(&text),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id,
+                    name,
+                    arguments: input,
+                }),
+                ContentBlock::Other => {}
+            }
+        }
+        if content.is_empty() && tool_calls.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+
+        Ok(ChatResponse {
+            content,
+            usage: Some(Usage {
+                prompt_tokens: response.usage.input_tokens,
+                completion_tokens: response.usage.output_tokens,
+            }),
+            tool_calls,
+            truncated: response.stop_reason.as_deref() == Some(MAX_TOKENS_STOP_REASON),
+        })
+    }
+
+    async fn stream(&self, request: &ChatRequest) -> Result<ChatStream, LlmError> {
+        let response = self.send(request, true).await?;
+
+        // The prompt tokens arrive with the first event, the completion tokens with the last.
+        // Tool calls arrive in pieces and are passed on at the end as well.
+        let events = server_sent_events("Anthropic", response).scan(
+            (AnthropicUsage::default(), ToolCallParts::default()),
+            |(usage, tool_calls), event| {
+                let events = match event.and_then(|event| {
+                    serde_json::from_str::<MessagesEvent>(&event.data)
+                        .map_err(|e| LlmError::invalid_response("Anthropic", e))
+                }) {
+                    Ok(MessagesEvent::MessageStart { message }) => {
+                        usage.input_tokens = message.usage.input_tokens;
+                        vec![]
+                    }
+                    Ok(MessagesEvent::ContentBlockStart {
+                        index,
+                        content_block: ContentBlock::ToolUse { id, name, .. },
+                    }) => {
+                        tool_calls.push(index, Some(id), Some(name), "");
+                        vec![]
+                    }
+                    Ok(MessagesEvent::ContentBlockDelta {
+                        delta: ContentDelta::TextDelta { text },
+                        ..
+                    }) => vec![Ok(StreamEvent::Delta(text))],
+                    Ok(MessagesEvent::ContentBlockDelta {
+                        index,
+                        delta: ContentDelta::InputJsonDelta { partial_json },
+                    }) => {
+                        tool_calls.push(index, None, None, &partial_json);
+                        vec![]
+                    }
+                    Ok(MessagesEvent::MessageDelta {
+                        delta,
+                        usage: total,
+                    }) => {
+                        let usage = StreamEvent::Usage(Usage {
+                            prompt_tokens: usage.input_tokens,
+                            completion_tokens: total.output_tokens,
+                        });
+                        let mut events: Vec<_> = tool_calls
+                            .finish()
+                            .into_iter()
+                            .map(StreamEvent::ToolCall)
+                            .chain([usage])
+                            .chain(
+                                (delta.stop_reason.as_deref() == Some(MAX_TOKENS_STOP_REASON))
+                                    .then_some(StreamEvent::Truncated),
+                            )
+                            .map(Ok)
+                            .collect();
+                        if delta.stop_reason.as_deref() == Some(REFUSAL_STOP_REASON) {
+                            events.push(Err(LlmError::ContentFiltered {
+                                provider: "Anthropic",
+                            }));
+                        }
+                        events
+                    }
+                    Ok(MessagesEvent::Error { error }) => {
+                        vec![Err(error.into())]
+                    }
+                    Ok(_) => vec![],
+                    Err(error) => vec![Err(error)],
+                };
+                futures::future::ready(Some(futures::stream::iter(events)))
+            },
+        );
+        Ok(events.flatten().boxed())
+    }
+}
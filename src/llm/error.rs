@@ -0,0 +1,216 @@
+//! Errors of LLM requests
+//!
+//! Providers report failures in their own ways, so errors are sorted into a few classes
+//! that the bot handles differently: transient ones are retried, and each class gets its own
+//! reply to the user and its own label in the logs.
+
+use std::fmt;
+use std::time::Duration;
+
+/// An LLM request that failed
+#[derive(Debug)]
+pub enum LlmError {
+    /// The API is rate limiting requests
+    RateLimited {
+        provider: &'static str,
+        message: String,
+        /// How long the API asked to wait before trying again
+        retry_after: Option<Duration>,
+    },
+    /// The conversation does not fit into the model's context window
+    ContextLengthExceeded {
+        provider: &'static str,
+        message: String,
+    },
+    /// The prompt or the answer was blocked by the provider's content filter
+    ContentFiltered { provider: &'static str },
+    /// The model answered with nothing
+    EmptyResponse,
+    /// The API could not be reached, or the connection broke
+    Network {
+        provider: &'static str,
+        message: String,
+    },
+    /// The API did not respond in time
+    Timeout,
+    /// The API answered with any other error
+    Api {
+        provider: &'static str,
+        /// The HTTP status, or the closest match for errors reported inside a stream
+        status: u16,
+        message: String,
+        /// How long the API asked to wait before trying again
+        retry_after: Option<Duration>,
+    },
+    /// The API's response could not be understood
+    InvalidResponse {
+        provider: &'static str,
+        message: String,
+    },
+}
+
+/// The class of an [`LlmError`], without its details
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LlmErrorKind {
+    RateLimited,
+    ContextLengthExceeded,
+    ContentFiltered,
+    EmptyResponse,
+    Network,
+    Timeout,
+    Api,
+    InvalidResponse,
+}
+
+impl LlmErrorKind {
+    /// A short, stable name for logs and metrics
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::RateLimited => "rate_limited",
+            Self::ContextLengthExceeded => "context_length_exceeded",
+            Self::ContentFiltered => "content_filtered",
+            Self::EmptyResponse => "empty_response",
+            Self::Network => "network",
+            Self::Timeout => "timeout",
+            Self::Api => "api_error",
+            Self::InvalidResponse => "invalid_response",
+        }
+    }
+}
+
+/// Phrases by which providers report prompts exceeding the context window
+const CONTEXT_LENGTH_PATTERNS: &[&str] = &[
+    "context_length_exceeded",
+    "context length",
+    "context window",
+    "maximum context",
+    "prompt is too long",
+    "input is too long",
+    "too many tokens",
+    "exceeds the maximum number of tokens",
+];
+
+/// Phrases by which providers report blocked content
+const CONTENT_FILTER_PATTERNS: &[&str] = &[
+    "content_filter",
+    "content_policy",
+    "content management policy",
+    "safety",
+];
+
+impl LlmError {
+    /// Build the error for an HTTP error response, classifying it by its status and message
+    pub fn from_response(
+        provider: &'static str,
+        status: u16,
+        message: String,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let lowercase = message.to_lowercase();
+        let mentions =
+            |patterns: &[&str]| patterns.iter().any(|pattern| lowercase.contains(pattern));
+
+        match status {
+            // A used-up quota is reported as a rate limit, but waiting does not help.
+            429 if !lowercase.contains("insufficient_quota") => Self::RateLimited {
+                provider,
+                message,
+                retry_after,
+            },
+            400 | 413 | 422 if mentions(CONTEXT_LENGTH_PATTERNS) => {
+                Self::ContextLengthExceeded { provider, message }
+            }
+            400 | 403 | 422 if mentions(CONTENT_FILTER_PATTERNS) => {
+                Self::ContentFiltered { provider }
+            }
+            _ => Self::Api {
+                provider,
+                status,
+                message,
+                retry_after,
+            },
+        }
+    }
+
+    /// Build the error for a failed HTTP request
+    pub fn network(provider: &'static str, error: impl fmt::Display) -> Self {
+        Self::Network {
+            provider,
+            message: error.to_string(),
+        }
+    }
+
+    /// Build the error for a response that could not be parsed
+    pub fn invalid_response(provider: &'static str, error: impl fmt::Display) -> Self {
+        Self::InvalidResponse {
+            provider,
+            message: error.to_string(),
+        }
+    }
+
+    /// The class of the error
+    pub fn kind(&self) -> LlmErrorKind {
+        match self {
+            Self::RateLimited { .. } => LlmErrorKind::RateLimited,
+            Self::ContextLengthExceeded { .. } => LlmErrorKind::ContextLengthExceeded,
+            Self::ContentFiltered { .. } => LlmErrorKind::ContentFiltered,
+            Self::EmptyResponse => LlmErrorKind::EmptyResponse,
+            Self::Network { .. } => LlmErrorKind::Network,
+            Self::Timeout => LlmErrorKind::Timeout,
+            Self::Api { .. } => LlmErrorKind::Api,
+            Self::InvalidResponse { .. } => LlmErrorKind::InvalidResponse,
+        }
+    }
+
+    /// Check if the same request may succeed when tried again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::Network { .. } | Self::Timeout => true,
+            Self::Api { status, .. } => matches!(status, 408 | 409 | 500..),
+            Self::ContextLengthExceeded { .. }
+            | Self::ContentFiltered { .. }
+            | Self::EmptyResponse
+            | Self::InvalidResponse { .. } => false,
+        }
+    }
+
+    /// How long the API asked to wait before trying again, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } | Self::Api { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited {
+                provider, message, ..
+            } => write!(f, "{} rate limit: {}", provider, message),
+            Self::ContextLengthExceeded { provider, message } => {
+                write!(f, "{} context length exceeded: {}", provider, message)
+            }
+            Self::ContentFiltered { provider } => {
+                write!(f, "{} blocked the content", provider)
+            }
+            Self::EmptyResponse => write!(f, "Received an empty response"),
+            Self::Network { provider, message } => {
+                write!(f, "{} request failed: {}", provider, message)
+            }
+            Self::Timeout => write!(f, "LLM request timed out"),
+            Self::Api {
+                provider,
+                status,
+                message,
+                ..
+            } => write!(f, "{} API error ({}): {}", provider, status, message),
+            Self::InvalidResponse { provider, message } => {
+                write!(f, "Invalid {} response: {}", provider, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
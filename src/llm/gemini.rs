@@ -0,0 +1,341 @@
+//! Google Gemini API
+
+use std::env;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::{
+    Capabilities, ChatRequest, ChatResponse, ChatStream, LlmError, LlmProvider, Role, StreamEvent,
+    ToolCall, ToolChoice, Usage, check_response, server_sent_events,
+};
+
+/// A provider for the Gemini API
+#[derive(Debug)]
+pub struct GeminiProvider {
+    http: reqwest::Client,
+    api_key: String,
+    api_base: String,
+}
+
+impl GeminiProvider {
+    /// Create the provider from `GEMINI_API_KEY` and `GEMINI_API_BASE`
+    pub fn from_env() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_key: env::var("GEMINI_API_KEY").expect("GEMINI_API_KEY must be set"),
+            api_base: env::var("GEMINI_API_BASE")
+                .unwrap_or_else(|_| "https://generativelanguage.googleapis.com/v1beta".to_string()),
+        }
+    }
+
+    /// Send a request to one of the model's content generation methods
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, LlmError> {
+        let contents: Vec<Value> = request
+            .messages
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    Role::User => "user",
+                    Role::Assistant => "model",
+                };
+                let results = message.tool_results.iter().map(|result| {
+                    let key = if result.is_error { "error" } else { "result" };
+                    json!({
+                        "functionResponse": {
+                            "name": result.name,
+                            "response": { key: result.content },
+                        },
+                    })
+                });
+                let images = message.images.iter().map(|image| {
+                    json!({
+                        "inlineData": { "mimeType": image.mime_type, "data": image.base64() },
+                    })
+                });
+                // Messages made of tool calls or results only have no text.
+                let has_text = !message.content.is_empty()
+                    || (message.tool_calls.is_empty() && message.tool_results.is_empty());
+                let text = has_text.then(|| json!({ "text": message.content }));
+                let calls = message.tool_calls.iter().map(|call| {
+                    json!({
+                        "functionCall": { "name": call.name, "args": call.arguments_object() },
+                    })
+                });
+                let parts: Vec<Value> = results.chain(images).chain(text).chain(calls).collect();
+                json!({ "role": role, "parts": parts })
+            })
+            .collect();
+        let mut body = json!({ "contents": contents });
+        if let Some(system) = &request.system {
+            body["systemInstruction"] = json!({ "parts": [{ "text": system }] });
+        }
+        if !request.tools.is_empty() {
+            let declarations: Vec<Value> = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "parameters": tool.parameters,
+                    })
+                })
+                .collect();
+            body["tools"] = json!([{ "functionDeclarations": declarations }]);
+            let mode = match request.tool_choice {
+                ToolChoice::Auto => "AUTO",
+                ToolChoice::None => "NONE",
+            };
+            body["toolConfig"] = json!({ "functionCallingConfig": { "mode": mode } });
+        }
+        log::debug!("LLM request: {:?}", request);
+
+        let method = if stream {
+            "streamGenerateContent?alt=sse"
+        } else {
+            "generateContent"
+        };
+        let response = self
+            .http
+            .post(format!(
+                "{}/models/{}:{}",
+                self.api_base.trim_end_matches('/'),
+                request.model,
+                method
+            ))
+            .header("x-goog-api-key", &self.api_key)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| LlmError::network("Gemini", e))?;
+        check_response("Gemini", response).await
+    }
+}
+
+/// A complete response, or one event of a streamed response
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    #[serde(default)]
+    usage_metadata: Option<UsageMetadata>,
+    #[serde(default)]
+    prompt_feedback: Option<PromptFeedback>,
+}
+
+/// One of the generated answers
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    #[serde(default)]
+    content: Option<Content>,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+/// The verdict on the prompt, present when it was blocked
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    #[serde(default)]
+    block_reason: Option<String>,
+}
+
+/// The finish reasons of answers stopped by a content filter
+const BLOCKED_FINISH_REASONS: &[&str] = &[
+    "SAFETY",
+    "RECITATION",
+    "BLOCKLIST",
+    "PROHIBITED_CONTENT",
+    "SPII",
+];
+
+/// The content of an answer
+#[derive(Debug, Deserialize)]
+struct Content {
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+/// A part of an answer's content
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    function_call: Option<FunctionCall>,
+}
+
+/// A tool call in an answer
+#[derive(Debug, Deserialize)]
+struct FunctionCall {
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+/// Token counts as reported by Gemini
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u32,
+    #[serde(default)]
+    candidates_token_count: u32,
+}
+
+impl GenerateContentResponse {
+    /// The text of the first candidate
+    fn text(&self) -> String {
+        self.candidates
+            .first()
+            .and_then(|candidate| candidate.content.as_ref())
+            .map(|content| {
+                content
+                    .parts
+                    .iter()
+                    .filter_map(|part| part.text.as_deref())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The tool calls of the first candidate, numbered from `first_index` where the API gives
+    /// them no ID
+    fn tool_calls(&self, first_index: usize) -> Vec<ToolCall> {
+        self.candidates
+            .first()
+            .and_then(|candidate| candidate.content.as_ref())
+            .map(|content| {
+                content
+                    .parts
+                    .iter()
+                    .filter_map(|part| part.function_call.as_ref())
+                    .enumerate()
+                    .map(|(index, call)| ToolCall {
+                        id: call
+                            .id
+                            .clone()
+                            .unwrap_or_else(|| format!("call_{}", first_index + index)),
+                        name: call.name.clone(),
+                        arguments: call.args.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Check if the prompt or the answer was blocked
+    fn is_blocked(&self) -> bool {
+        let prompt_blocked = self
+            .prompt_feedback
+            .as_ref()
+            .is_some_and(|feedback| feedback.block_reason.is_some());
+        let answer_blocked = self
+            .candidates
+            .first()
+            .and_then(|candidate| candidate.finish_reason.as_deref())
+            .is_some_and(|reason| BLOCKED_FINISH_REASONS.contains(&reason));
+        prompt_blocked || answer_blocked
+    }
+
+    /// Check if the answer was cut off at the token limit
+    fn is_truncated(&self) -> bool {
+        self.candidates
+            .first()
+            .and_then(|candidate| candidate.finish_reason.as_deref())
+            == Some("MAX_TOKENS")
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        self.usage_metadata.as_ref().map(|usage| Usage {
+            prompt_tokens: usage.prompt_token_count,
+            completion_tokens: usage.candidates_token_count,
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            streaming: true,
+            tools: true,
+        }
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let response: GenerateContentResponse = self
+            .send(request, false)
+            .await?
+            .json()
+            .await
+            .map_err(|e| LlmError::invalid_response("Gemini", e))?;
+        log::debug!("LLM response: {:?}", response);
+        if response.is_blocked() {
+            return Err(LlmError::ContentFiltered { provider: "Gemini" });
+        }
+
+        let content = response.text();
+        let tool_calls = response.tool_calls(0);
+        if content.is_empty() && tool_calls.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+
+        Ok(ChatResponse {
+            content,
+            usage: response.usage(),
+            tool_calls,
+            truncated: response.is_truncated(),
+        })
+    }
+
+    async fn stream(&self, request: &ChatRequest) -> Result<ChatStream, LlmError> {
+        let response = self.send(request, true).await?;
+
+        // Every event carries the usage so far, so the last one seen is the total. Tool calls
+        // arrive whole, and are counted to give them unique IDs.
+        let events = server_sent_events("Gemini", response).scan(0, |calls_seen, event| {
+            let events = match event.and_then(|event| {
+                serde_json::from_str::<GenerateContentResponse>(&event.data)
+                    .map_err(|e| LlmError::invalid_response("Gemini", e))
+            }) {
+                Ok(response) => {
+                    let text = response.text();
+                    let tool_calls = response.tool_calls(*calls_seen);
+                    *calls_seen += tool_calls.len();
+                    let mut events: Vec<_> = (!text.is_empty())
+                        .then_some(StreamEvent::Delta(text))
+                        .into_iter()
+                        .chain(tool_calls.into_iter().map(StreamEvent::ToolCall))
+                        .chain(response.usage().map(StreamEvent::Usage))
+                        .chain(response.is_truncated().then_some(StreamEvent::Truncated))
+                        .map(Ok)
+                        .collect();
+                    if response.is_blocked() {
+                        events.push(Err(LlmError::ContentFiltered { provider: "Gemini" }));
+                    }
+                    events
+                }
+                Err(error) => vec![Err(error)],
+            };
+            futures::future::ready(Some(futures::stream::iter(events)))
+        });
+        Ok(events.flatten().boxed())
+    }
+}
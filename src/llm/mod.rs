@@ -0,0 +1,436 @@
+//! LLM providers
+//!
+//! The bot talks to language models through the [`LlmProvider`] trait, so the handlers do
+//! not depend on any particular API. Besides OpenAI-compatible endpoints (via async-openai)
+//! there are native implementations of the Anthropic Messages API, Ollama's chat API and
+//! the Gemini API. Each model in the registry names the provider serving it.
+
+mod anthropic;
+mod error;
+mod gemini;
+mod ollama;
+mod openai;
+mod retry;
+
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::Engine;
+use eventsource_stream::{Event, Eventsource};
+use futures::{StreamExt, stream::BoxStream};
+
+pub use anthropic::AnthropicProvider;
+pub use error::{LlmError, LlmErrorKind};
+pub use gemini::GeminiProvider;
+pub use ollama::OllamaProvider;
+pub use openai::OpenAiProvider;
+pub use retry::{RetryPolicy, stream_with_retries};
+
+/// The author of a chat message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+/// A message of the conversation sent to the model
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+    /// Who wrote the message
+    pub role: Role,
+    /// The message text
+    pub content: String,
+    /// Images attached to the message, for models with vision
+    pub images: Vec<Image>,
+    /// Tools the model called in this message, for assistant messages
+    pub tool_calls: Vec<ToolCall>,
+    /// The results of the tools called in the previous message, for user messages
+    pub tool_results: Vec<ToolResult>,
+}
+
+impl ChatMessage {
+    /// Create a message consisting of text only
+    pub fn text(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            images: Vec::new(),
+            tool_calls: Vec::new(),
+            tool_results: Vec::new(),
+        }
+    }
+}
+
+/// A tool the model may call
+#[derive(Clone, Debug)]
+pub struct ToolSpec {
+    /// The name the model calls the tool by
+    pub name: String,
+    /// What the tool does, for the model to decide when to call it
+    pub description: String,
+    /// The JSON Schema of the tool's arguments
+    pub parameters: serde_json::Value,
+}
+
+/// Whether the model may call the tools of a request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// The model decides
+    #[default]
+    Auto,
+    /// The model has to answer without calling tools
+    None,
+}
+
+/// A call of a tool requested by the model
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    /// The ID the result is sent back with
+    pub id: String,
+    /// The name of the tool
+    pub name: String,
+    /// The arguments, a JSON object unless the model produced invalid JSON
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// The arguments as a JSON object, empty if the model produced anything else
+    pub fn arguments_object(&self) -> serde_json::Value {
+        if self.arguments.is_object() {
+            self.arguments.clone()
+        } else {
+            serde_json::json!({})
+        }
+    }
+}
+
+/// The outcome of a tool call, sent back to the model
+#[derive(Clone, Debug)]
+pub struct ToolResult {
+    /// The ID of the call
+    pub call_id: String,
+    /// The name of the called tool
+    pub name: String,
+    /// The tool's output, or a description of its failure
+    pub content: String,
+    /// Whether the tool failed
+    pub is_error: bool,
+}
+
+/// An image sent to the model
+#[derive(Clone)]
+pub struct Image {
+    /// The image's MIME type, such as `image/jpeg`
+    pub mime_type: String,
+    /// The encoded image
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// The image encoded as base64
+    pub fn base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.data)
+    }
+
+    /// The image as a `data:` URL
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.base64())
+    }
+}
+
+impl fmt::Debug for Image {
+    /// Leave the image data out of the logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Image")
+            .field("mime_type", &self.mime_type)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// A chat completion request
+#[derive(Clone, Debug)]
+pub struct ChatRequest {
+    /// The model to use
+    pub model: String,
+    /// Instructions given to the model ahead of the conversation
+    pub system: Option<String>,
+    /// The conversation, oldest message first
+    pub messages: Vec<ChatMessage>,
+    /// The tools the model may call
+    pub tools: Vec<ToolSpec>,
+    /// Whether the model may call the tools
+    pub tool_choice: ToolChoice,
+}
+
+/// Tokens spent on a request
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    /// Tokens consumed by the prompt
+    pub prompt_tokens: u32,
+    /// Tokens generated in the answer
+    pub completion_tokens: u32,
+}
+
+/// A complete answer from the model
+#[derive(Clone, Debug)]
+pub struct ChatResponse {
+    /// The answer text
+    pub content: String,
+    /// Tokens spent on the request, when reported by the API
+    pub usage: Option<Usage>,
+    /// The tools the model called
+    pub tool_calls: Vec<ToolCall>,
+    /// Whether the answer was cut off at the token limit
+    pub truncated: bool,
+}
+
+/// A piece of a streamed answer
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    /// More answer text
+    Delta(String),
+    /// A complete tool call
+    ToolCall(ToolCall),
+    /// The tokens spent on the request, usually sent at the end
+    Usage(Usage),
+    /// The answer was cut off at the token limit
+    Truncated,
+}
+
+/// A streamed answer
+pub type ChatStream = BoxStream<'static, Result<StreamEvent, LlmError>>;
+
+/// What a provider supports
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Capabilities {
+    /// Answers can be streamed as they are generated
+    pub streaming: bool,
+    /// The model can call tools
+    pub tools: bool,
+}
+
+/// A language model API
+#[async_trait]
+pub trait LlmProvider: fmt::Debug + Send + Sync {
+    /// The provider's name, for logs
+    fn name(&self) -> &'static str;
+
+    /// The features this provider supports
+    fn capabilities(&self) -> Capabilities;
+
+    /// Get the complete answer to a request
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError>;
+
+    /// Stream the answer to a request as it is generated
+    async fn stream(&self, request: &ChatRequest) -> Result<ChatStream, LlmError>;
+}
+
+impl ChatResponse {
+    /// Turn a complete answer into a stream delivering it as a single delta
+    pub fn into_stream(self) -> ChatStream {
+        // Answers consisting of tool calls only have no text to deliver.
+        let has_text = !self.content.is_empty() || self.tool_calls.is_empty();
+        let events = has_text
+            .then_some(StreamEvent::Delta(self.content))
+            .into_iter()
+            .chain(self.tool_calls.into_iter().map(StreamEvent::ToolCall))
+            .chain(self.usage.map(StreamEvent::Usage))
+            .chain(self.truncated.then_some(StreamEvent::Truncated))
+            .map(Ok);
+        futures::stream::iter(events).boxed()
+    }
+}
+
+/// The provider used for models that do not name one, from `LLM_PROVIDER` (`openai` by default)
+pub fn default_provider_name() -> String {
+    env::var("LLM_PROVIDER")
+        .unwrap_or_else(|_| "openai".to_string())
+        .to_ascii_lowercase()
+}
+
+/// Create a provider by name, configured from its environment variables
+pub fn provider_from_env(provider: &str) -> Arc<dyn LlmProvider> {
+    match provider {
+        "openai" => Arc::new(OpenAiProvider::from_env()),
+        "anthropic" => Arc::new(AnthropicProvider::from_env()),
+        "ollama" => Arc::new(OllamaProvider::from_env()),
+        "gemini" => Arc::new(GeminiProvider::from_env()),
+        _ => panic!(
+            "Unknown LLM provider `{}`, expected openai, anthropic, ollama or gemini",
+            provider
+        ),
+    }
+}
+
+/// Check the status of an HTTP response, turning error responses into an error
+pub async fn check_response(
+    provider: &'static str,
+    response: reqwest::Response,
+) -> Result<reqwest::Response, LlmError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = retry_after(response.headers());
+    let message = response.text().await.unwrap_or_default();
+    Err(LlmError::from_response(
+        provider,
+        status.as_u16(),
+        message,
+        retry_after,
+    ))
+}
+
+/// Tool calls streamed in pieces, assembled by their index in the answer
+#[derive(Debug, Default)]
+struct ToolCallParts {
+    /// The ID, name and argument JSON received so far, by index
+    calls: std::collections::BTreeMap<u32, (String, String, String)>,
+}
+
+impl ToolCallParts {
+    /// Add a piece of the call at `index`
+    fn push(&mut self, index: u32, id: Option<String>, name: Option<String>, arguments: &str) {
+        let (call_id, call_name, call_arguments) = self.calls.entry(index).or_default();
+        if let Some(id) = id {
+            *call_id = id;
+        }
+        if let Some(name) = name {
+            call_name.push_str(&name);
+        }
+        call_arguments.push_str(arguments);
+    }
+
+    /// Take the assembled calls
+    fn finish(&mut self) -> Vec<ToolCall> {
+        std::mem::take(&mut self.calls)
+            .into_values()
+            .map(|(id, name, arguments)| tool_call(id, name, &arguments))
+            .collect()
+    }
+}
+
+/// Build a tool call from its argument JSON, keeping invalid JSON as a string
+fn tool_call(id: String, name: String, arguments: &str) -> ToolCall {
+    let arguments = if arguments.trim().is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_str(arguments)
+            .unwrap_or_else(|_| serde_json::Value::String(arguments.to_string()))
+    };
+    ToolCall {
+        id,
+        name,
+        arguments,
+    }
+}
+
+/// Read the delay requested by `retry-after-ms` or `retry-after` (in seconds)
+///
+/// Values that are no valid delay, such as negative or huge ones, are ignored.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f64>().ok())
+    };
+
+    header("retry-after-ms")
+        .and_then(|millis| Duration::try_from_secs_f64(millis / 1000.0).ok())
+        .or_else(|| header("retry-after").and_then(|secs| Duration::try_from_secs_f64(secs).ok()))
+}
+
+/// Read a response body as a stream of server-sent events
+fn server_sent_events(
+    provider: &'static str,
+    response: reqwest::Response,
+) -> BoxStream<'static, Result<Event, LlmError>> {
+    response
+        .bytes_stream()
+        .eventsource()
+        .map(move |event| event.map_err(|e| LlmError::network(provider, e)))
+        .boxed()
+}
+
+/// Read a response body as a stream of newline-delimited JSON values
+fn json_lines(
+    provider: &'static str,
+    response: reqwest::Response,
+) -> BoxStream<'static, Result<String, LlmError>> {
+    let state = (response.bytes_stream().boxed(), Vec::new());
+    futures::stream::unfold(state, move |(mut bytes, mut buffer)| async move {
+        loop {
+            if let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                return Some((Ok(line), (bytes, buffer)));
+            }
+
+            match bytes.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    return Some((Err(LlmError::network(provider, e)), (bytes, buffer)));
+                }
+                None if buffer.is_empty() => return None,
+                None => {
+                    let line = String::from_utf8_lossy(&buffer).trim().to_string();
+                    return Some((Ok(line), (bytes, Vec::new())));
+                }
+            }
+        }
+    })
+    .filter(|line| futures::future::ready(!matches!(line, Ok(line) if line.is_empty())))
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn reads_retry_after_headers() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "2")])),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            retry_after(&headers(&[
+                ("retry-after", "2"),
+                ("retry-after-ms", "1500")
+            ])),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_after(&headers(&[])), None);
+    }
+
+    #[test]
+    fn ignores_invalid_retry_after_headers() {
+        for value in ["1e300", "-1", "NaN", "inf", "Wed, 21 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(retry_after(&headers(&[("retry-after", value)])), None);
+        }
+        // An invalid `retry-after-ms` leaves `retry-after` to go by.
+        assert_eq!(
+            retry_after(&headers(&[
+                ("retry-after-ms", "1e300"),
+                ("retry-after", "3")
+            ])),
+            Some(Duration::from_secs(3))
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Ollama's native chat API
+
+use std::env;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::{
+    Capabilities, ChatRequest, ChatResponse, ChatStream, Image, LlmError, LlmProvider, Role,
+    StreamEvent, ToolCall, ToolChoice, Usage, check_response, json_lines,
+};
+
+/// A provider for a local or remote Ollama server
+#[derive(Debug)]
+pub struct OllamaProvider {
+    http: reqwest::Client,
+    api_base: String,
+}
+
+impl OllamaProvider {
+    /// Create the provider from `OLLAMA_API_BASE`
+    pub fn from_env() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_base: env::var("OLLAMA_API_BASE")
+                .unwrap_or_else(|_| "http://localhost:11434".to_string()),
+        }
+    }
+
+    /// Send a request to the chat endpoint
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, LlmError> {
+        let system = request
+            .system
+            .iter()
+            .map(|system| json!({ "role": "system", "content": system }));
+        let messages: Vec<Value> = system
+            .chain(request.messages.iter().flat_map(|message| {
+                let role = match message.role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                };
+                // Tool results are messages of their own, ahead of the message carrying them.
+                let results = message.tool_results.iter().map(|result| {
+                    json!({ "role": "tool", "tool_name": result.name, "content": result.content })
+                });
+                let mut message_json = json!({ "role": role, "content": message.content });
+                if !message.images.is_empty() {
+                    let images: Vec<String> = message.images.iter().map(Image::base64).collect();
+                    message_json["images"] = json!(images);
+                }
+                if !message.tool_calls.is_empty() {
+                    let calls: Vec<Value> = message
+                        .tool_calls
+                        .iter()
+                        .map(|call| {
+                            json!({
+                                "function": { "name": call.name, "arguments": call.arguments_object() },
+                            })
+                        })
+                        .collect();
+                    message_json["tool_calls"] = json!(calls);
+                }
+                let has_text = !message.content.is_empty() || message.tool_results.is_empty();
+                results
+                    .chain(has_text.then_some(message_json))
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+        let mut body = json!({
+            "model": request.model,
+            "messages": messages,
+            "stream": stream,
+        });
+        // Ollama cannot be told not to call tools, so they are left out instead.
+        if !request.tools.is_empty() && request.tool_choice == ToolChoice::Auto {
+            let tools: Vec<Value> = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        },
+                    })
+                })
+                .collect();
+            body["tools"] = json!(tools);
+        }
+        log::debug!("LLM request: {:?}", request);
+
+        let response = self
+            .http
+            .post(format!("{}/api/chat", self.api_base.trim_end_matches('/')))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| LlmError::network("Ollama", e))?;
+        check_response("Ollama", response).await
+    }
+}
+
+/// A complete response, or one line of a streamed response
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    #[serde(default)]
+    message: Option<ChunkMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// The message part of a chunk
+#[derive(Debug, Deserialize)]
+struct ChunkMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+/// A tool call in a chunk
+#[derive(Debug, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+impl ChunkMessage {
+    /// The tool calls, numbered from `first_index` since Ollama gives them no IDs
+    fn take_tool_calls(&mut self, first_index: usize) -> Vec<ToolCall> {
+        std::mem::take(&mut self.tool_calls)
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| ToolCall {
+                id: format!("call_{}", first_index + index),
+                name: call.function.name,
+                arguments: call.function.arguments,
+            })
+            .collect()
+    }
+}
+
+impl ChatChunk {
+    /// The token usage, which is only reported with the final chunk
+    fn usage(&self) -> Option<Usage> {
+        self.done.then_some(Usage {
+            prompt_tokens: self.prompt_eval_count,
+            completion_tokens: self.eval_count,
+        })
+    }
+
+    /// Check if the answer was cut off at the token limit
+    fn is_truncated(&self) -> bool {
+        self.done_reason.as_deref() == Some("length")
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            streaming: true,
+            tools: true,
+        }
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let response: ChatChunk = self
+            .send(request, false)
+            .await?
+            .json()
+            .await
+            .map_err(|e| LlmError::invalid_response("Ollama", e))?;
+        log::debug!("LLM response: {:?}", response);
+
+        if let Some(error) = response.error {
+            return Err(api_error(error));
+        }
+        let usage = response.usage();
+        let truncated = response.is_truncated();
+        let mut message = response.message.ok_or(LlmError::EmptyResponse)?;
+        let tool_calls = message.take_tool_calls(0);
+        if message.content.is_empty() && tool_calls.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+
+        Ok(ChatResponse {
+            content: message.content,
+            usage,
+            tool_calls,
+            truncated,
+        })
+    }
+
+    async fn stream(&self, request: &ChatRequest) -> Result<ChatStream, LlmError> {
+        let response = self.send(request, true).await?;
+
+        // Tool calls arrive whole, and are counted to give them unique IDs.
+        let events = json_lines("Ollama", response).scan(0, |calls_seen, line| {
+            let events = match line.and_then(|line| {
+                serde_json::from_str::<ChatChunk>(&line)
+                    .map_err(|e| LlmError::invalid_response("Ollama", e))
+            }) {
+                Ok(ChatChunk {
+                    error: Some(error), ..
+                }) => vec![Err(api_error(error))],
+                Ok(chunk) => {
+                    let usage = chunk.usage();
+                    let truncated = chunk.is_truncated();
+                    let (content, tool_calls) = match chunk.message {
+                        Some(mut message) => {
+                            let tool_calls = message.take_tool_calls(*calls_seen);
+                            (message.content, tool_calls)
+                        }
+                        None => (String::new(), Vec::new()),
+                    };
+                    *calls_seen += tool_calls.len();
+                    (!content.is_empty())
+                        .then_some(StreamEvent::Delta(content))
+                        .into_iter()
+                        .chain(tool_calls.into_iter().map(StreamEvent::ToolCall))
+                        .chain(usage.map(StreamEvent::Usage))
+                        .chain(truncated.then_some(StreamEvent::Truncated))
+                        .map(Ok)
+                        .collect()
+                }
+                Err(error) => vec![Err(error)],
+            };
+            futures::future::ready(Some(futures::stream::iter(events)))
+        });
+        Ok(events.flatten().boxed())
+    }
+}
+
+/// Build the error for an error message in a successful response
+fn api_error(message: String) -> LlmError {
+    LlmError::from_response("Ollama", 500, message, None)
+}
@@ -0,0 +1,304 @@
+//! OpenAI-compatible chat completions API
+
+use std::env;
+
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImage,
+    ChatCompletionRequestMessageContentPartText, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+    ChatCompletionStreamOptions, ChatCompletionTool, ChatCompletionToolChoiceOption,
+    ChatCompletionToolType, CompletionUsage, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse, FinishReason, FunctionCall, FunctionObject, ImageUrl,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use super::{
+    Capabilities, ChatMessage, ChatRequest, ChatResponse, ChatStream, LlmError, LlmProvider, Role,
+    StreamEvent, ToolCall, ToolCallParts, ToolChoice, Usage, check_response, server_sent_events,
+    tool_call,
+};
+
+/// A provider for OpenAI and OpenAI-compatible APIs
+///
+/// Requests and responses use async-openai's types, but are sent directly so that error
+/// statuses and `Retry-After` reach the retry logic instead of async-openai's own backoff.
+#[derive(Debug)]
+pub struct OpenAiProvider {
+    http: reqwest::Client,
+    api_key: String,
+    api_base: String,
+}
+
+impl OpenAiProvider {
+    /// Create the provider from `OPENAI_API_KEY` and `OPENAI_API_BASE`
+    pub fn from_env() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_key: env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set"),
+            api_base: env::var("OPENAI_API_BASE").expect("OPENAI_API_BASE must be set"),
+        }
+    }
+
+    /// Send a request to the chat completions endpoint
+    async fn send(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response, LlmError> {
+        let request = self.build_request(request, stream)?;
+        log::debug!("LLM request: {:?}", request);
+
+        let response = self
+            .http
+            .post(format!(
+                "{}/chat/completions",
+                self.api_base.trim_end_matches('/')
+            ))
+            .bearer_auth(&self.api_key)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| LlmError::network("OpenAI", e))?;
+        check_response("OpenAI", response).await
+    }
+
+    /// Build the request to OpenAI
+    fn build_request(
+        &self,
+        request: &ChatRequest,
+        stream: bool,
+    ) -> Result<CreateChatCompletionRequest, LlmError> {
+        let system = request
+            .system
+            .iter()
+            .map(|system| ChatCompletionRequestSystemMessage::from(system.as_str()).into());
+        let messages: Vec<ChatCompletionRequestMessage> = system
+            .chain(
+                request
+                    .messages
+                    .iter()
+                    .flat_map(|message| match message.role {
+                        Role::User => user_messages(message),
+                        Role::Assistant => vec![assistant_message(message).into()],
+                    }),
+            )
+            .collect();
+
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(&request.model).messages(messages);
+        if !request.tools.is_empty() {
+            let tools: Vec<ChatCompletionTool> = request
+                .tools
+                .iter()
+                .map(|tool| ChatCompletionTool {
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionObject {
+                        name: tool.name.clone(),
+                        description: Some(tool.description.clone()),
+                        parameters: Some(tool.parameters.clone()),
+                        strict: None,
+                    },
+                })
+                .collect();
+            let choice = match request.tool_choice {
+                ToolChoice::Auto => ChatCompletionToolChoiceOption::Auto,
+                ToolChoice::None => ChatCompletionToolChoiceOption::None,
+            };
+            args.tools(tools).tool_choice(choice);
+        }
+        if stream {
+            args.stream(true)
+                .stream_options(ChatCompletionStreamOptions {
+                    include_usage: true,
+                });
+        }
+        args.build()
+            .map_err(|e| LlmError::invalid_response("OpenAI", e))
+    }
+}
+
+/// Convert a user message, sending tool results as messages of their own before it
+fn user_messages(message: &ChatMessage) -> Vec<ChatCompletionRequestMessage> {
+    let results = message.tool_results.iter().map(|result| {
+        ChatCompletionRequestToolMessage {
+            content: result.content.clone().into(),
+            tool_call_id: result.call_id.clone(),
+        }
+        .into()
+    });
+    // Messages carrying tool results have no text of their own.
+    let text = (message.tool_results.is_empty() || !message.content.is_empty())
+        .then(|| user_message(message).into());
+    results.chain(text).collect()
+}
+
+/// Convert an assistant message with the tools it called
+fn assistant_message(message: &ChatMessage) -> ChatCompletionRequestAssistantMessage {
+    if message.tool_calls.is_empty() {
+        return message.content.as_str().into();
+    }
+
+    let tool_calls = message
+        .tool_calls
+        .iter()
+        .map(|call| ChatCompletionMessageToolCall {
+            id: call.id.clone(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: call.name.clone(),
+                arguments: call.arguments_object().to_string(),
+            },
+        })
+        .collect();
+    ChatCompletionRequestAssistantMessage {
+        content: (!message.content.is_empty()).then(|| message.content.as_str().into()),
+        tool_calls: Some(tool_calls),
+        ..Default::default()
+    }
+}
+
+/// Convert a user message, sending its images as data URLs next to the text
+fn user_message(message: &ChatMessage) -> ChatCompletionRequestUserMessage {
+    if message.images.is_empty() {
+        return message.content.as_str().into();
+    }
+
+    let images = message.images.iter().map(|image| {
+        ChatCompletionRequestMessageContentPartImage {
+            image_url: ImageUrl::from(image.data_url()),
+        }
+        .into()
+    });
+    let text = ChatCompletionRequestMessageContentPartText::from(message.content.as_str()).into();
+    let parts: Vec<ChatCompletionRequestUserMessageContentPart> = images.chain([text]).collect();
+    ChatCompletionRequestUserMessageContent::Array(parts).into()
+}
+
+impl From<CompletionUsage> for Usage {
+    fn from(usage: CompletionUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            streaming: true,
+            tools: true,
+        }
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let response: CreateChatCompletionResponse = self
+            .send(request, false)
+            .await?
+            .json()
+            .await
+            .map_err(|e| LlmError::invalid_response("OpenAI", e))?;
+        log::debug!("LLM response: {:?}", response);
+
+        // Extract the response content
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(LlmError::EmptyResponse)?;
+        if choice.finish_reason == Some(FinishReason::ContentFilter) {
+            return Err(LlmError::ContentFiltered { provider: "OpenAI" });
+        }
+        let truncated = choice.finish_reason == Some(FinishReason::Length);
+        let tool_calls: Vec<ToolCall> = choice
+            .message
+            .tool_calls
+            .unwrap_or_default()
+            .into_iter()
+            .map(|call| tool_call(call.id, call.function.name, &call.function.arguments))
+            .collect();
+        let content = choice.message.content.unwrap_or_default();
+        if content.is_empty() && tool_calls.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+
+        Ok(ChatResponse {
+            content,
+            usage: response.usage.map(Usage::from),
+            tool_calls,
+            truncated,
+        })
+    }
+
+    async fn stream(&self, request: &ChatRequest) -> Result<ChatStream, LlmError> {
+        let response = self.send(request, true).await?;
+
+        let events = server_sent_events("OpenAI", response)
+            .take_while(|event| {
+                futures::future::ready(!matches!(event, Ok(event) if event.data == "[DONE]"))
+            })
+            // Tool calls arrive in pieces and are passed on once the choice is finished.
+            .scan(ToolCallParts::default(), |tool_calls, event| {
+                let events = match event.and_then(|event| {
+                    serde_json::from_str::<CreateChatCompletionStreamResponse>(&event.data)
+                        .map_err(|e| LlmError::invalid_response("OpenAI", e))
+                }) {
+                    Ok(chunk) => {
+                        log::debug!("LLM response chunk: {:?}", chunk);
+
+                        // The final chunk carries the usage of the whole request and no choices
+                        let choice = chunk.choices.into_iter().next();
+                        let finish_reason = choice.as_ref().and_then(|choice| choice.finish_reason);
+                        let delta = choice.map(|choice| choice.delta);
+                        let (content, calls) = delta
+                            .map(|delta| (delta.content, delta.tool_calls.unwrap_or_default()))
+                            .unwrap_or_default();
+                        for call in calls {
+                            let (name, arguments) = call
+                                .function
+                                .map(|function| (function.name, function.arguments))
+                                .unwrap_or_default();
+                            tool_calls.push(
+                                call.index,
+                                call.id,
+                                name,
+                                arguments.as_deref().unwrap_or_default(),
+                            );
+                        }
+                        let finished_calls = match finish_reason {
+                            Some(_) => tool_calls.finish(),
+                            None => Vec::new(),
+                        };
+
+                        let mut events: Vec<_> = content
+                            .map(StreamEvent::Delta)
+                            .into_iter()
+                            .chain(finished_calls.into_iter().map(StreamEvent::ToolCall))
+                            .chain(chunk.usage.map(|usage| StreamEvent::Usage(usage.into())))
+                            .chain(
+                                (finish_reason == Some(FinishReason::Length))
+                                    .then_some(StreamEvent::Truncated),
+                            )
+                            .map(Ok)
+                            .collect();
+                        if finish_reason == Some(FinishReason::ContentFilter) {
+                            events.push(Err(LlmError::ContentFiltered { provider: "OpenAI" }));
+                        }
+                        events
+                    }
+                    Err(error) => vec![Err(error)],
+                };
+                futures::future::ready(Some(futures::stream::iter(events)))
+            });
+        Ok(events.flatten().boxed())
+    }
+}
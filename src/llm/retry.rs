@@ -0,0 +1,140 @@
+//! Retrying failed requests
+//!
+//! Rate limits, overloaded servers and network hiccups are usually over within seconds, so
+//! requests failing with a transient error are tried again after an exponentially growing,
+//! jittered delay, or after the delay the API asked for in `Retry-After`. A request only
+//! counts as successful once the first part of its answer has arrived: errors before that
+//! point are retried, errors after it are passed on, since part of the answer may already
+//! have been shown.
+
+use std::env;
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::time::timeout;
+
+use super::{ChatRequest, ChatStream, LlmError, LlmProvider};
+
+/// How requests are timed out and retried
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Time allowed for the first part of an answer, and between its following parts
+    pub timeout: Duration,
+    /// How often a failed request is tried again
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for each following one
+    pub base_delay: Duration,
+    /// The longest delay between two tries; requests asking for more are not retried
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Read the policy from `LLM_REQUEST_TIMEOUT_SECS`, `LLM_MAX_RETRIES`,
+    /// `LLM_RETRY_BASE_DELAY_MS` and `LLM_RETRY_MAX_DELAY_SECS`
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            timeout: Duration::from_secs(read("LLM_REQUEST_TIMEOUT_SECS", 60)),
+            max_retries: read("LLM_MAX_RETRIES", 2) as u32,
+            base_delay: Duration::from_millis(read("LLM_RETRY_BASE_DELAY_MS", 500)),
+            max_delay: Duration::from_secs(read("LLM_RETRY_MAX_DELAY_SECS", 30)),
+        }
+    }
+
+    /// The delay before retry number `attempt` (counting from 0) after `error`
+    ///
+    /// Returns `None` if the error should not be retried.
+    fn delay(&self, attempt: u32, error: &LlmError) -> Option<Duration> {
+        if attempt >= self.max_retries || !error.is_transient() {
+            return None;
+        }
+        // Asking to wait longer than the longest delay moves on to the fallback models.
+        if let Some(retry_after) = error.retry_after() {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+
+        // Exponential backoff with jitter, so that concurrent requests do not retry in lockstep
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        Some(backoff.mul_f64(rand::random_range(0.5..=1.0)))
+    }
+}
+
+/// Send a request, retrying it according to the policy, and stream its answer
+pub async fn stream_with_retries(
+    provider: &dyn LlmProvider,
+    request: &ChatRequest,
+    policy: RetryPolicy,
+) -> Result<ChatStream, LlmError> {
+    let mut attempt = 0;
+    loop {
+        let error = match start_stream(provider, request, policy.timeout).await {
+            Ok(stream) => return Ok(with_idle_timeout(stream, policy.timeout)),
+            Err(error) => error,
+        };
+
+        let Some(delay) = policy.delay(attempt, &error) else {
+            return Err(error);
+        };
+        log::warn!(
+            "LLM request to {} failed ({}), retrying in {:?}: {}",
+            request.model,
+            error.kind().label(),
+            delay,
+            error
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Send a request and wait for the first part of its answer
+async fn start_stream(
+    provider: &dyn LlmProvider,
+    request: &ChatRequest,
+    limit: Duration,
+) -> Result<ChatStream, LlmError> {
+    let mut stream = if provider.capabilities().streaming {
+        timeout(limit, provider.stream(request))
+            .await
+            .map_err(|_| LlmError::Timeout)??
+    } else {
+        timeout(limit, provider.chat(request))
+            .await
+            .map_err(|_| LlmError::Timeout)??
+            .into_stream()
+    };
+
+    // Errors may only show up once the stream is read, so peek at its first event.
+    match timeout(limit, stream.next())
+        .await
+        .map_err(|_| LlmError::Timeout)?
+    {
+        Some(Ok(first)) => Ok(futures::stream::once(async { Ok(first) })
+            .chain(stream)
+            .boxed()),
+        Some(Err(error)) => Err(error),
+        None => Err(LlmError::EmptyResponse),
+    }
+}
+
+/// End a stream with a timeout error when no event arrives for too long
+fn with_idle_timeout(stream: ChatStream, limit: Duration) -> ChatStream {
+    futures::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        match timeout(limit, stream.next()).await {
+            Ok(Some(event)) => Some((event, Some(stream))),
+            Ok(None) => None,
+            Err(_) => Some((Err(LlmError::Timeout), None)),
+        }
+    })
+    .boxed()
+}
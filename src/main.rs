@@ -1,22 +1,69 @@
-//! Telegram Bot with OpenAI integration
+//! Telegram Bot with LLM integration
 //!
-//! A Telegram bot that responds to @ mentions using the OpenAI chat completions API.
-//! It supports configurable model selection and customizable greeting messages.
+//! A Telegram bot that responds to @ mentions and replies to its own answers in groups,
+//! and to every message in private chats, using OpenAI-compatible, Anthropic, Ollama or
+//! Gemini models. It supports configurable model selection, customizable greeting
+//! messages, per-chat conversation memory and reply-chain threading, all persisted in a
+//! local SQLite database. Answers are streamed into the reply as they are generated and
+//! rendered from Markdown into Telegram formatting.
+
+mod access;
+mod context_window;
+mod controls;
+mod history;
+mod i18n;
+mod imagine;
+mod llm;
+mod markdown;
+mod mcp;
+mod media;
+mod models;
+mod output;
+mod rate_limit;
+mod speech;
+mod storage;
+mod streaming;
+mod system_prompt;
+mod tools;
+mod trigger;
+mod usage;
 
 use std::env;
 use std::sync::Arc;
 
-use async_openai::{
-    Client,
-    config::OpenAIConfig,
+use futures::StreamExt;
+use teloxide::{
+    dispatching::UpdateFilterExt,
+    prelude::*,
     types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestUserMessage,
-        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs,
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Me, MessageEntityKind,
+        MessageEntityRef, MessageId, ReplyParameters, UpdateKind,
     },
+    utils::command::BotCommands,
 };
-use teloxide::{
-    dispatching::UpdateFilterExt, prelude::*, types::MessageEntityKind, utils::command::BotCommands,
+
+use access::{AccessPolicy, Role as AccessRole};
+use context_window::{ContextConfig, Summary, TokenCounter};
+use controls::{Action, ActiveAnswers, StopSignal};
+use history::{ConversationHistory, ConversationKey, HistoryWindow, Turn};
+use i18n::Language;
+use imagine::{GeneratedImage, ImageGenerator, ImagineRequest};
+use llm::{
+    ChatMessage, ChatRequest, ChatStream, Image, LlmError, RetryPolicy, Role, StreamEvent,
+    ToolCall, ToolChoice, Usage,
 };
+use mcp::McpServers;
+use media::{AudioSource, ImageSource};
+use models::{ModelInfo, ModelRegistry, ModelScope};
+use output::{MAX_MESSAGE_LEN, OutputConfig};
+use rate_limit::{Permit, RateLimiter};
+use speech::{MAX_SPEECH_LEN, Speaker, Transcriber};
+use storage::{SqliteStorage, Storage, UsagePeriod, UsageRecord, UsageScope};
+use streaming::StreamingReply;
+use system_prompt::{SystemPrompts, TEMPLATE_VARIABLES};
+use tools::{ToolContext, Tools};
+use trigger::{Trigger, TriggerPolicy};
+use usage::{BudgetExceeded, Budgets};
 
 /// Bot commands that users can invoke
 #[derive(BotCommands, Clone, Debug)]
@@ -26,53 +73,128 @@ enum Command {
     Help,
     #[command(description = "Start the bot")]
     Start,
+    #[command(
+        description = "Show, set (/system <prompt>) or clear (/system clear) the system prompt"
+    )]
+    System(String),
+    #[command(description = "Choose the model, from a menu or by name (/model <name>)")]
+    Model(String),
+    #[command(description = "Turn voice replies on or off (/voice on, /voice off)")]
+    Voice(String),
+    #[command(description = "Generate an image (/imagine [size=1024x1024] [quality=hd] <prompt>)")]
+    Imagine(String),
+    #[command(
+        description = "List MCP servers, or turn one on or off (/mcp on <name>, /mcp off <name>)"
+    )]
+    Mcp(String),
+    #[command(description = "Show the tokens and money you and this chat have spent")]
+    Usage,
+    #[command(description = "Forget this conversation and start over")]
+    Reset,
+    #[command(description = "Forget an exchange, replying to its question or answer")]
+    Forget,
+    #[command(description = "Show what I remember of this conversation")]
+    History,
 }
 
+/// The longest excerpt of a conversation summary shown by /history, in characters
+const MAX_SUMMARY_EXCERPT_LEN: usize = 500;
+
+/// The longest caption Telegram allows on a photo, in characters
+const MAX_CAPTION_LEN: usize = 1024;
+
+/// Prefix of the callback data of the model selection buttons, followed by the model's index
+const MODEL_CALLBACK_PREFIX: &str = "model:";
+
+/// The mark of prompts that came with an image, as they are remembered
+const IMAGE_MARKER: &str = "[image] ";
+
+/// The request to go on with an incomplete answer
+const CONTINUE_PROMPT: &str =
+    "Continue your answer exactly where it stopped, without repeating anything.";
+
 /// Bot configuration structure
 #[derive(Clone, Debug)]
 struct BotConfig {
     /// The greeting message to display for /start and /help commands
     greeting_message: String,
-    /// The OpenAI API client
-    oai_client: Arc<Client<OpenAIConfig>>,
-    /// The LLM model to use for completions
-    llm_model_name: String,
+    /// The models users can choose from and their providers
+    models: Arc<ModelRegistry>,
+    /// How much conversation history is sent with each request
+    history_window: HistoryWindow,
+    /// Path of the SQLite database holding the persistent state
+    database_path: String,
+    /// Who the bot serves and who administers it
+    access: Arc<AccessPolicy>,
+    /// How often users and chats may ask the model
+    rate_limiter: Arc<RateLimiter>,
+    /// How many tokens and how much money users and chats may spend
+    budgets: Budgets,
+    /// How requests are fitted into the models' context windows
+    context: ContextConfig,
+    /// How answers are delivered to the chat
+    output: OutputConfig,
+    /// Which messages the bot answers in each type of chat
+    trigger_policy: TriggerPolicy,
+    /// The default and per-chat system prompts
+    system_prompts: SystemPrompts,
+    /// How failed LLM requests are timed out and retried
+    retry_policy: RetryPolicy,
+    /// The speech-to-text API for recordings, if configured
+    transcriber: Option<Transcriber>,
+    /// The text-to-speech API for voice replies, if configured
+    speaker: Option<Speaker>,
+    /// The image generation API for /imagine, if configured
+    image_generator: Option<ImageGenerator>,
+    /// The tools models can call
+    tools: Tools,
+    /// The MCP servers chats can add tools from
+    mcp: Arc<McpServers>,
+    /// The answers streaming in, to stop them
+    answers: Arc<ActiveAnswers>,
 }
 
 impl BotConfig {
     /// Create a new bot configuration from environment variables
-    fn from_env() -> Self {
-        // Setup OpenAI endpoint.
-        let openai_api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-        let openai_api_base = env::var("OPENAI_API_BASE").expect("OPENAI_API_BASE must be set");
-        let openai_model_name =
-            env::var("OPENAI_MODEL_NAME").expect("OPENAI_MODEL_NAME must be set");
-        let openai_model_alias =
-            env::var("OPENAI_MODEL_ALIAS").unwrap_or(openai_model_name.clone());
-        let openai_config = OpenAIConfig::new()
-            .with_api_key(openai_api_key)
-            .with_api_base(openai_api_base);
-        let openai_client = Arc::new(Client::with_config(openai_config));
+    fn from_env(me: &Me) -> Self {
+        // Setup the models and their providers.
+        let models = Arc::new(ModelRegistry::from_env());
 
         // Bot info.
-        let bot_username = env::var("BOT_USERNAME").expect("BOT_USERNAME must be set");
         let greeting_message = env::var("BOT_GREETING_MESSAGE").unwrap_or_else(|_| {
             format!(
-                "Hello! I'm an AI assistant bot using {}. Mention me (@{}) in a message to talk to me.", 
-                openai_model_alias, bot_username,
+                "Hello! I'm an AI assistant bot using {}. Mention me ({}) in a message to talk to me.",
+                models.default_model().display_name(),
+                me.mention(),
             )
         });
 
         Self {
             greeting_message,
-            oai_client: openai_client,
-            llm_model_name: openai_model_name,
+            models,
+            history_window: HistoryWindow::from_env(),
+            database_path: env::var("DATABASE_PATH")
+                .unwrap_or_else(|_| "telegram-bot-llm.sqlite3".to_string()),
+            access: Arc::new(AccessPolicy::from_env()),
+            rate_limiter: Arc::new(RateLimiter::from_env()),
+            budgets: Budgets::from_env(),
+            context: ContextConfig::from_env(),
+            output: OutputConfig::from_env(),
+            trigger_policy: TriggerPolicy::from_env(),
+            system_prompts: SystemPrompts::from_env(),
+            retry_policy: RetryPolicy::from_env(),
+            transcriber: Transcriber::from_env(),
+            speaker: Speaker::from_env(),
+            image_generator: ImageGenerator::from_env(),
+            tools: Tools::from_env(),
+            mcp: Arc::new(McpServers::from_env()),
+            answers: Arc::new(ActiveAnswers::default()),
         }
     }
 
-    /// Get a clone of the OpenAI client
-    fn openai_client(&self) -> Arc<Client<OpenAIConfig>> {
-        Arc::clone(&self.oai_client)
+    /// Get a clone of the model registry
+    fn model_registry(&self) -> Arc<ModelRegistry> {
+        Arc::clone(&self.models)
     }
 }
 
@@ -84,17 +206,46 @@ async fn main() {
     // Get Telegram bot token from environment variable
     let bot = Bot::from_env();
 
+    // Fetch the bot's own identity, used to recognize mentions of it
+    let me = bot.get_me().await.expect("Failed to fetch bot info");
+
     // Load bot configuration from environment
-    let config = BotConfig::from_env();
+    let config = BotConfig::from_env(&me);
+
+    let default_model = config.models.default_model();
+    log::info!(
+        "LLM bot started with {} models, default model: {} ({})",
+        config.models.models().len(),
+        default_model.name,
+        config.models.provider(default_model).name()
+    );
+
+    // Open the persistent storage
+    let storage: Arc<dyn Storage> =
+        Arc::new(SqliteStorage::open(&config.database_path).expect("Failed to open the database"));
 
-    log::info!("LLM bot started with model: {}", config.llm_model_name);
+    // Conversation memory shared by all handlers
+    let history = Arc::new(ConversationHistory::new(
+        config.history_window,
+        Arc::clone(&storage),
+    ));
 
     // Set up the message handler
     let handler = create_message_handler(&config);
 
     // Start the bot
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![config.openai_client()])
+        .dependencies(dptree::deps![
+            config.model_registry(),
+            Arc::clone(&config.access),
+            history,
+            storage
+        ])
+        // Buttons like Stop must get through while an answer in the same chat streams in.
+        .distribution_function(|update| match update.kind {
+            UpdateKind::CallbackQuery(_) => None,
+            _ => update.chat().map(|chat| chat.id),
+        })
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -102,11 +253,79 @@ async fn main() {
 }
 
 /// Handle commands like /start and /help
-async fn command_handler(bot: Bot, msg: Message, greeting: String) -> ResponseResult<()> {
-    match Command::parse(msg.text().unwrap_or_default(), "bot") {
+async fn command_handler(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    history: Arc<ConversationHistory>,
+    storage: Arc<dyn Storage>,
+    config: &BotConfig,
+) -> ResponseResult<()> {
+    match Command::parse(msg.text().unwrap_or_default(), me.username()) {
         Ok(cmd) => match cmd {
             Command::Help | Command::Start => {
-                bot.send_message(msg.chat.id, greeting).await?;
+                bot.send_message(msg.chat.id, config.greeting_message.clone())
+                    .await?;
+            }
+            Command::System(prompt) => {
+                system_command(
+                    &bot,
+                    &msg,
+                    &config.access,
+                    &*storage,
+                    &config.system_prompts,
+                    prompt.trim(),
+                )
+                .await?;
+            }
+            Command::Model(name) => {
+                model_command(
+                    &bot,
+                    &msg,
+                    &config.access,
+                    &config.models,
+                    &*storage,
+                    name.trim(),
+                )
+                .await?;
+            }
+            Command::Voice(argument) => {
+                let enabled = config.speaker.is_some();
+                voice_command(
+                    &bot,
+                    &msg,
+                    &config.access,
+                    &*storage,
+                    enabled,
+                    argument.trim(),
+                )
+                .await?;
+            }
+            Command::Imagine(arguments) => {
+                imagine_command(&bot, &msg, &*storage, config, &arguments).await?;
+            }
+            Command::Usage => {
+                usage_command(&bot, &msg, &*storage, &config.budgets).await?;
+            }
+            Command::Reset => {
+                reset_command(&bot, &msg, &config.access, &history, &*storage).await?;
+            }
+            Command::Forget => {
+                forget_command(&bot, &msg, &history).await?;
+            }
+            Command::History => {
+                history_command(&bot, &msg, &history, &*storage, config).await?;
+            }
+            Command::Mcp(arguments) => {
+                mcp_command(
+                    &bot,
+                    &msg,
+                    &config.access,
+                    &*storage,
+                    &config.mcp,
+                    arguments.trim(),
+                )
+                .await?;
             }
         },
         Err(_) => {
@@ -117,81 +336,1536 @@ async fn command_handler(bot: Bot, msg: Message, greeting: String) -> ResponseRe
     Ok(())
 }
 
-/// Extract the message text from a Telegram message
-fn extract_message_text(msg: &Message) -> String {
-    msg.text().unwrap_or("Hello").to_string()
+/// Show, set or clear the system prompt of a chat
+async fn system_command(
+    bot: &Bot,
+    msg: &Message,
+    access: &AccessPolicy,
+    storage: &dyn Storage,
+    prompts: &SystemPrompts,
+    argument: &str,
+) -> ResponseResult<()> {
+    let reply = if argument.is_empty() {
+        match system_prompt::chat_template(storage, msg.chat.id) {
+            Ok(Some(template)) => format!("This chat's system prompt:\n\n{}", template),
+            Ok(None) => match prompts.default_template() {
+                Some(template) => {
+                    format!("This chat uses the default system prompt:\n\n{}", template)
+                }
+                None => format!(
+                    "This chat has no system prompt. Set one with /system <prompt>, \
+                     using {} to fill in details.",
+                    TEMPLATE_VARIABLES
+                ),
+            },
+            Err(error) => {
+                log::error!("Failed to load system prompt: {}", error);
+                "Sorry, I couldn't load the system prompt.".to_string()
+            }
+        }
+    } else if !is_sender_chat_admin(bot, access, msg).await? {
+        "Only chat administrators can change the system prompt.".to_string()
+    } else if argument.eq_ignore_ascii_case("clear") {
+        match system_prompt::clear_chat_template(storage, msg.chat.id) {
+            Ok(()) => "The system prompt of this chat is cleared.".to_string(),
+            Err(error) => {
+                log::error!("Failed to clear system prompt: {}", error);
+                "Sorry, I couldn't clear the system prompt.".to_string()
+            }
+        }
+    } else {
+        match system_prompt::set_chat_template(storage, msg.chat.id, argument) {
+            Ok(()) => "The system prompt of this chat is updated.".to_string(),
+            Err(error) => {
+                log::error!("Failed to save system prompt: {}", error);
+                "Sorry, I couldn't save the system prompt.".to_string()
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Turn voice replies of a chat on or off, toggling them without an argument
+async fn voice_command(
+    bot: &Bot,
+    msg: &Message,
+    access: &AccessPolicy,
+    storage: &dyn Storage,
+    enabled: bool,
+    argument: &str,
+) -> ResponseResult<()> {
+    let wanted = match argument.to_ascii_lowercase().as_str() {
+        "" => speech::voice_replies(storage, msg.chat.id)
+            .map(|current| !current)
+            .map_err(|error| log::error!("Failed to load voice setting: {}", error))
+            .ok(),
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    };
+
+    let reply = if !enabled {
+        "Voice replies are not available on this bot.".to_string()
+    } else if !argument.is_empty() && wanted.is_none() {
+        "Use /voice on or /voice off.".to_string()
+    } else if !is_sender_chat_admin(bot, access, msg).await? {
+        "Only chat administrators can change voice replies.".to_string()
+    } else if let Some(wanted) = wanted {
+        match speech::set_voice_replies(storage, msg.chat.id, wanted) {
+            Ok(()) if wanted => "I'll answer with voice messages in this chat.".to_string(),
+            Ok(()) => "I'll answer with text in this chat, except to voice notes.".to_string(),
+            Err(error) => {
+                log::error!("Failed to save voice setting: {}", error);
+                "Sorry, I couldn't save the voice setting.".to_string()
+            }
+        }
+    } else {
+        "Sorry, I couldn't load the voice setting.".to_string()
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// List the MCP servers and whether this chat uses them, or turn one on or off
+async fn mcp_command(
+    bot: &Bot,
+    msg: &Message,
+    access: &AccessPolicy,
+    storage: &dyn Storage,
+    servers: &McpServers,
+    arguments: &str,
+) -> ResponseResult<()> {
+    let (action, name) = arguments.split_once(' ').unwrap_or((arguments, ""));
+    let wanted = match action.to_ascii_lowercase().as_str() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    };
+
+    let reply = if servers.servers().is_empty() {
+        "No MCP servers are configured on this bot.".to_string()
+    } else if arguments.is_empty() {
+        let mut lines = vec!["MCP servers:".to_string()];
+        for server in servers.servers() {
+            let state = match servers.is_enabled(storage, msg.chat.id, server) {
+                Ok(true) => "on",
+                Ok(false) => "off",
+                Err(error) => {
+                    log::error!("Failed to load MCP setting: {}", error);
+                    "unknown"
+                }
+            };
+            let mut line = format!("• {} ({})", server.name(), state);
+            if let Some(description) = &server.config.description {
+                line.push_str(&format!(": {}", description));
+            }
+            lines.push(line);
+        }
+        lines.push("Turn one on or off with /mcp on <name> or /mcp off <name>.".to_string());
+        lines.join("\n")
+    } else if let (Some(wanted), Some(server)) = (wanted, servers.find(name.trim())) {
+        if !is_sender_chat_admin(bot, access, msg).await? {
+            "Only chat administrators can turn MCP servers on or off.".to_string()
+        } else {
+            match servers.set_enabled(storage, msg.chat.id, server, wanted) {
+                Ok(()) if !wanted => format!("{} is off in this chat.", server.name()),
+                // Connect right away, to tell what the server offers or that it's down
+                Ok(()) => match server.tools().await {
+                    Ok(tools) => format!(
+                        "{} is on in this chat, with {} tools.",
+                        server.name(),
+                        tools.len()
+                    ),
+                    Err(error) => {
+                        log::error!(
+                            "Failed to connect to MCP server {}: {}",
+                            server.name(),
+                            error
+                        );
+                        format!(
+                            "{} is on in this chat, but I can't reach it right now.",
+                            server.name()
+                        )
+                    }
+                },
+                Err(error) => {
+                    log::error!("Failed to save MCP setting: {}", error);
+                    "Sorry, I couldn't save the MCP setting.".to_string()
+                }
+            }
+        }
+    } else if wanted.is_some() && !name.trim().is_empty() {
+        format!("There is no MCP server called {}. See /mcp.", name.trim())
+    } else {
+        "Use /mcp, /mcp on <name> or /mcp off <name>.".to_string()
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Show what the sender and the chat have spent today and this month
+async fn usage_command(
+    bot: &Bot,
+    msg: &Message,
+    storage: &dyn Storage,
+    budgets: &Budgets,
+) -> ResponseResult<()> {
+    let mut scopes = Vec::new();
+    if let Some(user) = &msg.from {
+        scopes.push(("You", UsageScope::User(user.id)));
+    }
+    // In private chats the chat's usage is the user's.
+    if !msg.chat.is_private() {
+        scopes.push(("This chat", UsageScope::Chat(msg.chat.id)));
+    }
+
+    let mut sections = Vec::new();
+    for (name, scope) in scopes {
+        for (period, period_name) in [
+            (UsagePeriod::Today, "today"),
+            (UsagePeriod::ThisMonth, "this month"),
+        ] {
+            match usage::report(storage, budgets, scope, period) {
+                Ok(report) => sections.push(format!("{} {}: {}", name, period_name, report)),
+                Err(error) => {
+                    log::error!("Failed to load usage: {}", error);
+                    sections = vec!["Sorry, I couldn't load the usage.".to_string()];
+                    break;
+                }
+            }
+        }
+    }
+
+    bot.send_message(msg.chat.id, sections.join("\n\n"))
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Forget the conversation of a chat or forum topic, together with its summary
+async fn reset_command(
+    bot: &Bot,
+    msg: &Message,
+    access: &AccessPolicy,
+    history: &ConversationHistory,
+    storage: &dyn Storage,
+) -> ResponseResult<()> {
+    let conversation = ConversationKey::from_message(msg);
+    let reply = if !is_sender_chat_admin(bot, access, msg).await? {
+        "Only chat administrators can reset the conversation.".to_string()
+    } else {
+        let result = history.reset(conversation).and_then(|forgotten| {
+            context_window::clear_summary(storage, conversation).map(|()| forgotten)
+        });
+        match result {
+            Ok(forgotten) => {
+                log::info!(
+                    "Reset conversation of chat {}, forgetting {} turns",
+                    msg.chat.id,
+                    forgotten
+                );
+                "I forgot this conversation. Let's start over!".to_string()
+            }
+            Err(error) => {
+                log::error!("Failed to reset conversation: {}", error);
+                "Sorry, I couldn't reset the conversation.".to_string()
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Forget the exchange the command replies to, the question and the answer to it
+async fn forget_command(
+    bot: &Bot,
+    msg: &Message,
+    history: &ConversationHistory,
+) -> ResponseResult<()> {
+    let reply = match replied_message(msg) {
+        None => "Reply to a question of yours or an answer of mine with /forget.".to_string(),
+        Some(target) => match history.forget_exchange(msg.chat.id, target.id) {
+            Ok(0) => "I don't remember that message.".to_string(),
+            Ok(_) => "I forgot this exchange.".to_string(),
+            Err(error) => {
+                log::error!("Failed to forget exchange: {}", error);
+                "Sorry, I couldn't forget this exchange.".to_string()
+            }
+        },
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Show what the bot remembers of a conversation: its turns, their tokens and the summary
+async fn history_command(
+    bot: &Bot,
+    msg: &Message,
+    history: &ConversationHistory,
+    storage: &dyn Storage,
+    config: &BotConfig,
+) -> ResponseResult<()> {
+    let conversation = ConversationKey::from_message(msg);
+    let model = config
+        .models
+        .selected(storage, msg.chat.id, msg.from.as_ref().map(|user| user.id));
+    let counter = TokenCounter::for_model(model);
+
+    // The same turns and summary a new message would be answered with
+    let summary = if config.context.summarize {
+        context_window::load_summary(storage, conversation).unwrap_or_else(|error| {
+            log::error!("Failed to load conversation summary: {}", error);
+            None
+        })
+    } else {
+        None
+    };
+    let mut context = history.context(conversation, &counter);
+    if summary.is_some() {
+        uncovered_turns(history, conversation, &mut context, summary.as_ref());
+    }
+    let tokens: usize = context
+        .iter()
+        .map(|turn| counter.count(&turn.content))
+        .chain(summary.iter().map(|summary| counter.count(&summary.text)))
+        .sum();
+
+    let mut sections = Vec::new();
+    if context.is_empty() && summary.is_none() {
+        sections.push("I don't remember anything of this conversation yet.".to_string());
+    } else {
+        let questions = context
+            .iter()
+            .filter(|turn| turn.role == Role::User)
+            .count();
+        sections.push(format!(
+            "I remember {} messages of this conversation ({} questions), about {} tokens for {}.",
+            context.len(),
+            questions,
+            tokens,
+            model.display_name()
+        ));
+    }
+    if let Some(summary) = &summary {
+        let mut excerpt: String = summary.text.chars().take(MAX_SUMMARY_EXCERPT_LEN).collect();
+        if excerpt.len() < summary.text.len() {
+            excerpt.push('…');
+        }
+        sections.push(format!("Summary of the earlier conversation:\n{}", excerpt));
+    }
+    sections.push(format!(
+        "I keep up to {} messages or about {} tokens. Start over with /reset, or reply \
+         /forget to a question or answer to drop it.",
+        config.history_window.max_turns, config.history_window.max_tokens
+    ));
+
+    bot.send_message(msg.chat.id, sections.join("\n\n"))
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Generate an image from a prompt and send it to the chat
+async fn imagine_command(
+    bot: &Bot,
+    msg: &Message,
+    storage: &dyn Storage,
+    config: &BotConfig,
+    arguments: &str,
+) -> ResponseResult<()> {
+    let reply = |text: String| {
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+    };
+    let Some(generator) = &config.image_generator else {
+        reply("Image generation is not available on this bot.".to_string()).await?;
+        return Ok(());
+    };
+    let Some(user_id) = msg.from.as_ref().map(|user| user.id) else {
+        return Ok(());
+    };
+    let mut request = match ImagineRequest::parse(arguments) {
+        Ok(request) => request,
+        Err(usage) => {
+            reply(usage).await?;
+            return Ok(());
+        }
+    };
+
+    if !check_budgets(bot, msg, storage, config, true).await? {
+        return Ok(());
+    }
+
+    // Count the image up front, so requests at the same time can't exceed the quota together
+    match storage.record_image_generation(msg.chat.id, user_id, generator.daily_quota) {
+        Ok(true) => {}
+        Ok(false) => {
+            let text = format!(
+                "You have used all {} images for today. Please try again tomorrow.",
+                generator.daily_quota.unwrap_or_default()
+            );
+            reply(text).await?;
+            return Ok(());
+        }
+        Err(error) => log::error!("Failed to record image generation: {}", error),
+    }
+    let release = || {
+        if let Err(error) = storage.release_image_generation(msg.chat.id, user_id) {
+            log::error!("Failed to release image generation: {}", error);
+        }
+    };
+
+    let Some(_permit) = acquire_permit(bot, msg, config, true).await? else {
+        release();
+        return Ok(());
+    };
+
+    bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::UploadPhoto)
+        .await?;
+
+    if generator.rewrite_prompts {
+        let model = config.models.selected(storage, msg.chat.id, Some(user_id));
+        match imagine::rewrite_prompt(&config.models, model, &request.prompt, config.retry_policy)
+            .await
+        {
+            Ok((prompt, usage)) => {
+                request.prompt = prompt;
+                if let Some(usage) = usage {
+                    let record = UsageRecord {
+                        chat_id: msg.chat.id,
+                        user_id: Some(user_id),
+                        model: model.name.clone(),
+                        prompt_tokens: usage.prompt_tokens,
+                        completion_tokens: usage.completion_tokens,
+                        cost: model.cost(&usage),
+                    };
+                    if let Err(error) = storage.record_usage(&record) {
+                        log::error!("Failed to record usage: {}", error);
+                    }
+                }
+            }
+            Err(error) => log::warn!(
+                "Failed to rewrite image prompt ({}), using it as is: {}",
+                error.kind().label(),
+                error
+            ),
+        }
+    }
+
+    let image = match generator.generate(&request).await {
+        Ok(GeneratedImage::Data(data)) => InputFile::memory(data).file_name("image.png"),
+        Ok(GeneratedImage::Url(url)) => InputFile::url(url),
+        Err(error) => {
+            log::error!(
+                "Image generation failed ({}): {}",
+                error.kind().label(),
+                error
+            );
+            release();
+            let language = Language::of_user(msg.from.as_ref());
+            reply(i18n::llm_error_reply(error.kind(), language).to_string()).await?;
+            return Ok(());
+        }
+    };
+
+    let caption: String = request.prompt.chars().take(MAX_CAPTION_LEN).collect();
+    bot.send_photo(msg.chat.id, image)
+        .caption(caption)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Show the model menu, or select a model by name
+async fn model_command(
+    bot: &Bot,
+    msg: &Message,
+    access: &AccessPolicy,
+    models: &ModelRegistry,
+    storage: &dyn Storage,
+    name: &str,
+) -> ResponseResult<()> {
+    let Some(user_id) = msg.from.as_ref().map(|user| user.id) else {
+        return Ok(());
+    };
+
+    if name.is_empty() {
+        let current = models.selected(storage, msg.chat.id, Some(user_id));
+        bot.send_message(msg.chat.id, model_menu_text(models, current))
+            .reply_markup(model_keyboard(models, current))
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let reply = match models.find(name) {
+        None => format!(
+            "There is no model called {}. Use /model to see the available ones.",
+            name
+        ),
+        Some(_)
+            if models.scope() == ModelScope::Chat
+                && !is_sender_chat_admin(bot, access, msg).await? =>
+        {
+            "Only chat administrators can change the model.".to_string()
+        }
+        Some(model) => match models.select(storage, msg.chat.id, user_id, model) {
+            Ok(()) => format!("Switched to {}.", model.display_name()),
+            Err(error) => {
+                log::error!("Failed to save model selection: {}", error);
+                "Sorry, I couldn't save the model selection.".to_string()
+            }
+        },
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Handle presses of the model selection buttons
+async fn model_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    models: Arc<ModelRegistry>,
+    storage: Arc<dyn Storage>,
+    access: Arc<AccessPolicy>,
+) -> ResponseResult<()> {
+    let index: Option<usize> = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(MODEL_CALLBACK_PREFIX))
+        .and_then(|index| index.parse().ok());
+    let Some(menu) = query.regular_message() else {
+        bot.answer_callback_query(query.id.clone())
+            .text("This menu is no longer available, use /model again.")
+            .await?;
+        return Ok(());
+    };
+
+    let notice = match index.and_then(|index| models.models().get(index)) {
+        None => "This model is no longer available.".to_string(),
+        Some(_)
+            if models.scope() == ModelScope::Chat
+                && !access
+                    .is_chat_admin(&bot, &menu.chat, query.from.id)
+                    .await? =>
+        {
+            "Only chat administrators can change the model.".to_string()
+        }
+        Some(model) => match models.select(&*storage, menu.chat.id, query.from.id, model) {
+            Ok(()) => {
+                // The menu shows the chat's model, which only changes with chat-wide selections.
+                if models.scope() == ModelScope::Chat {
+                    let result = bot
+                        .edit_message_text(menu.chat.id, menu.id, model_menu_text(&models, model))
+                        .reply_markup(model_keyboard(&models, model))
+                        .await;
+                    if let Err(error) = result {
+                        log::warn!("Failed to update the model menu: {}", error);
+                    }
+                }
+                format!("Switched to {}.", model.display_name())
+            }
+            Err(error) => {
+                log::error!("Failed to save model selection: {}", error);
+                "Sorry, I couldn't save the model selection.".to_string()
+            }
+        },
+    };
+
+    bot.answer_callback_query(query.id.clone())
+        .text(notice)
+        .await?;
+    Ok(())
+}
+
+/// Handle the Stop, Regenerate and Continue buttons under answers
+async fn answer_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    history: Arc<ConversationHistory>,
+    storage: Arc<dyn Storage>,
+    me: Me,
+    config: &BotConfig,
+) -> ResponseResult<()> {
+    // The buttons sit under the bot's reply to the prompt
+    let action = query.data.as_deref().and_then(Action::parse);
+    let answer = query.regular_message();
+    let prompt = answer.and_then(|answer| answer.reply_to_message());
+    let (Some(action), Some(answer), Some(prompt)) = (action, answer, prompt) else {
+        bot.answer_callback_query(query.id.clone())
+            .text("This button is no longer available.")
+            .await?;
+        return Ok(());
+    };
+
+    let asked = prompt
+        .from
+        .as_ref()
+        .is_some_and(|user| user.id == query.from.id);
+    if !asked
+        && !config
+            .access
+            .is_chat_admin(&bot, &answer.chat, query.from.id)
+            .await?
+    {
+        bot.answer_callback_query(query.id.clone())
+            .text("Only whoever asked and chat administrators can use these buttons.")
+            .await?;
+        return Ok(());
+    }
+
+    let question = match action {
+        Action::Stop if config.answers.stop(answer.chat.id, answer.id) => {
+            Err("Stopping the answer.")
+        }
+        Action::Stop => Err("This answer is already complete."),
+        Action::Regenerate | Action::Continue => {
+            let turns = storage
+                .message(answer.chat.id, prompt.id)
+                .and_then(|prompt| Ok(prompt.zip(storage.message(answer.chat.id, answer.id)?)));
+            match turns {
+                Ok(Some((prompt, previous))) if previous.role == Role::Assistant => {
+                    if action == Action::Regenerate {
+                        Ok(Question::Again {
+                            prompt,
+                            previous: previous.message_id,
+                            requester: query.from.id,
+                        })
+                    } else {
+                        Ok(Question::Continue {
+                            prompt,
+                            previous,
+                            requester: query.from.id,
+                        })
+                    }
+                }
+                Ok(_) => Err("I don't remember this answer anymore."),
+                Err(error) => {
+                    log::error!("Failed to load answer: {}", error);
+                    Err("Sorry, I couldn't load this answer.")
+                }
+            }
+        }
+    };
+    let question = match question {
+        Ok(question) => question,
+        Err(notice) => {
+            bot.answer_callback_query(query.id.clone())
+                .text(notice)
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Whoever presses the button is charged for the new answer
+    let language = Language::of_user(Some(&query.from));
+    if let Some(exceeded) = spent_budget(&*storage, config, answer.chat.id, Some(query.from.id)) {
+        bot.answer_callback_query(query.id.clone())
+            .text(i18n::budget_exceeded_reply(exceeded, language))
+            .await?;
+        return Ok(());
+    }
+    let role = config
+        .access
+        .user_role(&bot, &answer.chat, query.from.id)
+        .await
+        .unwrap_or_else(|error| {
+            log::warn!("Failed to look up chat administrators: {}", error);
+            AccessRole::Member
+        });
+    let _permit = match config
+        .rate_limiter
+        .acquire(answer.chat.id, Some((query.from.id, role)))
+    {
+        Ok(permit) => permit,
+        Err(exceeded) => {
+            log::info!(
+                "Rate limit exceeded in chat {}: {:?}",
+                answer.chat.id,
+                exceeded
+            );
+            bot.answer_callback_query(query.id.clone())
+                .text(i18n::rate_limit_reply(exceeded, language))
+                .await?;
+            return Ok(());
+        }
+    };
+    bot.answer_callback_query(query.id.clone()).await?;
+
+    // The buttons move on to the new answer
+    if let Err(error) = bot
+        .edit_message_reply_markup(answer.chat.id, answer.id)
+        .await
+    {
+        log::warn!("Failed to remove the answer buttons: {}", error);
+    }
+    handle_mention(bot, prompt.clone(), question, history, storage, me, config).await
+}
+
+/// The text of the model menu
+fn model_menu_text(models: &ModelRegistry, current: &ModelInfo) -> String {
+    let list = models
+        .models()
+        .iter()
+        .map(|model| format!("• {}", model))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let prompt = match models.scope() {
+        ModelScope::Chat => "Choose the model for this chat:",
+        ModelScope::User => "Choose your model:",
+    };
+
+    format!(
+        "Current model: {}\n\nAvailable models:\n{}\n\n{}",
+        current.display_name(),
+        list,
+        prompt
+    )
+}
+
+/// The buttons of the model menu, one per model, the current one checked
+fn model_keyboard(models: &ModelRegistry, current: &ModelInfo) -> InlineKeyboardMarkup {
+    // Model names can exceed the 64 bytes Telegram allows for callback data, indices cannot.
+    let buttons = models.models().iter().enumerate().map(|(index, model)| {
+        let label = if model.name == current.name {
+            format!("✓ {}", model.display_name())
+        } else {
+            model.display_name().to_string()
+        };
+        let data = format!("{}{}", MODEL_CALLBACK_PREFIX, index);
+        vec![InlineKeyboardButton::callback(label, data)]
+    });
+
+    InlineKeyboardMarkup::new(buttons)
+}
+
+/// Check if the sender of a message may change the settings of its chat
+async fn is_sender_chat_admin(
+    bot: &Bot,
+    access: &AccessPolicy,
+    msg: &Message,
+) -> ResponseResult<bool> {
+    // Anonymous administrators send their messages on behalf of the chat itself.
+    if msg
+        .sender_chat
+        .as_ref()
+        .is_some_and(|chat| chat.id == msg.chat.id)
+    {
+        return Ok(true);
+    }
+
+    match &msg.from {
+        Some(user) => access.is_chat_admin(bot, &msg.chat, user.id).await,
+        None => Ok(false),
+    }
+}
+
+/// Extract the message text or caption from a Telegram message, without the mentions of
+/// the bot
+fn extract_message_text(msg: &Message, me: &Me) -> String {
+    // A bare image is a request to look at it, anything else a greeting.
+    let default = if media::has_image(msg) {
+        "Describe this image."
+    } else {
+        "Hello"
+    };
+    strip_bot_mentions(msg, me).unwrap_or_else(|| default.to_string())
+}
+
+/// The text or caption of a message without the mentions of the bot, if anything is left
+fn strip_bot_mentions(msg: &Message, me: &Me) -> Option<String> {
+    let text = msg.text().or(msg.caption())?;
+
+    // Cut out the mentions and join what is left around them
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for mention in bot_mentions(msg, me) {
+        segments.push(&text[start..mention.start()]);
+        start = mention.end();
+    }
+    segments.push(&text[start..]);
+
+    let prompt = segments
+        .into_iter()
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!prompt.is_empty()).then_some(prompt)
+}
+
+/// Handle voice notes, audio files and video notes
+///
+/// Recordings addressed to the bot are transcribed and answered like text messages; the
+/// others get their transcript as a reply, if enabled.
+async fn handle_recording(
+    bot: Bot,
+    msg: Message,
+    history: Arc<ConversationHistory>,
+    storage: Arc<dyn Storage>,
+    me: Me,
+    config: &BotConfig,
+) -> ResponseResult<()> {
+    let Some(transcriber) = &config.transcriber else {
+        return Ok(());
+    };
+    let Some(source) = AudioSource::of_message(&msg) else {
+        return Ok(());
+    };
+    // Recordings rarely come with a caption, so in chats answering everything they count too.
+    let addressed = matches!(config.trigger_policy.for_chat(&msg.chat), Trigger::Always)
+        || should_answer(&msg, &me, config.trigger_policy);
+    if !addressed && !transcriber.reply_unaddressed {
+        return Ok(());
+    }
+    if !check_budgets(&bot, &msg, &*storage, config, addressed).await? {
+        return Ok(());
+    }
+    // Transcribing counts as a request, and so does answering the transcript along with it.
+    let Some(_permit) = acquire_permit(&bot, &msg, config, addressed).await? else {
+        return Ok(());
+    };
+
+    bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
+        .await?;
+    let transcript = match source.download(&bot).await {
+        Ok(audio) => transcriber
+            .transcribe(audio)
+            .await
+            .map_err(|error| format!("{} ({})", error, error.kind().label())),
+        Err(error) => Err(format!("download failed: {}", error)),
+    };
+    let transcript = match transcript {
+        Ok(transcript) if !transcript.is_empty() => transcript,
+        result => {
+            if let Err(error) = result {
+                log::error!("Failed to transcribe recording: {}", error);
+            }
+            // Only complain to users who were talking to the bot.
+            if addressed {
+                bot.send_message(msg.chat.id, "Sorry, I couldn't make out this recording.")
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+            }
+            return Ok(());
+        }
+    };
+
+    if addressed {
+        // A caption is the instruction, the recording what it is about.
+        let prompt = match strip_bot_mentions(&msg, &me) {
+            Some(caption) => format!("{}\n\n{}", caption, transcript),
+            None => transcript,
+        };
+        let question = Question::New(prompt);
+        return handle_mention(bot, msg, question, history, storage, me, config).await;
+    }
+
+    for part in output::split_message(&transcript, MAX_MESSAGE_LEN) {
+        bot.send_message(msg.chat.id, part)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+    }
+    Ok(())
+}
+
+/// What the bot is asked to answer
+enum Question {
+    /// A new message, with its text
+    New(String),
+    /// A prompt answered before, to answer again in place of the previous answer
+    Again {
+        prompt: Turn,
+        previous: MessageId,
+        /// The user who pressed the button, who is charged for the answer
+        requester: UserId,
+    },
+    /// An incomplete answer to a prompt, to go on with
+    Continue {
+        prompt: Turn,
+        previous: Turn,
+        /// The user who pressed the button, who is charged for the answer
+        requester: UserId,
+    },
+}
+
+/// Count a request against the rate limits of its sender and chat
+///
+/// Returns the permit to hold until the request is done, or `None` when a limit is exceeded,
+/// in which case the sender is asked to slow down if `reply` is set.
+async fn acquire_permit(
+    bot: &Bot,
+    msg: &Message,
+    config: &BotConfig,
+    reply: bool,
+) -> ResponseResult<Option<Permit>> {
+    let role = config.access.role(bot, msg).await.unwrap_or_else(|error| {
+        log::warn!("Failed to look up chat administrators: {}", error);
+        AccessRole::Member
+    });
+    let user = msg.from.as_ref().map(|user| (user.id, role));
+    match config.rate_limiter.acquire(msg.chat.id, user) {
+        Ok(permit) => Ok(Some(permit)),
+        Err(exceeded) => {
+            log::info!(
+                "Rate limit exceeded in chat {}: {:?}",
+                msg.chat.id,
+                exceeded
+            );
+            if reply {
+                let language = Language::of_user(msg.from.as_ref());
+                bot.send_message(msg.chat.id, i18n::rate_limit_reply(exceeded, language))
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Turn down users and chats that have spent their budget; the bot's administrators have none
+///
+/// Returns whether the request may go ahead. When it may not, the sender is told so if
+/// `reply` is set.
+async fn check_budgets(
+    bot: &Bot,
+    msg: &Message,
+    storage: &dyn Storage,
+    config: &BotConfig,
+    reply: bool,
+) -> ResponseResult<bool> {
+    let user_id = msg.from.as_ref().map(|user| user.id);
+    let Some(exceeded) = spent_budget(storage, config, msg.chat.id, user_id) else {
+        return Ok(true);
+    };
+    if reply {
+        let language = Language::of_user(msg.from.as_ref());
+        bot.send_message(msg.chat.id, i18n::budget_exceeded_reply(exceeded, language))
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+    }
+    Ok(false)
 }
 
-/// Handle mentions to the bot
+/// Find the budget a user or their chat has spent, if any
+fn spent_budget(
+    storage: &dyn Storage,
+    config: &BotConfig,
+    chat_id: ChatId,
+    user_id: Option<UserId>,
+) -> Option<BudgetExceeded> {
+    if user_id.is_some_and(|user_id| config.access.is_bot_admin(user_id)) {
+        return None;
+    }
+    match config.budgets.check(storage, chat_id, user_id) {
+        Ok(Some(exceeded)) => {
+            log::info!("Budget exceeded in chat {}: {:?}", chat_id, exceeded);
+            Some(exceeded)
+        }
+        Ok(None) => None,
+        Err(error) => {
+            log::error!("Failed to check budgets: {}", error);
+            None
+        }
+    }
+}
+
+/// Leave the turns a summary covers out of a conversation's context, returning the turns
+/// that fell out of the context and are not covered yet, oldest first
+fn uncovered_turns(
+    history: &ConversationHistory,
+    conversation: ConversationKey,
+    context: &mut Vec<Turn>,
+    summary: Option<&Summary>,
+) -> Vec<Turn> {
+    // The turns that fell out come right before the context, so the summary's end is looked
+    // for in both together.
+    let mut turns = history.dropped(conversation, context);
+    let dropped = turns.len();
+    turns.append(context);
+    let covered = summary.map_or(0, |summary| summary.covered(&turns));
+    *context = turns.split_off(dropped.max(covered));
+    turns.drain(..covered.min(dropped));
+    turns
+}
+
+/// Handle mentions to the bot, answering the question asked by `msg`
+///
+/// The caller has checked the budgets and holds the rate limiter's permit for the request.
 async fn handle_mention(
     bot: Bot,
     msg: Message,
-    client: Arc<Client<OpenAIConfig>>,
-    model_name: &str,
+    question: Question,
+    history: Arc<ConversationHistory>,
+    storage: Arc<dyn Storage>,
+    me: Me,
+    config: &BotConfig,
 ) -> ResponseResult<()> {
-    // Extract the message text without the mention
-    let message_text = extract_message_text(&msg);
+    let models = &config.models;
+
+    // Keep track of the chats the bot is used in
+    if let Err(error) = storage.save_chat(&msg.chat) {
+        log::error!("Failed to save chat: {}", error);
+    }
+    // The tokens spent are counted for whoever asked for the answer
+    let requester = match &question {
+        Question::New(_) => msg.from.as_ref().map(|user| user.id),
+        Question::Again { requester, .. } | Question::Continue { requester, .. } => {
+            Some(*requester)
+        }
+    };
+
+    // Continue the reply chain when replying, otherwise the recent conversation. Prompts
+    // answered before are answered from the turns that came before them.
+    let conversation = ConversationKey::from_message(&msg);
+    let (parent, parent_id) = match &question {
+        Question::New(_) => {
+            let parent = replied_message(&msg);
+            (parent, parent.map(|parent| parent.id))
+        }
+        Question::Again { prompt, .. } | Question::Continue { prompt, .. } => {
+            (None, prompt.reply_to)
+        }
+    };
+    let selected = models.selected(
+        &*storage,
+        msg.chat.id,
+        msg.from.as_ref().map(|user| user.id),
+    );
+    let counter = TokenCounter::for_model(selected);
+    let mut context = match parent_id {
+        Some(parent_id) => {
+            let chain = history.reply_chain(msg.chat.id, parent_id, &counter);
+            match parent {
+                Some(parent) if chain.is_empty() => {
+                    unseen_parent_turn(parent, &me).into_iter().collect()
+                }
+                _ => chain,
+            }
+        }
+        None => history.context(conversation, &counter),
+    };
+    if !matches!(question, Question::New(_)) {
+        context.truncate(history::turns_before(&context, msg.id));
+    }
+
+    // Summaries replace the turns they cover, and take in the turns falling out of the context
+    let summarize = config.context.summarize && parent_id.is_none();
+    let summary = if summarize {
+        context_window::load_summary(&*storage, conversation).unwrap_or_else(|error| {
+            log::error!("Failed to load conversation summary: {}", error);
+            None
+        })
+    } else {
+        None
+    };
+    let mut dropped = Vec::new();
+    if summarize {
+        dropped = uncovered_turns(&history, conversation, &mut context, summary.as_ref());
+    }
+
+    // Incomplete answers are continued after the prompt and the answer so far
+    let message_text = match &question {
+        Question::New(text) => text.clone(),
+        Question::Again { prompt, .. } => prompt
+            .content
+            .strip_prefix(IMAGE_MARKER)
+            .unwrap_or(&prompt.content)
+            .to_string(),
+        Question::Continue {
+            prompt, previous, ..
+        } => {
+            context.extend([prompt.clone(), previous.clone()]);
+            CONTINUE_PROMPT.to_string()
+        }
+    };
+
+    // Look at the message's image, or at the image it replies to
+    let image_source = match question {
+        Question::Continue { .. } => None,
+        _ => ImageSource::of_message(&msg).or_else(|| parent.and_then(ImageSource::of_message)),
+    };
+    if image_source.is_some() && !selected.vision {
+        let text = format!(
+            "{} can't see images. Choose a model with vision using /model.",
+            selected.display_name()
+        );
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
 
     // Send a "typing" action to show the bot is processing
     bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
         .await?;
 
-    // Send request to OpenAI and handle the response
-    match send_openai_request(&client, model_name, &message_text).await {
-        Ok(content) => {
-            // Reply with the AI-generated response
-            bot.send_message(msg.chat.id, content).await?;
+    let mut images = Vec::new();
+    if let Some(source) = image_source {
+        match source.download(&bot).await {
+            Ok(image) => images.push(image),
+            Err(error) => {
+                log::error!("Failed to download image: {}", error);
+                bot.send_message(msg.chat.id, "Sorry, I couldn't download the image.")
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+
+    // The built-in tools and those of the MCP servers the chat uses, connecting to the servers
+    // only for models that call tools
+    let calls_tools = config.tools.max_rounds > 0 && models.provider(selected).capabilities().tools;
+    let tools = if calls_tools {
+        config
+            .tools
+            .with(config.mcp.tools_for_chat(&*storage, msg.chat.id).await)
+    } else {
+        config.tools.clone()
+    };
+
+    // Reply with a placeholder that is filled in as the answer streams in, and can be stopped
+    let buttons = config.output.buttons.then(controls::streaming_keyboard);
+    let mut reply = StreamingReply::start(&bot, &msg, config.output, buttons).await?;
+    let stop = config.answers.start(msg.chat.id, reply.message_id());
+
+    // Send request to the LLM and handle the response
+    let mut system = config.system_prompts.for_message(&*storage, &msg, &me);
+    if let Some(summary) = &summary {
+        system = Some(summary.extend_system_prompt(system));
+    }
+    let has_images = !images.is_empty();
+    let mut request = build_request(&selected.name, system, &context, &message_text, images);
+    // Tool schemas take up context too, so they are part of the request before it is fitted
+    if calls_tools && !tools.is_empty() {
+        request.tools = tools.specs();
+    }
+    let trimmed =
+        context_window::fit_request(&mut request, selected, config.context.reserved_tokens);
+    if summarize {
+        dropped.extend_from_slice(&context[..trimmed]);
+    }
+    let tool_context = ToolContext {
+        chat_id: msg.chat.id,
+        storage: Arc::clone(&storage),
+    };
+    let result = answer_with_tools(
+        config,
+        selected,
+        request,
+        &tools,
+        &mut reply,
+        &tool_context,
+        &stop,
+    )
+    .await;
+    match result {
+        Ok((
+            model,
+            Completion {
+                content,
+                usage,
+                truncated,
+                ..
+            },
+        )) => {
+            // Count the tokens spent on this request
+            if let Some(usage) = usage {
+                let record = UsageRecord {
+                    chat_id: msg.chat.id,
+                    user_id: requester,
+                    model: model.name.clone(),
+                    prompt_tokens: usage.prompt_tokens,
+                    completion_tokens: usage.completion_tokens,
+                    cost: model.cost(&usage),
+                };
+                if let Err(error) = storage.record_usage(&record) {
+                    log::error!("Failed to record usage: {}", error);
+                }
+            }
+
+            // Show the complete AI-generated response, offering to regenerate or continue it
+            let buttons = config
+                .output
+                .buttons
+                .then(|| controls::answer_keyboard(truncated));
+            let answer_id = reply.finish(&content, buttons).await?;
+
+            // Read it out to users who spoke, and in chats that asked for voice replies
+            if let Some(speaker) = &config.speaker {
+                let wants_voice = msg.voice().is_some()
+                    || speech::voice_replies(&*storage, msg.chat.id).unwrap_or_else(|error| {
+                        log::error!("Failed to load voice setting: {}", error);
+                        false
+                    });
+                if wants_voice {
+                    send_voice_answer(&bot, msg.chat.id, speaker, &content, answer_id).await?;
+                }
+            }
+
+            // Remember the exchange for follow-up questions and reply chains. Only the text is
+            // kept, marked where it came with an image. New answers to a prompt replace the
+            // previous one, continued answers being remembered whole.
+            let mut answer = Turn {
+                role: Role::Assistant,
+                content,
+                message_id: answer_id,
+                reply_to: Some(msg.id),
+            };
+            match question {
+                Question::New(_) => {
+                    let prompt_content = if has_images {
+                        format!("{}{}", IMAGE_MARKER, message_text)
+                    } else {
+                        message_text
+                    };
+                    let prompt = Turn {
+                        role: Role::User,
+                        content: prompt_content,
+                        message_id: msg.id,
+                        reply_to: parent_id,
+                    };
+                    history.record_exchange(conversation, prompt, answer);
+                }
+                Question::Again { previous, .. } => {
+                    history.replace_answer(conversation, previous, answer);
+                }
+                Question::Continue { previous, .. } => {
+                    answer.content = format!("{}{}", previous.content, answer.content);
+                    history.replace_answer(conversation, previous.message_id, answer);
+                }
+            }
+
+            // Summarize what fell out of the context in the background
+            if !dropped.is_empty() {
+                tokio::spawn(update_summary(
+                    config.clone(),
+                    Arc::clone(&storage),
+                    model.clone(),
+                    conversation,
+                    summary,
+                    dropped,
+                    requester,
+                ));
+            }
         }
         Err(error) => {
-            log::error!("OpenAI request error: {}", error);
-            bot.send_message(
-                msg.chat.id,
-                "Sorry, I encountered an error while processing your request.",
-            )
-            .await?;
+            log::error!("LLM request failed ({}): {}", error.kind().label(), error);
+            let language = Language::of_user(msg.from.as_ref());
+            reply
+                .finish(i18n::llm_error_reply(error.kind(), language), None)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge turns that fell out of a conversation's context into its summary
+async fn update_summary(
+    config: BotConfig,
+    storage: Arc<dyn Storage>,
+    model: ModelInfo,
+    conversation: ConversationKey,
+    previous: Option<Summary>,
+    turns: Vec<Turn>,
+    user_id: Option<UserId>,
+) {
+    let result = context_window::summarize(
+        &config.models,
+        &model,
+        previous.as_ref(),
+        &turns,
+        config.retry_policy,
+    )
+    .await;
+    let (summary, usage) = match result {
+        Ok(result) => result,
+        Err(error) => {
+            log::error!(
+                "Failed to summarize conversation ({}): {}",
+                error.kind().label(),
+                error
+            );
+            return;
+        }
+    };
+
+    log::info!(
+        "Summarized {} turns of chat {}",
+        turns.len(),
+        conversation.chat_id
+    );
+    if let Err(error) = context_window::save_summary(&*storage, conversation, &summary) {
+        log::error!("Failed to save conversation summary: {}", error);
+    }
+    if let Some(usage) = usage {
+        let record = UsageRecord {
+            chat_id: conversation.chat_id,
+            user_id,
+            model: model.name.clone(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost: model.cost(&usage),
+        };
+        if let Err(error) = storage.record_usage(&record) {
+            log::error!("Failed to record usage: {}", error);
         }
     }
+}
+
+/// Send an answer as a voice message, replying to its text
+///
+/// Failures are only logged, since the answer has already been delivered as text.
+async fn send_voice_answer(
+    bot: &Bot,
+    chat_id: ChatId,
+    speaker: &Speaker,
+    answer: &str,
+    answer_id: MessageId,
+) -> ResponseResult<()> {
+    let text = markdown::to_plain_text(answer);
+    if text.chars().count() > MAX_SPEECH_LEN {
+        log::info!("Answer is too long to be read out, sending it as text only");
+        return Ok(());
+    }
 
+    bot.send_chat_action(chat_id, teloxide::types::ChatAction::RecordVoice)
+        .await?;
+    match speaker.synthesize(&text).await {
+        Ok(audio) => {
+            // Users can forbid voice messages in their privacy settings.
+            let result = bot
+                .send_voice(chat_id, InputFile::memory(audio).file_name("answer.ogg"))
+                .reply_parameters(ReplyParameters::new(answer_id))
+                .await;
+            if let Err(error) = result {
+                log::warn!("Failed to send voice reply: {}", error);
+            }
+        }
+        Err(error) => log::error!(
+            "Failed to synthesize voice reply ({}): {}",
+            error.kind().label(),
+            error
+        ),
+    }
     Ok(())
 }
 
-/// Send a request to OpenAI and return the response content
-async fn send_openai_request(
-    client: &Client<OpenAIConfig>,
+/// A completed answer from the model
+struct Completion {
+    /// The answer text
+    content: String,
+    /// Tokens spent on the request, when reported by the API
+    usage: Option<Usage>,
+    /// The tools the model called
+    tool_calls: Vec<ToolCall>,
+    /// Whether the answer is incomplete, cut off at the token limit or stopped
+    truncated: bool,
+}
+
+/// Answer a request, running the tools the model calls until it gives its final answer
+///
+/// The model may call `tools` when their specs are part of the request. Later rounds start
+/// with the model that answered the previous one. The last round allowed forbids further
+/// tool calls, so the model has to answer with what it has. An incomplete answer ends the
+/// rounds, whether cut off or stopped. Returns the model that answered last and the whole
+/// answer, with the tokens spent on all rounds.
+async fn answer_with_tools<'a>(
+    config: &'a BotConfig,
+    selected: &'a ModelInfo,
+    mut request: ChatRequest,
+    tools: &Tools,
+    reply: &mut StreamingReply,
+    tool_context: &ToolContext,
+    stop: &StopSignal,
+) -> Result<(&'a ModelInfo, Completion), LlmError> {
+    let models = &config.models;
+    let max_rounds = if request.tools.is_empty() {
+        0
+    } else {
+        tools.max_rounds
+    };
+
+    let mut model = selected;
+    let mut usage: Option<Usage> = None;
+    let mut truncated = false;
+    for round in 0..=max_rounds {
+        if round == max_rounds {
+            request.tool_choice = ToolChoice::None;
+        }
+        let (answering, stream) =
+            send_llm_request(models, model, &request, config.retry_policy).await?;
+        model = answering;
+        let completion = stream_answer(reply, stream, stop).await?;
+        if let Some(round_usage) = completion.usage {
+            let total = usage.get_or_insert_with(Usage::default);
+            total.prompt_tokens += round_usage.prompt_tokens;
+            total.completion_tokens += round_usage.completion_tokens;
+        }
+        if completion.truncated || completion.tool_calls.is_empty() {
+            truncated = completion.truncated;
+            break;
+        }
+
+        // Keep what the model said before calling the tools apart from what follows
+        if !completion.content.trim().is_empty() {
+            reply.push("\n\n").await;
+        }
+        let results = tools.call_all(&completion.tool_calls, tool_context).await;
+        request.messages.push(ChatMessage {
+            tool_calls: completion.tool_calls,
+            ..ChatMessage::text(Role::Assistant, completion.content)
+        });
+        request.messages.push(ChatMessage {
+            tool_results: results,
+            ..ChatMessage::text(Role::User, "")
+        });
+    }
+
+    if reply.text().trim().is_empty() {
+        return Err(LlmError::EmptyResponse);
+    }
+
+    Ok((
+        model,
+        Completion {
+            content: reply.text().trim_end().to_string(),
+            usage,
+            tool_calls: Vec::new(),
+            truncated,
+        },
+    ))
+}
+
+/// Feed a streamed answer into the reply and collect this round's text and tool calls
+///
+/// Stopping the answer ends the stream, keeping the text received so far.
+async fn stream_answer(
+    reply: &mut StreamingReply,
+    mut stream: ChatStream,
+    stop: &StopSignal,
+) -> Result<Completion, LlmError> {
+    let mut content = String::new();
+    let mut usage = None;
+    let mut tool_calls = Vec::new();
+    let mut truncated = false;
+    loop {
+        let event = tokio::select! {
+            event = stream.next() => event,
+            () = stop.stopped() => {
+                log::info!("Answer stopped by the user");
+                truncated = true;
+                break;
+            }
+        };
+        let Some(event) = event else {
+            break;
+        };
+        match event? {
+            StreamEvent::Delta(delta) => {
+                content.push_str(&delta);
+                reply.push(&delta).await;
+            }
+            StreamEvent::ToolCall(call) => tool_calls.push(call),
+            StreamEvent::Usage(total) => usage = Some(total),
+            StreamEvent::Truncated => truncated = true,
+        }
+    }
+
+    Ok(Completion {
+        content,
+        usage,
+        tool_calls,
+        truncated,
+    })
+}
+
+/// Build the request for a new message in a conversation
+fn build_request(
     model_name: &str,
+    system: Option<String>,
+    context: &[Turn],
     message_text: &str,
-) -> Result<String, String> {
-    // Create the request to OpenAI
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(model_name)
-        .messages([ChatCompletionRequestMessage::User(
-            ChatCompletionRequestUserMessage {
-                content: ChatCompletionRequestUserMessageContent::Text(message_text.to_string()),
-                name: None,
-            },
-        )])
-        .build()
-        .map_err(|e| format!("Failed to build request: {}", e))?;
-    log::debug!("LLM request: {:?}", request);
+    images: Vec<Image>,
+) -> ChatRequest {
+    // Replay the conversation so far, followed by the new message
+    let mut messages: Vec<ChatMessage> = context
+        .iter()
+        .map(|turn| ChatMessage::text(turn.role, turn.content.clone()))
+        .collect();
+    messages.push(ChatMessage {
+        images,
+        ..ChatMessage::text(Role::User, message_text)
+    });
 
-    // Send the request to OpenAI
-    let response = client
-        .chat()
-        .create(request)
-        .await
-        .map_err(|e| format!("OpenAI API error: {:?}", e))?;
-    log::debug!("LLM response: {:?}", response);
+    ChatRequest {
+        model: model_name.to_string(),
+        system,
+        messages,
+        tools: Vec::new(),
+        tool_choice: ToolChoice::Auto,
+    }
+}
 
-    // Extract the response content
-    if let Some(choice) = response.choices.first() {
-        if let Some(content) = &choice.message.content {
-            Ok(content.clone())
-        } else {
-            Err("Received an empty response".to_string())
+/// Send a request to the LLM and return the model that answered it and its answer stream
+///
+/// When the selected model keeps failing, the request goes to the fallback models in turn.
+async fn send_llm_request<'a>(
+    models: &'a ModelRegistry,
+    selected: &'a ModelInfo,
+    request: &ChatRequest,
+    retry_policy: RetryPolicy,
+) -> Result<(&'a ModelInfo, ChatStream), LlmError> {
+    let needs_vision = request
+        .messages
+        .iter()
+        .any(|message| !message.images.is_empty());
+    let mut last_error = None;
+    for model in models.fallback_chain(selected, needs_vision) {
+        let provider = models.provider(model);
+        let request = ChatRequest {
+            model: model.name.clone(),
+            ..request.clone()
+        };
+        match llm::stream_with_retries(&*provider, &request, retry_policy).await {
+            Ok(stream) => {
+                log::info!("Answering with model {} ({})", model.name, provider.name());
+                return Ok((model, stream));
+            }
+            Err(error) => {
+                log::warn!(
+                    "Model {} failed ({}): {}",
+                    model.name,
+                    error.kind().label(),
+                    error
+                );
+                last_error = Some(error);
+            }
         }
-    } else {
-        Err("No response choices available".to_string())
     }
+
+    Err(last_error.expect("the fallback chain starts with the selected model"))
 }
 
 /// Create the message handler for the bot
@@ -201,29 +1875,233 @@ fn create_message_handler(
 {
     // Clone the config to move into the closures
     let config = config.clone();
+    let command_config = config.clone();
+    let callback_config = config.clone();
+    let recording_config = config.clone();
+    let trigger_policy = config.trigger_policy;
+    let transcription_enabled = config.transcriber.is_some();
 
-    Update::filter_message()
-        .branch(dptree::entry().filter_command::<Command>().endpoint(
-            move |bot: Bot, msg: Message| {
-                let greeting = config.greeting_message.clone();
-                async move { command_handler(bot, msg, greeting).await }
-            },
-        ))
-        .branch(dptree::filter(is_mention_message).endpoint(
-            move |bot: Bot, msg: Message, client: Arc<Client<OpenAIConfig>>| {
-                let model = config.llm_model_name.clone();
-                async move { handle_mention(bot, msg, client, &model).await }
-            },
-        ))
+    let messages =
+        Update::filter_message()
+            // Users and chats the bot does not serve go no further
+            .branch(
+                dptree::filter(|msg: Message, access: Arc<AccessPolicy>| {
+                    !access.allows(&msg.chat, msg.from.as_ref())
+                })
+                .endpoint(
+                    move |bot: Bot, msg: Message, me: Me, access: Arc<AccessPolicy>| async move {
+                        refuse_message(bot, msg, me, access, trigger_policy).await
+                    },
+                ),
+            )
+            .branch(dptree::entry().filter_command::<Command>().endpoint(
+                move |bot: Bot,
+                      msg: Message,
+                      me: Me,
+                      history: Arc<ConversationHistory>,
+                      storage: Arc<dyn Storage>| {
+                    let config = command_config.clone();
+                    async move { command_handler(bot, msg, me, history, storage, &config).await }
+                },
+            ))
+            .branch(
+                dptree::filter(move |msg: Message| transcription_enabled && media::has_audio(&msg))
+                    .endpoint(
+                        move |bot: Bot,
+                              msg: Message,
+                              history: Arc<ConversationHistory>,
+                              storage: Arc<dyn Storage>,
+                              me: Me| {
+                            let config = recording_config.clone();
+                            async move {
+                                handle_recording(bot, msg, history, storage, me, &config).await
+                            }
+                        },
+                    ),
+            )
+            .branch(
+                dptree::filter(move |msg: Message, me: Me| {
+                    should_answer(&msg, &me, trigger_policy)
+                })
+                .endpoint(
+                    move |bot: Bot,
+                          msg: Message,
+                          history: Arc<ConversationHistory>,
+                          storage: Arc<dyn Storage>,
+                          me: Me| {
+                        let config = config.clone();
+                        async move {
+                            // Turn down users and chats out of budget or asking too often,
+                            // holding the permit until answered
+                            if !check_budgets(&bot, &msg, &*storage, &config, true).await? {
+                                return Ok(());
+                            }
+                            let Some(_permit) = acquire_permit(&bot, &msg, &config, true).await?
+                            else {
+                                return Ok(());
+                            };
+                            let question = Question::New(extract_message_text(&msg, &me));
+                            handle_mention(bot, msg, question, history, storage, me, &config).await
+                        }
+                    },
+                ),
+            );
+
+    let callbacks = Update::filter_callback_query()
+        .branch(
+            dptree::filter(|query: CallbackQuery, access: Arc<AccessPolicy>| {
+                query
+                    .regular_message()
+                    .is_some_and(|menu| !access.allows(&menu.chat, Some(&query.from)))
+            })
+            .endpoint(refuse_callback),
+        )
+        .branch(
+            dptree::filter(|query: CallbackQuery| {
+                query
+                    .data
+                    .as_deref()
+                    .is_some_and(|data| data.starts_with(MODEL_CALLBACK_PREFIX))
+            })
+            .endpoint(model_callback),
+        )
+        .branch(
+            dptree::filter(|query: CallbackQuery| {
+                query
+                    .data
+                    .as_deref()
+                    .is_some_and(|data| data.starts_with(controls::CALLBACK_PREFIX))
+            })
+            .endpoint(
+                move |bot: Bot,
+                      query: CallbackQuery,
+                      history: Arc<ConversationHistory>,
+                      storage: Arc<dyn Storage>,
+                      me: Me| {
+                    let config = callback_config.clone();
+                    async move { answer_callback(bot, query, history, storage, me, &config).await }
+                },
+            ),
+        );
+
+    dptree::entry().branch(messages).branch(callbacks)
 }
 
-/// Check if a message contains a mention
-fn is_mention_message(msg: &Message) -> bool {
-    if let Some(entities) = msg.entities() {
-        entities
-            .iter()
-            .any(|entity| matches!(entity.kind, MessageEntityKind::Mention))
-    } else {
-        false
+/// Turn away a message from a user or chat the bot does not serve
+///
+/// Only commands and messages the bot would otherwise answer get a refusal, so that it
+/// stays quiet in groups it merely sits in.
+async fn refuse_message(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    access: Arc<AccessPolicy>,
+    trigger_policy: TriggerPolicy,
+) -> ResponseResult<()> {
+    log::info!(
+        "Refused message in chat {} from user {:?}",
+        msg.chat.id,
+        msg.from.as_ref().map(|user| user.id.0)
+    );
+    let addressed = is_command(&msg) || should_answer(&msg, &me, trigger_policy);
+    if access.reply_when_denied && addressed {
+        let language = Language::of_user(msg.from.as_ref());
+        bot.send_message(msg.chat.id, i18n::access_denied_reply(language))
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
     }
+    Ok(())
+}
+
+/// Turn away a button press from a user or chat the bot does not serve
+async fn refuse_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    access: Arc<AccessPolicy>,
+) -> ResponseResult<()> {
+    let mut answer = bot.answer_callback_query(query.id.clone());
+    if access.reply_when_denied {
+        answer = answer.text(i18n::access_denied_reply(Language::of_user(Some(
+            &query.from,
+        ))));
+    }
+    answer.await?;
+    Ok(())
+}
+
+/// Check if the bot should answer a message, according to the trigger for its chat type
+fn should_answer(msg: &Message, me: &Me, policy: TriggerPolicy) -> bool {
+    match policy.for_chat(&msg.chat) {
+        Trigger::Always => {
+            (msg.text().is_some() || msg.caption().is_some() || media::has_image(msg))
+                && !is_command(msg)
+        }
+        Trigger::MentionOrReply => is_addressed_to_bot(msg, me),
+        Trigger::Mention => is_mention_message(msg, me),
+        Trigger::Never => false,
+    }
+}
+
+/// Check if a message is addressed to the bot, either by mention or by replying to it
+fn is_addressed_to_bot(msg: &Message, me: &Me) -> bool {
+    is_mention_message(msg, me) || is_reply_to_bot(msg, me)
+}
+
+/// Check if a message starts with a bot command, including commands of other bots
+fn is_command(msg: &Message) -> bool {
+    msg.parse_entities()
+        .unwrap_or_default()
+        .iter()
+        .any(|entity| entity.start() == 0 && matches!(entity.kind(), MessageEntityKind::BotCommand))
+}
+
+/// Check if a message replies to one of the bot's own messages
+fn is_reply_to_bot(msg: &Message, me: &Me) -> bool {
+    replied_message(msg)
+        .and_then(|parent| parent.from.as_ref())
+        .is_some_and(|author| author.id == me.id)
+}
+
+/// Get the message a message explicitly replies to
+fn replied_message(msg: &Message) -> Option<&Message> {
+    // Inside forum topics every message "replies" to the topic's creation message.
+    msg.reply_to_message()
+        .filter(|parent| parent.forum_topic_created().is_none())
+}
+
+/// Build a turn from a replied-to message that is not in the conversation history
+fn unseen_parent_turn(parent: &Message, me: &Me) -> Option<Turn> {
+    let content = parent.text().or(parent.caption())?;
+    let role = match &parent.from {
+        Some(author) if author.id == me.id => Role::Assistant,
+        _ => Role::User,
+    };
+
+    Some(Turn {
+        role,
+        content: content.to_string(),
+        message_id: parent.id,
+        reply_to: None,
+    })
+}
+
+/// Check if a message contains a mention of the bot
+fn is_mention_message(msg: &Message, me: &Me) -> bool {
+    !bot_mentions(msg, me).is_empty()
+}
+
+/// Get the entities of a message or its caption that mention the bot, by username or as a
+/// text mention
+fn bot_mentions<'a>(msg: &'a Message, me: &Me) -> Vec<MessageEntityRef<'a>> {
+    let mention = me.mention();
+    msg.parse_entities()
+        .or_else(|| msg.parse_caption_entities())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entity| match entity.kind() {
+            MessageEntityKind::Mention => entity.text().eq_ignore_ascii_case(&mention),
+            MessageEntityKind::TextMention { user } => user.id == me.id,
+            _ => false,
+        })
+        .collect()
 }
@@ -0,0 +1,283 @@
+//! Rendering model output for Telegram
+//!
+//! Models answer in CommonMark, which Telegram does not understand. This converts it into
+//! the small HTML subset Telegram supports: headings become bold, lists get bullet or number
+//! prefixes, and formatting that Telegram would reject (such as nested identical tags or
+//! markup inside code) is left out rather than producing an unparseable message.
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+
+/// Convert CommonMark into Telegram-flavoured HTML
+pub fn to_telegram_html(markdown: &str) -> String {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut renderer = HtmlRenderer::default();
+    for event in Parser::new_ext(markdown, options) {
+        renderer.event(event);
+    }
+
+    renderer.out.trim_end().to_string()
+}
+
+/// Convert CommonMark into plain text, such as for reading it out
+///
+/// Formatting is dropped, while blocks and list items stay on lines of their own.
+pub fn to_plain_text(markdown: &str) -> String {
+    let mut out = String::new();
+    for event in Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH) {
+        match event {
+            Event::Text(text) | Event::Code(text) => out.push_str(&text),
+            Event::SoftBreak => out.push(' '),
+            Event::HardBreak
+            | Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item)
+            | Event::End(TagEnd::CodeBlock) => out.push('\n'),
+            _ => {}
+        }
+    }
+
+    out.trim_end().to_string()
+}
+
+/// Escape text for use in Telegram HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Streaming CommonMark-to-HTML renderer state
+#[derive(Default)]
+struct HtmlRenderer {
+    out: String,
+    /// Formatting tags currently open, innermost last; `None` marks tags that were skipped
+    open: Vec<Option<&'static str>>,
+    /// The next item number of every open list, `None` for bullet lists
+    lists: Vec<Option<u64>>,
+    /// Newlines to write before the next content
+    pending_newlines: usize,
+    /// The closing tags of the code block being rendered, if inside one
+    code_block_end: Option<&'static str>,
+}
+
+impl HtmlRenderer {
+    fn event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => {
+                self.flush();
+                self.out.push_str(&escape(&text));
+            }
+            Event::Code(code) => {
+                self.flush();
+                if self.code_block_end.is_some() || self.is_open("code") {
+                    self.out.push_str(&escape(&code));
+                } else {
+                    self.out
+                        .push_str(&format!("<code>{}</code>", escape(&code)));
+                }
+            }
+            Event::Html(html) | Event::InlineHtml(html) => {
+                self.flush();
+                self.out.push_str(&escape(&html));
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) => {
+                self.flush();
+                self.out.push_str(&escape(&math));
+            }
+            Event::FootnoteReference(label) => {
+                self.flush();
+                self.out.push_str(&format!("[{}]", escape(&label)));
+            }
+            Event::SoftBreak | Event::HardBreak => self.out.push('\n'),
+            Event::Rule => {
+                self.flush();
+                self.out.push_str("———");
+                self.pending_newlines = 2;
+            }
+            Event::TaskListMarker(checked) => {
+                self.flush();
+                self.out.push_str(if checked { "☑ " } else { "☐ " });
+            }
+        }
+    }
+
+    fn start(&mut self, tag: Tag<'_>) {
+        match tag {
+            Tag::Heading { .. } => self.open_tag("b", ""),
+            Tag::BlockQuote(_) => self.open_tag("blockquote", ""),
+            Tag::CodeBlock(kind) => {
+                self.require_newlines(1);
+                self.flush();
+                let language = match &kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or(""),
+                    CodeBlockKind::Indented => "",
+                };
+                if language.is_empty() {
+                    self.out.push_str("<pre>");
+                    self.code_block_end = Some("</pre>");
+                } else {
+                    self.out.push_str(&format!(
+                        "<pre><code class=\"language-{}\">",
+                        escape(language)
+                    ));
+                    self.code_block_end = Some("</code></pre>");
+                }
+            }
+            Tag::List(start) => {
+                self.require_newlines(1);
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.require_newlines(1);
+                self.flush();
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}.", *number - 1)
+                    }
+                    _ => "•".to_string(),
+                };
+                self.out.push_str(&format!("{indent}{marker} "));
+            }
+            Tag::Emphasis => self.open_tag("i", ""),
+            Tag::Strong => self.open_tag("b", ""),
+            Tag::Strikethrough => self.open_tag("s", ""),
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                // Telegram rejects links it cannot resolve, such as relative ones.
+                let is_absolute = ["http://", "https://", "tg://", "mailto:"]
+                    .iter()
+                    .any(|scheme| dest_url.starts_with(scheme));
+                if is_absolute {
+                    self.open_tag("a", &format!(" href=\"{}\"", escape(&dest_url)));
+                } else {
+                    self.open.push(None);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph => self.require_newlines(2),
+            TagEnd::Heading(_) => {
+                self.close_tag();
+                self.require_newlines(2);
+            }
+            TagEnd::BlockQuote(_) => {
+                // The quote's own trailing newlines belong outside of it.
+                self.pending_newlines = 0;
+                self.close_tag();
+                self.require_newlines(2);
+            }
+            TagEnd::CodeBlock => {
+                if self.out.ends_with('\n') {
+                    self.out.pop();
+                }
+                if let Some(end) = self.code_block_end.take() {
+                    self.out.push_str(end);
+                }
+                self.require_newlines(2);
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+                self.require_newlines(if self.lists.is_empty() { 2 } else { 1 });
+            }
+            TagEnd::Item => self.require_newlines(1),
+            TagEnd::Emphasis
+            | TagEnd::Strong
+            | TagEnd::Strikethrough
+            | TagEnd::Link
+            | TagEnd::Image => self.close_tag(),
+            _ => {}
+        }
+    }
+
+    /// Open a formatting tag, unless it is already open or not allowed here
+    fn open_tag(&mut self, name: &'static str, attributes: &str) {
+        if self.code_block_end.is_some() || self.is_open(name) {
+            self.open.push(None);
+            return;
+        }
+
+        self.flush();
+        self.out.push_str(&format!("<{name}{attributes}>"));
+        self.open.push(Some(name));
+    }
+
+    /// Close the innermost formatting tag
+    fn close_tag(&mut self) {
+        if let Some(Some(name)) = self.open.pop() {
+            self.out.push_str(&format!("</{name}>"));
+        }
+    }
+
+    fn is_open(&self, name: &str) -> bool {
+        self.open.iter().flatten().any(|&open| open == name)
+    }
+
+    /// Ask for at least `count` newlines before the next content
+    fn require_newlines(&mut self, count: usize) {
+        self.pending_newlines = self.pending_newlines.max(count);
+    }
+
+    /// Write the pending newlines, except at the very start of the output
+    fn flush(&mut self) {
+        if !self.out.is_empty() {
+            self.out.push_str(&"\n".repeat(self.pending_newlines));
+        }
+        self.pending_newlines = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_entities_in_text_code_and_links() {
+        assert_eq!(
+            to_telegram_html("a < b && c > \"d\""),
+            "a &lt; b &amp;&amp; c &gt; &quot;d&quot;"
+        );
+        assert_eq!(
+            to_telegram_html("`<b>&amp;</b>`"),
+            "<code>&lt;b&gt;&amp;amp;&lt;/b&gt;</code>"
+        );
+        assert_eq!(
+            to_telegram_html("[x](https://example.com/?a=1&b=\"2\")"),
+            "<a href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\">x</a>"
+        );
+    }
+
+    #[test]
+    fn escapes_raw_html_instead_of_passing_it_on() {
+        assert_eq!(
+            to_telegram_html("<script>alert(1)</script>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+    }
+
+    #[test]
+    fn skips_nested_identical_tags() {
+        assert_eq!(
+            to_telegram_html("**bold *and __bold__* again**"),
+            "<b>bold <i>and bold</i> again</b>"
+        );
+    }
+
+    #[test]
+    fn renders_code_blocks_without_markup_inside() {
+        assert_eq!(
+            to_telegram_html("```rust\nlet x = *y & 1;\n```"),
+            "<pre><code class=\"language-rust\">let x = *y &amp; 1;</code></pre>"
+        );
+    }
+
+    #[test]
+    fn drops_relative_links() {
+        assert_eq!(to_telegram_html("[docs](/docs)"), "docs");
+    }
+}
@@ -0,0 +1,298 @@
+//! A session with an MCP server: initialization, discovery and calls
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value, json};
+
+use super::McpError;
+use super::transport::Transport;
+
+/// The protocol revision the bot speaks
+const PROTOCOL_VERSION: &str = "2025-06-18";
+
+/// A tool offered by a server
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The JSON Schema of the tool's arguments
+    #[serde(default = "empty_schema")]
+    pub input_schema: Value,
+}
+
+/// A resource a server can be asked for
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteResource {
+    pub uri: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+}
+
+/// A prompt template offered by a server
+#[derive(Clone, Debug, Deserialize)]
+pub struct RemotePrompt {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub arguments: Vec<PromptArgument>,
+}
+
+/// An argument filled into a prompt template
+#[derive(Clone, Debug, Deserialize)]
+pub struct PromptArgument {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// The output of a tool call
+#[derive(Clone, Debug)]
+pub struct ToolOutput {
+    /// The output as text
+    pub text: String,
+    /// Whether the tool reported a failure
+    pub is_error: bool,
+}
+
+/// The result of `initialize`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitializeResult {
+    protocol_version: String,
+    #[serde(default)]
+    capabilities: ServerCapabilities,
+}
+
+/// What the server offers
+#[derive(Debug, Default, Deserialize)]
+struct ServerCapabilities {
+    #[serde(default)]
+    tools: Option<Value>,
+    #[serde(default)]
+    resources: Option<Value>,
+    #[serde(default)]
+    prompts: Option<Value>,
+}
+
+/// A connected server and what it offers
+pub struct McpClient {
+    transport: Box<dyn Transport>,
+    next_id: AtomicU64,
+    pub tools: Vec<RemoteTool>,
+    pub resources: Vec<RemoteResource>,
+    pub prompts: Vec<RemotePrompt>,
+}
+
+impl McpClient {
+    /// Initialize the session and discover the server's tools, resources and prompts
+    pub async fn connect(transport: Box<dyn Transport>) -> Result<Self, McpError> {
+        let mut client = Self {
+            transport,
+            next_id: AtomicU64::new(1),
+            tools: Vec::new(),
+            resources: Vec::new(),
+            prompts: Vec::new(),
+        };
+
+        let initialized: InitializeResult = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await?;
+        client
+            .transport
+            .set_protocol_version(&initialized.protocol_version);
+        client
+            .transport
+            .notify(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+
+        let capabilities = initialized.capabilities;
+        if capabilities.tools.is_some() {
+            client.tools = client.list("tools/list", "tools").await?;
+        }
+        if capabilities.resources.is_some() {
+            client.resources = client.list("resources/list", "resources").await?;
+        }
+        if capabilities.prompts.is_some() {
+            client.prompts = client.list("prompts/list", "prompts").await?;
+        }
+        Ok(client)
+    }
+
+    /// Call a tool
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<ToolOutput, McpError> {
+        let result: Value = self
+            .request(
+                "tools/call",
+                json!({ "name": name, "arguments": arguments }),
+            )
+            .await?;
+
+        let mut text = content_text(&result["content"]);
+        if text.is_empty() {
+            if let Some(structured) = result.get("structuredContent") {
+                text = structured.to_string();
+            }
+        }
+        Ok(ToolOutput {
+            text,
+            is_error: result["isError"].as_bool().unwrap_or(false),
+        })
+    }
+
+    /// Read a resource as text
+    pub async fn read_resource(&self, uri: &str) -> Result<String, McpError> {
+        let result: Value = self
+            .request("resources/read", json!({ "uri": uri }))
+            .await?;
+        let contents = result["contents"].as_array().cloned().unwrap_or_default();
+        let texts: Vec<String> = contents
+            .iter()
+            .map(|content| match content["text"].as_str() {
+                Some(text) => text.to_string(),
+                None => format!(
+                    "[binary content of type {}]",
+                    content["mimeType"].as_str().unwrap_or("unknown")
+                ),
+            })
+            .collect();
+        Ok(texts.join("\n\n"))
+    }
+
+    /// Fill in a prompt template, returning its messages as text
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: Map<String, Value>,
+    ) -> Result<String, McpError> {
+        let result: Value = self
+            .request(
+                "prompts/get",
+                json!({ "name": name, "arguments": arguments }),
+            )
+            .await?;
+        let messages = result["messages"].as_array().cloned().unwrap_or_default();
+        let texts: Vec<String> = messages
+            .iter()
+            .map(|message| {
+                format!(
+                    "{}: {}",
+                    message["role"].as_str().unwrap_or("user"),
+                    content_text(&message["content"])
+                )
+            })
+            .collect();
+        Ok(texts.join("\n\n"))
+    }
+
+    /// Collect all pages of a list
+    async fn list<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        field: &str,
+    ) -> Result<Vec<T>, McpError> {
+        let mut items = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut page: Value = self.request(method, params).await?;
+            let page_items: Vec<T> = serde_json::from_value(page[field].take())
+                .map_err(|e| McpError::Transport(format!("invalid {} result: {}", method, e)))?;
+            items.extend(page_items);
+
+            cursor = page["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Send a request and parse its result
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, McpError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut response = self
+            .transport
+            .request(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": method,
+                "params": params,
+            }))
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            return Err(McpError::Server {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+        serde_json::from_value(response["result"].take())
+            .map_err(|e| McpError::Transport(format!("invalid {} result: {}", method, e)))
+    }
+}
+
+/// Turn content blocks into text, describing what can't be shown as text
+fn content_text(content: &Value) -> String {
+    let blocks = match content {
+        Value::Array(blocks) => blocks.as_slice(),
+        block => std::slice::from_ref(block),
+    };
+    let texts: Vec<String> = blocks
+        .iter()
+        .filter_map(|block| match block["type"].as_str()? {
+            "text" => block["text"].as_str().map(str::to_string),
+            "resource" => Some(
+                block["resource"]["text"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| {
+                        format!(
+                            "[resource {}]",
+                            block["resource"]["uri"].as_str().unwrap_or_default()
+                        )
+                    }),
+            ),
+            "resource_link" => Some(format!(
+                "[resource {}]",
+                block["uri"].as_str().unwrap_or_default()
+            )),
+            kind => Some(format!(
+                "[{} of type {}]",
+                kind,
+                block["mimeType"].as_str().unwrap_or("unknown")
+            )),
+        })
+        .collect();
+    texts.join("\n\n")
+}
+
+fn empty_schema() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
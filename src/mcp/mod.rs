@@ -0,0 +1,379 @@
+//! Tools from Model Context Protocol servers
+//!
+//! `MCP_SERVERS_FILE` points to a TOML file listing MCP servers, each either a command
+//! started as a subprocess speaking over stdio or a URL reached over streamable HTTP. A
+//! server is connected the first time it is needed, and its tools are offered to the model
+//! next to the built-in ones as `<server>__<tool>`. Answers don't wait long for a server
+//! to connect, and a server that could not be reached is left alone for a minute. Its resources and prompts are offered
+//! through the tools `<server>__read_resource` and `<server>__get_prompt`. Servers marked
+//! `enabled` are used in every chat unless turned off there; chat administrators turn
+//! servers on and off with `/mcp`.
+
+mod client;
+mod tool;
+mod transport;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use teloxide::types::ChatId;
+
+use crate::storage::{Storage, StorageResult};
+use crate::tools::Tool;
+use client::McpClient;
+use transport::{HttpTransport, StdioTransport, Transport};
+
+/// How long connecting to a server and discovering what it offers may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long an answer waits for a server to connect; connecting goes on in the background
+const ANSWER_CONNECT_WAIT: Duration = Duration::from_secs(3);
+
+/// How long a server that could not be reached is left alone before connecting again
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// The prefix of the chat settings turning servers on or off
+const SETTING_PREFIX: &str = "mcp:";
+
+/// An error talking to an MCP server
+#[derive(Debug)]
+pub enum McpError {
+    /// The server could not be reached or sent something unexpected
+    Transport(String),
+    /// The server answered a request with an error
+    Server { code: i64, message: String },
+}
+
+impl fmt::Display for McpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(message) => write!(f, "{}", message),
+            Self::Server { code, message } => write!(f, "{} (error {})", message, code),
+        }
+    }
+}
+
+impl std::error::Error for McpError {}
+
+/// A server listed in `MCP_SERVERS_FILE`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerConfig {
+    /// The name the server's tools are prefixed with and `/mcp` refers to it by
+    pub name: String,
+    /// What the server is for, shown by `/mcp`
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The command starting a stdio server
+    #[serde(default)]
+    command: Option<String>,
+    /// The arguments of the command
+    #[serde(default)]
+    args: Vec<String>,
+    /// Environment variables set for the command
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// The endpoint of a streamable HTTP server
+    #[serde(default)]
+    url: Option<String>,
+    /// Headers sent to the HTTP server, such as `Authorization`
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Whether chats use the server unless they turn it off
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// The contents of `MCP_SERVERS_FILE`
+#[derive(Debug, Deserialize)]
+struct ServersFile {
+    servers: Vec<ServerConfig>,
+}
+
+/// A configured server and its connection, if established
+pub struct McpServer {
+    pub config: ServerConfig,
+    client: tokio::sync::Mutex<Option<Arc<McpClient>>>,
+    /// When connecting last failed
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl McpServer {
+    fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            client: tokio::sync::Mutex::new(None),
+            failed_at: Mutex::new(None),
+        }
+    }
+
+    /// The name of the server
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Check if connecting failed too recently to try again
+    fn is_resting(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|failed_at| failed_at.elapsed() < RETRY_DELAY)
+    }
+
+    /// Get the connection to the server, waiting up to `wait` for it to connect if there is
+    /// none
+    ///
+    /// Connecting goes on after the wait, so the server is ready for later requests.
+    async fn client(self: &Arc<Self>, wait: Duration) -> Result<Arc<McpClient>, McpError> {
+        // The lock is held while connecting, which is not waited for here.
+        if let Some(client) = self
+            .client
+            .try_lock()
+            .ok()
+            .and_then(|client| client.clone())
+        {
+            return Ok(client);
+        }
+        if self.is_resting() {
+            return Err(McpError::Transport(
+                "connecting failed recently, not trying again yet".to_string(),
+            ));
+        }
+
+        let server = Arc::clone(self);
+        let connecting = tokio::spawn(async move { server.connected_client().await });
+        match tokio::time::timeout(wait, connecting).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(error)) => Err(McpError::Transport(format!("connecting failed: {}", error))),
+            Err(_) => Err(McpError::Transport("still connecting".to_string())),
+        }
+    }
+
+    /// Get the connection to the server, connecting first if there is none
+    async fn connected_client(&self) -> Result<Arc<McpClient>, McpError> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = &*client {
+            return Ok(Arc::clone(client));
+        }
+        // Requests that queued up behind a failed attempt don't repeat it.
+        if self.is_resting() {
+            return Err(McpError::Transport(
+                "connecting failed recently, not trying again yet".to_string(),
+            ));
+        }
+
+        let result = tokio::time::timeout(CONNECT_TIMEOUT, self.connect())
+            .await
+            .unwrap_or_else(|_| Err(McpError::Transport("connecting took too long".to_string())));
+        let connected = match result {
+            Ok(connected) => connected,
+            Err(error) => {
+                *self.failed_at.lock().unwrap() = Some(Instant::now());
+                return Err(error);
+            }
+        };
+        *self.failed_at.lock().unwrap() = None;
+        log::info!(
+            "Connected to MCP server {} with {} tools, {} resources and {} prompts",
+            self.config.name,
+            connected.tools.len(),
+            connected.resources.len(),
+            connected.prompts.len()
+        );
+        let connected = Arc::new(connected);
+        *client = Some(Arc::clone(&connected));
+        Ok(connected)
+    }
+
+    async fn connect(&self) -> Result<McpClient, McpError> {
+        let config = &self.config;
+        let transport: Box<dyn Transport> = match (&config.command, &config.url) {
+            (Some(command), _) => Box::new(StdioTransport::spawn(
+                &config.name,
+                command,
+                &config.args,
+                &config.env,
+            )?),
+            (None, Some(url)) => Box::new(HttpTransport::new(url, &config.headers)?),
+            (None, None) => unreachable!("servers are checked to have a command or a URL"),
+        };
+        McpClient::connect(transport).await
+    }
+
+    /// Drop a connection that failed, so that the next use connects again
+    async fn disconnect(&self, failed: &Arc<McpClient>) {
+        let mut client = self.client.lock().await;
+        if client
+            .as_ref()
+            .is_some_and(|client| Arc::ptr_eq(client, failed))
+        {
+            log::warn!("Disconnected from MCP server {}", self.config.name);
+            *client = None;
+        }
+    }
+
+    /// The tools offering what the server has, connecting to it if needed
+    pub async fn tools(self: &Arc<Self>) -> Result<Vec<Arc<dyn Tool>>, McpError> {
+        self.tools_within(CONNECT_TIMEOUT).await
+    }
+
+    /// The tools offering what the server has, waiting up to `wait` for it to connect
+    async fn tools_within(
+        self: &Arc<Self>,
+        wait: Duration,
+    ) -> Result<Vec<Arc<dyn Tool>>, McpError> {
+        let client = self.client(wait).await?;
+        Ok(tool::server_tools(self, &client))
+    }
+}
+
+impl fmt::Debug for McpServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("McpServer")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The configured MCP servers
+#[derive(Debug, Default)]
+pub struct McpServers {
+    servers: Vec<Arc<McpServer>>,
+}
+
+impl McpServers {
+    /// Load the servers from `MCP_SERVERS_FILE`, if set
+    pub fn from_env() -> Self {
+        let Ok(path) = env::var("MCP_SERVERS_FILE") else {
+            return Self::default();
+        };
+        let configs = load_servers(&path).unwrap_or_else(|error| panic!("{}", error));
+        Self {
+            servers: configs
+                .into_iter()
+                .map(|config| Arc::new(McpServer::new(config)))
+                .collect(),
+        }
+    }
+
+    /// The servers, in the order they are configured
+    pub fn servers(&self) -> &[Arc<McpServer>] {
+        &self.servers
+    }
+
+    /// Find a server by name
+    pub fn find(&self, name: &str) -> Option<&Arc<McpServer>> {
+        self.servers
+            .iter()
+            .find(|server| server.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Check if a chat uses a server
+    pub fn is_enabled(
+        &self,
+        storage: &dyn Storage,
+        chat_id: ChatId,
+        server: &McpServer,
+    ) -> StorageResult<bool> {
+        let key = format!("{}{}", SETTING_PREFIX, server.name());
+        Ok(match storage.chat_setting(chat_id, &key)?.as_deref() {
+            Some("on") => true,
+            Some("off") => false,
+            _ => server.config.enabled,
+        })
+    }
+
+    /// Turn a server on or off for a chat
+    pub fn set_enabled(
+        &self,
+        storage: &dyn Storage,
+        chat_id: ChatId,
+        server: &McpServer,
+        enabled: bool,
+    ) -> StorageResult<()> {
+        let key = format!("{}{}", SETTING_PREFIX, server.name());
+        if enabled == server.config.enabled {
+            storage.delete_chat_setting(chat_id, &key)
+        } else {
+            storage.set_chat_setting(chat_id, &key, if enabled { "on" } else { "off" })
+        }
+    }
+
+    /// The tools of the servers a chat uses
+    ///
+    /// Servers that can't be reached, or don't connect within a few seconds, are left out.
+    pub async fn tools_for_chat(
+        &self,
+        storage: &dyn Storage,
+        chat_id: ChatId,
+    ) -> Vec<Arc<dyn Tool>> {
+        let mut enabled = Vec::new();
+        for server in &self.servers {
+            match self.is_enabled(storage, chat_id, server) {
+                Ok(true) => enabled.push(server),
+                Ok(false) => {}
+                Err(error) => log::error!("Failed to load MCP setting: {}", error),
+            }
+        }
+
+        let results = futures::future::join_all(
+            enabled
+                .iter()
+                .map(|server| server.tools_within(ANSWER_CONNECT_WAIT)),
+        )
+        .await;
+        enabled
+            .into_iter()
+            .zip(results)
+            .flat_map(|(server, result)| {
+                result.unwrap_or_else(|error| {
+                    log::error!(
+                        "Failed to connect to MCP server {}: {}",
+                        server.name(),
+                        error
+                    );
+                    Vec::new()
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read the server list from a TOML file and check it
+fn load_servers(path: &str) -> Result<Vec<ServerConfig>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read MCP_SERVERS_FILE {}: {}", path, e))?;
+    let file: ServersFile = toml::from_str(&contents)
+        .map_err(|e| format!("Invalid MCP_SERVERS_FILE {}: {}", path, e))?;
+
+    for (index, server) in file.servers.iter().enumerate() {
+        let valid_name = !server.name.is_empty()
+            && server
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "Invalid MCP server name `{}`, use letters, digits, `-` and `_`",
+                server.name
+            ));
+        }
+        if file.servers[..index]
+            .iter()
+            .any(|other| other.name.eq_ignore_ascii_case(&server.name))
+        {
+            return Err(format!("MCP server `{}` is listed twice", server.name));
+        }
+        if server.command.is_some() == server.url.is_some() {
+            return Err(format!(
+                "MCP server `{}` needs either a command or a url",
+                server.name
+            ));
+        }
+    }
+    Ok(file.servers)
+}
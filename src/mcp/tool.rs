@@ -0,0 +1,223 @@
+//! The tools, resources and prompts of a server, offered to the model as tools
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+use super::client::{McpClient, RemotePrompt, RemoteResource, RemoteTool};
+use super::{McpError, McpServer};
+use crate::llm::ToolSpec;
+use crate::tools::{Tool, ToolContext, string_argument};
+
+/// The longest tool name providers accept
+const MAX_NAME_LEN: usize = 64;
+
+/// The most resources listed in the description of the resource tool
+const MAX_LISTED_RESOURCES: usize = 50;
+
+/// Build the tools for everything a connected server offers
+pub fn server_tools(server: &Arc<McpServer>, client: &Arc<McpClient>) -> Vec<Arc<dyn Tool>> {
+    let mut tools: Vec<Arc<dyn Tool>> = client
+        .tools
+        .iter()
+        .map(|tool| -> Arc<dyn Tool> {
+            Arc::new(ServerTool {
+                server: Arc::clone(server),
+                client: Arc::clone(client),
+                tool: tool.clone(),
+            })
+        })
+        .collect();
+    if !client.resources.is_empty() {
+        tools.push(Arc::new(ResourceReader {
+            server: Arc::clone(server),
+            client: Arc::clone(client),
+        }));
+    }
+    if !client.prompts.is_empty() {
+        tools.push(Arc::new(PromptGetter {
+            server: Arc::clone(server),
+            client: Arc::clone(client),
+        }));
+    }
+    tools
+}
+
+/// The name a tool is offered under: the server's name and the tool's, limited to the
+/// characters and length providers accept
+fn tool_name(server: &McpServer, name: &str) -> String {
+    format!("{}__{}", server.name(), name)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_NAME_LEN)
+        .collect()
+}
+
+/// Turn the outcome of a request into a tool's output, dropping the connection if it broke
+async fn finish<T>(
+    server: &McpServer,
+    client: &Arc<McpClient>,
+    result: Result<T, McpError>,
+) -> Result<T, String> {
+    if let Err(McpError::Transport(_)) = &result {
+        server.disconnect(client).await;
+    }
+    result.map_err(|error| error.to_string())
+}
+
+/// A tool of a server
+struct ServerTool {
+    server: Arc<McpServer>,
+    client: Arc<McpClient>,
+    tool: RemoteTool,
+}
+
+#[async_trait]
+impl Tool for ServerTool {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: tool_name(&self.server, &self.tool.name),
+            description: self.tool.description.clone().unwrap_or_default(),
+            parameters: self.tool.input_schema.clone(),
+        }
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> Result<String, String> {
+        let result = self.client.call_tool(&self.tool.name, arguments).await;
+        let output = finish(&self.server, &self.client, result).await?;
+        if output.is_error {
+            Err(output.text)
+        } else {
+            Ok(output.text)
+        }
+    }
+}
+
+/// Reads the resources of a server
+struct ResourceReader {
+    server: Arc<McpServer>,
+    client: Arc<McpClient>,
+}
+
+#[async_trait]
+impl Tool for ResourceReader {
+    fn spec(&self) -> ToolSpec {
+        let mut description = format!(
+            "Read a resource of the {} server by its URI. Available resources:",
+            self.server.name()
+        );
+        for resource in self.client.resources.iter().take(MAX_LISTED_RESOURCES) {
+            description.push_str(&format!("\n- {}", describe_resource(resource)));
+        }
+        if self.client.resources.len() > MAX_LISTED_RESOURCES {
+            description.push_str("\n- and more");
+        }
+
+        ToolSpec {
+            name: tool_name(&self.server, "read_resource"),
+            description,
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "uri": { "type": "string", "description": "The URI of the resource" },
+                },
+                "required": ["uri"],
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> Result<String, String> {
+        let uri = string_argument(&arguments, "uri")?;
+        let result = self.client.read_resource(uri).await;
+        finish(&self.server, &self.client, result).await
+    }
+}
+
+fn describe_resource(resource: &RemoteResource) -> String {
+    let mut line = format!("{} ({})", resource.uri, resource.name);
+    if let Some(mime_type) = &resource.mime_type {
+        line.push_str(&format!(", {}", mime_type));
+    }
+    if let Some(description) = &resource.description {
+        line.push_str(&format!(": {}", description));
+    }
+    line
+}
+
+/// Fills in the prompt templates of a server
+struct PromptGetter {
+    server: Arc<McpServer>,
+    client: Arc<McpClient>,
+}
+
+#[async_trait]
+impl Tool for PromptGetter {
+    fn spec(&self) -> ToolSpec {
+        let mut description = format!(
+            "Get a prompt template of the {} server, filled in with arguments. Available prompts:",
+            self.server.name()
+        );
+        for prompt in &self.client.prompts {
+            description.push_str(&format!("\n- {}", describe_prompt(prompt)));
+        }
+
+        ToolSpec {
+            name: tool_name(&self.server, "get_prompt"),
+            description,
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "description": "The name of the prompt" },
+                    "arguments": {
+                        "type": "object",
+                        "description": "The prompt's arguments, by name",
+                        "additionalProperties": { "type": "string" },
+                    },
+                },
+                "required": ["name"],
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> Result<String, String> {
+        let name = string_argument(&arguments, "name")?;
+        let prompt_arguments = arguments["arguments"]
+            .as_object()
+            .cloned()
+            .unwrap_or_default();
+        let result = self.client.get_prompt(name, prompt_arguments).await;
+        finish(&self.server, &self.client, result).await
+    }
+}
+
+fn describe_prompt(prompt: &RemotePrompt) -> String {
+    let mut line = prompt.name.clone();
+    if !prompt.arguments.is_empty() {
+        let arguments: Vec<String> = prompt
+            .arguments
+            .iter()
+            .map(|argument| {
+                let mut text = argument.name.clone();
+                if !argument.required {
+                    text.push('?');
+                }
+                if let Some(description) = &argument.description {
+                    text.push_str(&format!(": {}", description));
+                }
+                text
+            })
+            .collect();
+        line.push_str(&format!(" ({})", arguments.join("; ")));
+    }
+    if let Some(description) = &prompt.description {
+        line.push_str(&format!(" - {}", description));
+    }
+    line
+}
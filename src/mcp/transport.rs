@@ -0,0 +1,354 @@
+//! The connections JSON-RPC messages travel over to an MCP server
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use eventsource_stream::Eventsource;
+use futures::StreamExt;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::oneshot;
+
+use super::McpError;
+
+/// How long the server may take to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The header carrying the session of a streamable HTTP connection
+const SESSION_HEADER: &str = "mcp-session-id";
+
+/// The header carrying the negotiated protocol version
+const PROTOCOL_VERSION_HEADER: &str = "mcp-protocol-version";
+
+/// A connection to an MCP server
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Send a request and wait for the response with the same ID
+    async fn request(&self, message: Value) -> Result<Value, McpError>;
+
+    /// Send a notification, which has no response
+    async fn notify(&self, message: Value) -> Result<(), McpError>;
+
+    /// Send the negotiated protocol version along with later messages
+    fn set_protocol_version(&self, _version: &str) {}
+}
+
+/// Requests waiting for their response, by ID
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>;
+
+/// A server running as a subprocess, exchanging one JSON message per line on stdin and stdout
+pub struct StdioTransport {
+    stdin: Arc<tokio::sync::Mutex<ChildStdin>>,
+    pending: Pending,
+    /// Set once the server closed its output, usually because it exited
+    closed: Arc<AtomicBool>,
+    /// The server process, killed when the transport is dropped
+    _child: Child,
+}
+
+impl StdioTransport {
+    /// Start the server process
+    pub fn spawn(
+        name: &str,
+        command: &str,
+        args: &[String],
+        env: &HashMap<String, String>,
+    ) -> Result<Self, McpError> {
+        let mut child = Command::new(command)
+            .args(args)
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| McpError::Transport(format!("failed to start `{}`: {}", command, e)))?;
+
+        let stdin = Arc::new(tokio::sync::Mutex::new(
+            child.stdin.take().expect("stdin is piped"),
+        ));
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let pending = Pending::default();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(read_messages(
+            name.to_string(),
+            stdout,
+            Arc::clone(&stdin),
+            Arc::clone(&pending),
+            Arc::clone(&closed),
+        ));
+
+        // Servers log to stderr, which would otherwise fill up the pipe.
+        if let Some(stderr) = child.stderr.take() {
+            let name = name.to_string();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    log::debug!("MCP server {}: {}", name, line);
+                }
+            });
+        }
+
+        Ok(Self {
+            stdin,
+            pending,
+            closed,
+            _child: child,
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for StdioTransport {
+    async fn request(&self, message: Value) -> Result<Value, McpError> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(McpError::Transport("the server has exited".to_string()));
+        }
+        let id = message["id"].as_u64().expect("requests have a numeric ID");
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("pending requests lock poisoned")
+            .insert(id, sender);
+
+        if let Err(error) = write_message(&self.stdin, &message).await {
+            self.pending
+                .lock()
+                .expect("pending requests lock poisoned")
+                .remove(&id);
+            return Err(error);
+        }
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(McpError::Transport("the server has exited".to_string())),
+            Err(_) => {
+                self.pending
+                    .lock()
+                    .expect("pending requests lock poisoned")
+                    .remove(&id);
+                Err(McpError::Transport("the server took too long".to_string()))
+            }
+        }
+    }
+
+    async fn notify(&self, message: Value) -> Result<(), McpError> {
+        write_message(&self.stdin, &message).await
+    }
+}
+
+/// Write a message as a single line
+async fn write_message(
+    stdin: &tokio::sync::Mutex<ChildStdin>,
+    message: &Value,
+) -> Result<(), McpError> {
+    let mut line = message.to_string();
+    line.push('\n');
+    let mut stdin = stdin.lock().await;
+    stdin
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| McpError::Transport(e.to_string()))?;
+    stdin
+        .flush()
+        .await
+        .map_err(|e| McpError::Transport(e.to_string()))
+}
+
+/// Hand the responses the server writes to the requests waiting for them, until it exits
+async fn read_messages(
+    name: String,
+    stdout: ChildStdout,
+    stdin: Arc<tokio::sync::Mutex<ChildStdin>>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            log::warn!("MCP server {} wrote invalid JSON: {}", name, line);
+            continue;
+        };
+
+        if message.get("method").is_none() {
+            let sender = message["id"].as_u64().and_then(|id| {
+                pending
+                    .lock()
+                    .expect("pending requests lock poisoned")
+                    .remove(&id)
+            });
+            if let Some(sender) = sender {
+                let _ = sender.send(message);
+            }
+        } else if let Some(id) = message.get("id") {
+            // Requests from the server, of which only pings are supported
+            let response = server_request_response(id, &message["method"]);
+            if let Err(error) = write_message(&stdin, &response).await {
+                log::warn!("Failed to answer MCP server {}: {}", name, error);
+            }
+        } else {
+            log::debug!("MCP server {} sent {}", name, message["method"]);
+        }
+    }
+
+    log::info!("MCP server {} closed its output", name);
+    closed.store(true, Ordering::Relaxed);
+    // Dropping the senders fails the requests still waiting.
+    pending
+        .lock()
+        .expect("pending requests lock poisoned")
+        .clear();
+}
+
+/// The response to a request the server sent to the bot
+fn server_request_response(id: &Value, method: &Value) -> Value {
+    if method == "ping" {
+        json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+    } else {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32601, "message": "Method not found" },
+        })
+    }
+}
+
+/// A server reached over streamable HTTP, answering each POSTed message with JSON or with a
+/// stream of server-sent events
+pub struct HttpTransport {
+    http: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    /// The session the server assigned on initialization
+    session_id: Mutex<Option<String>>,
+    /// The protocol version negotiated on initialization
+    protocol_version: Mutex<Option<String>>,
+}
+
+impl HttpTransport {
+    /// Prepare the connection, with extra headers such as `Authorization` sent along
+    pub fn new(url: &str, headers: &HashMap<String, String>) -> Result<Self, McpError> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|e| McpError::Transport(format!("invalid header {}: {}", name, e)))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .map_err(|e| McpError::Transport(format!("invalid header {}: {}", name, e)))?;
+            header_map.insert(name, value);
+        }
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url: url.to_string(),
+            headers: header_map,
+            session_id: Mutex::new(None),
+            protocol_version: Mutex::new(None),
+        })
+    }
+
+    /// POST a message, returning the response
+    async fn post(&self, message: &Value) -> Result<reqwest::Response, McpError> {
+        let mut request = self
+            .http
+            .post(&self.url)
+            .timeout(REQUEST_TIMEOUT)
+            .headers(self.headers.clone())
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(message);
+        if let Some(session_id) = self
+            .session_id
+            .lock()
+            .expect("session lock poisoned")
+            .clone()
+        {
+            request = request.header(SESSION_HEADER, session_id);
+        }
+        if let Some(version) = self
+            .protocol_version
+            .lock()
+            .expect("protocol version lock poisoned")
+            .clone()
+        {
+            request = request.header(PROTOCOL_VERSION_HEADER, version);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| McpError::Transport(e.to_string()))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(McpError::Transport(format!("HTTP {}: {}", status, body)));
+        }
+
+        if let Some(session_id) = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            *self.session_id.lock().expect("session lock poisoned") = Some(session_id.to_string());
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn request(&self, message: Value) -> Result<Value, McpError> {
+        let response = self.post(&message).await?;
+        let id = &message["id"];
+        let is_event_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+
+        if !is_event_stream {
+            let body: Value = response
+                .json()
+                .await
+                .map_err(|e| McpError::Transport(e.to_string()))?;
+            // The response may come in a batch.
+            let response = match body {
+                Value::Array(messages) => messages.into_iter().find(|reply| &reply["id"] == id),
+                reply => Some(reply),
+            };
+            return response
+                .ok_or_else(|| McpError::Transport("the server sent no response".to_string()));
+        }
+
+        // Servers may send notifications and requests of their own before the response.
+        let mut events = response.bytes_stream().eventsource();
+        while let Some(event) = events.next().await {
+            let event = event.map_err(|e| McpError::Transport(e.to_string()))?;
+            let Ok(reply) = serde_json::from_str::<Value>(&event.data) else {
+                continue;
+            };
+            if reply.get("method").is_none() && &reply["id"] == id {
+                return Ok(reply);
+            }
+        }
+        Err(McpError::Transport(
+            "the server closed the stream without a response".to_string(),
+        ))
+    }
+
+    async fn notify(&self, message: Value) -> Result<(), McpError> {
+        self.post(&message).await.map(|_| ())
+    }
+
+    fn set_protocol_version(&self, version: &str) {
+        *self
+            .protocol_version
+            .lock()
+            .expect("protocol version lock poisoned") = Some(version.to_string());
+    }
+}
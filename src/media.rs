@@ -0,0 +1,182 @@
+//! Images and recordings attached to messages
+//!
+//! Photos, image documents and static stickers are downloaded from Telegram and passed to
+//! models with vision along with the message text. Photos come in several sizes, of which
+//! the largest is used; animated and video stickers are represented by their thumbnail.
+//! Voice notes, audio files and video notes are downloaded for transcription.
+
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::{FileMeta, Message};
+
+use crate::llm::Image;
+
+/// The largest file that is downloaded, in bytes
+///
+/// The Bot API does not serve files larger than 20 MB to bots anyway.
+const MAX_FILE_SIZE: u32 = 20 * 1024 * 1024;
+
+/// An image attached to a message, not downloaded yet
+#[derive(Clone, Debug)]
+pub struct ImageSource {
+    file_id: String,
+    /// The image's size in bytes
+    size: u32,
+    mime_type: String,
+}
+
+impl ImageSource {
+    /// Find the image attached to a message, if any
+    pub fn of_message(msg: &Message) -> Option<Self> {
+        if let Some(photo) = msg.photo().and_then(|sizes| {
+            sizes
+                .iter()
+                .max_by_key(|size| u64::from(size.width) * u64::from(size.height))
+        }) {
+            return Some(Self::new(&photo.file, "image/jpeg"));
+        }
+
+        if let Some(document) = msg.document() {
+            let mime_type = document.mime_type.as_ref()?.essence_str();
+            return mime_type
+                .starts_with("image/")
+                .then(|| Self::new(&document.file, mime_type));
+        }
+
+        let sticker = msg.sticker()?;
+        if sticker.is_static() {
+            Some(Self::new(&sticker.file, "image/webp"))
+        } else {
+            // Animated and video stickers are shown by their still thumbnail.
+            let thumbnail = sticker.thumbnail.as_ref()?;
+            Some(Self::new(&thumbnail.file, "image/webp"))
+        }
+    }
+
+    fn new(file: &FileMeta, mime_type: &str) -> Self {
+        Self {
+            file_id: file.id.clone(),
+            size: file.size,
+            mime_type: mime_type.to_string(),
+        }
+    }
+
+    /// Download the image from Telegram
+    pub async fn download(&self, bot: &Bot) -> Result<Image, String> {
+        let data = download(bot, &self.file_id, self.size).await?;
+
+        // Documents may be labelled wrongly, and sticker thumbnails come in several formats.
+        let mime_type = sniff_mime_type(&data)
+            .unwrap_or(&self.mime_type)
+            .to_string();
+        Ok(Image { mime_type, data })
+    }
+}
+
+/// A recording attached to a message, not downloaded yet
+#[derive(Clone, Debug)]
+pub struct AudioSource {
+    file_id: String,
+    /// The recording's size in bytes
+    size: u32,
+    /// The file name to upload the recording as, its extension telling the format
+    file_name: String,
+    mime_type: String,
+}
+
+/// A downloaded recording
+#[derive(Clone, Debug)]
+pub struct Audio {
+    pub file_name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl AudioSource {
+    /// Find the voice note, audio file or video note attached to a message, if any
+    pub fn of_message(msg: &Message) -> Option<Self> {
+        if let Some(voice) = msg.voice() {
+            return Some(Self {
+                file_id: voice.file.id.clone(),
+                size: voice.file.size,
+                file_name: "voice.ogg".to_string(),
+                mime_type: "audio/ogg".to_string(),
+            });
+        }
+
+        if let Some(audio) = msg.audio() {
+            let mime_type = audio
+                .mime_type
+                .as_ref()
+                .map_or("audio/mpeg", |mime| mime.essence_str());
+            return Some(Self {
+                file_id: audio.file.id.clone(),
+                size: audio.file.size,
+                file_name: audio
+                    .file_name
+                    .clone()
+                    .unwrap_or_else(|| "audio.mp3".to_string()),
+                mime_type: mime_type.to_string(),
+            });
+        }
+
+        let video_note = msg.video_note()?;
+        Some(Self {
+            file_id: video_note.file.id.clone(),
+            size: video_note.file.size,
+            file_name: "video_note.mp4".to_string(),
+            mime_type: "video/mp4".to_string(),
+        })
+    }
+
+    /// Download the recording from Telegram
+    pub async fn download(&self, bot: &Bot) -> Result<Audio, String> {
+        Ok(Audio {
+            file_name: self.file_name.clone(),
+            mime_type: self.mime_type.clone(),
+            data: download(bot, &self.file_id, self.size).await?,
+        })
+    }
+}
+
+/// Download a file from Telegram
+async fn download(bot: &Bot, file_id: &str, size: u32) -> Result<Vec<u8>, String> {
+    if size > MAX_FILE_SIZE {
+        return Err(format!("the file is too large ({} bytes)", size));
+    }
+
+    let file = bot
+        .get_file(file_id.to_string())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut data = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut data)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+/// Recognize the common image formats by their first bytes
+fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Check if a message has an image attached
+pub fn has_image(msg: &Message) -> bool {
+    ImageSource::of_message(msg).is_some()
+}
+
+/// Check if a message has a recording attached
+pub fn has_audio(msg: &Message) -> bool {
+    AudioSource::of_message(msg).is_some()
+}
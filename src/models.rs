@@ -0,0 +1,323 @@
+//! The models users can choose from
+//!
+//! Without further configuration the bot offers the single model named by `LLM_MODEL_NAME`.
+//! `MODELS_FILE` points to a TOML file listing several models, each with the provider that
+//! serves it, its context window, its price and whether it understands images; users then
+//! switch between them with `/model`. Depending on `MODEL_SELECTION_SCOPE` the choice applies
+//! to the whole chat or to the user who made it, and it is kept in the storage across
+//! restarts. When the selected model fails, the models listed in `LLM_FALLBACK_MODELS` are
+//! tried in order.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use teloxide::types::{ChatId, UserId};
+
+use crate::llm::{self, LlmProvider, Usage};
+use crate::storage::{Storage, StorageResult};
+
+/// The setting holding the selected model's name
+const SETTING_KEY: &str = "model";
+
+/// A model users can choose
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelInfo {
+    /// The name the provider knows the model by
+    pub name: String,
+    /// The name shown to users, if different
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// The provider serving the model, `LLM_PROVIDER` if not given
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// The number of tokens the model can take in, prompt and answer together
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    /// The price of a million prompt tokens
+    #[serde(default)]
+    pub prompt_price: Option<f64>,
+    /// The price of a million generated tokens
+    #[serde(default)]
+    pub completion_price: Option<f64>,
+    /// Whether the model understands images
+    #[serde(default)]
+    pub vision: bool,
+    /// The tiktoken encoding of the model, such as `o200k_base`, or `estimate` to estimate
+    /// token counts; found by the model's name if not given
+    #[serde(default)]
+    pub tokenizer: Option<String>,
+    /// The average number of characters per token, for estimating token counts
+    #[serde(default)]
+    pub chars_per_token: Option<f64>,
+}
+
+impl ModelInfo {
+    /// The name shown to users
+    pub fn display_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The price of the tokens spent on a request, counting missing prices as free
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let prompt = f64::from(usage.prompt_tokens) * self.prompt_price.unwrap_or_default();
+        let completion =
+            f64::from(usage.completion_tokens) * self.completion_price.unwrap_or_default();
+        (prompt + completion) / 1_000_000.0
+    }
+
+    /// Check if a name typed by a user refers to this model
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .alias
+                .as_deref()
+                .is_some_and(|alias| alias.eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for ModelInfo {
+    /// Describe the model with its context window and price, as far as they are known
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display_name())?;
+        let mut details = Vec::new();
+        if let Some(context_window) = self.context_window {
+            details.push(format!("{}k context", context_window / 1000));
+        }
+        if self.vision {
+            details.push("vision".to_string());
+        }
+        if let (Some(prompt), Some(completion)) = (self.prompt_price, self.completion_price) {
+            details.push(format!("${prompt} / ${completion} per 1M tokens"));
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The contents of `MODELS_FILE`
+#[derive(Debug, Deserialize)]
+struct ModelsFile {
+    models: Vec<ModelInfo>,
+}
+
+/// Who a model selection applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelScope {
+    /// Everyone in the chat, changed by chat administrators
+    Chat,
+    /// Only the user who made the choice, in every chat
+    User,
+}
+
+impl FromStr for ModelScope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "chat" => Ok(Self::Chat),
+            "user" => Ok(Self::User),
+            _ => Err(format!("unknown scope `{}`, expected chat or user", value)),
+        }
+    }
+}
+
+/// The available models and the providers serving them
+#[derive(Debug)]
+pub struct ModelRegistry {
+    /// The models, the default one first
+    models: Vec<ModelInfo>,
+    /// The providers by name
+    providers: HashMap<String, Arc<dyn LlmProvider>>,
+    /// The models tried in order when the selected one fails, as indices into `models`
+    fallbacks: Vec<usize>,
+    /// Who a model selection applies to
+    scope: ModelScope,
+}
+
+impl ModelRegistry {
+    /// Load the registry from `MODELS_FILE`, `LLM_MODEL_NAME`, `LLM_FALLBACK_MODELS` and
+    /// `MODEL_SELECTION_SCOPE`
+    ///
+    /// `LLM_MODEL_NAME` picks the default model; without it the first listed model is used.
+    pub fn from_env() -> Self {
+        let default_name = env::var("LLM_MODEL_NAME")
+            .or_else(|_| env::var("OPENAI_MODEL_NAME"))
+            .ok();
+        let mut models = match env::var("MODELS_FILE") {
+            Ok(path) => load_models(&path).unwrap_or_else(|error| panic!("{}", error)),
+            Err(_) => {
+                let name = default_name.clone().expect("LLM_MODEL_NAME must be set");
+                let alias = env::var("LLM_MODEL_ALIAS")
+                    .or_else(|_| env::var("OPENAI_MODEL_ALIAS"))
+                    .ok();
+                vec![ModelInfo {
+                    name,
+                    alias,
+                    provider: None,
+                    context_window: env::var("LLM_CONTEXT_WINDOW")
+                        .ok()
+                        .and_then(|value| value.parse().ok()),
+                    prompt_price: None,
+                    completion_price: None,
+                    vision: env::var("LLM_MODEL_VISION")
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(false),
+                    tokenizer: None,
+                    chars_per_token: None,
+                }]
+            }
+        };
+        assert!(
+            !models.is_empty(),
+            "MODELS_FILE must list at least one model"
+        );
+
+        // Move the default model to the front
+        if let Some(default_name) = default_name {
+            let index = models
+                .iter()
+                .position(|model| model.name == default_name)
+                .unwrap_or_else(|| {
+                    panic!("LLM_MODEL_NAME `{}` is not in MODELS_FILE", default_name)
+                });
+            let model = models.remove(index);
+            models.insert(0, model);
+        }
+
+        // Every model gets a provider, and every provider is set up once
+        let default_provider = llm::default_provider_name();
+        let mut providers = HashMap::new();
+        for model in &mut models {
+            let provider = model
+                .provider
+                .get_or_insert_with(|| default_provider.clone());
+            provider.make_ascii_lowercase();
+            providers
+                .entry(provider.clone())
+                .or_insert_with(|| llm::provider_from_env(provider));
+        }
+
+        let fallbacks = env::var("LLM_FALLBACK_MODELS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                models
+                    .iter()
+                    .position(|model| model.name == name)
+                    .unwrap_or_else(|| panic!("Fallback model `{}` is not in MODELS_FILE", name))
+            })
+            .collect();
+
+        let scope = match env::var("MODEL_SELECTION_SCOPE") {
+            Ok(value) => value
+                .parse()
+                .unwrap_or_else(|error| panic!("Invalid MODEL_SELECTION_SCOPE: {}", error)),
+            Err(_) => ModelScope::Chat,
+        };
+
+        Self {
+            models,
+            providers,
+            fallbacks,
+            scope,
+        }
+    }
+
+    /// All available models, the default one first
+    pub fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    /// The model used until another one is selected
+    pub fn default_model(&self) -> &ModelInfo {
+        &self.models[0]
+    }
+
+    /// Who a model selection applies to
+    pub fn scope(&self) -> ModelScope {
+        self.scope
+    }
+
+    /// Find a model by its name or alias
+    pub fn find(&self, name: &str) -> Option<&ModelInfo> {
+        self.models.iter().find(|model| model.matches(name))
+    }
+
+    /// The provider serving a model
+    pub fn provider(&self, model: &ModelInfo) -> Arc<dyn LlmProvider> {
+        let name = model.provider.as_deref().unwrap_or_default();
+        Arc::clone(&self.providers[name])
+    }
+
+    /// The models to try for a request, in order: the selected one, then the fallbacks
+    ///
+    /// Requests with images only fall back to models with vision.
+    pub fn fallback_chain<'a>(
+        &'a self,
+        selected: &'a ModelInfo,
+        needs_vision: bool,
+    ) -> Vec<&'a ModelInfo> {
+        let fallbacks = self
+            .fallbacks
+            .iter()
+            .map(|&index| &self.models[index])
+            .filter(|model| model.name != selected.name)
+            .filter(|model| model.vision || !needs_vision);
+        std::iter::once(selected).chain(fallbacks).collect()
+    }
+
+    /// The model selected for a chat or user, falling back to the default model
+    pub fn selected(
+        &self,
+        storage: &dyn Storage,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+    ) -> &ModelInfo {
+        let name = match (self.scope, user_id) {
+            (ModelScope::User, Some(user_id)) => storage.user_setting(user_id, SETTING_KEY),
+            (ModelScope::User, None) => Ok(None),
+            (ModelScope::Chat, _) => storage.chat_setting(chat_id, SETTING_KEY),
+        };
+        let name = name.unwrap_or_else(|error| {
+            log::error!("Failed to load selected model: {}", error);
+            None
+        });
+
+        // Selections of models that were since removed from the registry are ignored.
+        name.and_then(|name| self.models.iter().find(|model| model.name == name))
+            .unwrap_or_else(|| self.default_model())
+    }
+
+    /// Remember the model selected for a chat or user
+    pub fn select(
+        &self,
+        storage: &dyn Storage,
+        chat_id: ChatId,
+        user_id: UserId,
+        model: &ModelInfo,
+    ) -> StorageResult<()> {
+        match self.scope {
+            ModelScope::User => storage.set_user_setting(user_id, SETTING_KEY, &model.name),
+            ModelScope::Chat => storage.set_chat_setting(chat_id, SETTING_KEY, &model.name),
+        }
+    }
+}
+
+/// Read the model list from a TOML file
+fn load_models(path: &str) -> Result<Vec<ModelInfo>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read MODELS_FILE {}: {}", path, e))?;
+    let file: ModelsFile =
+        toml::from_str(&contents).map_err(|e| format!("Invalid MODELS_FILE {}: {}", path, e))?;
+    Ok(file.models)
+}
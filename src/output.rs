@@ -0,0 +1,298 @@
+//! Fitting answers into Telegram messages
+//!
+//! Telegram rejects messages longer than 4096 characters, so long answers are split into
+//! several messages on paragraph boundaries, keeping code blocks intact where possible and
+//! re-opening them in the next message where not. Very long answers can be sent as a
+//! Markdown document instead.
+
+use std::env;
+use std::time::Duration;
+
+/// The longest text a single Telegram message can hold, in UTF-16 code units
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// How answers are delivered to the chat
+#[derive(Clone, Copy, Debug)]
+pub struct OutputConfig {
+    /// Minimum time between two edits of a streamed reply
+    pub edit_interval: Duration,
+    /// Answers longer than this many characters are sent as a document instead of messages
+    pub document_threshold: Option<usize>,
+    /// Whether answers carry Stop, Regenerate and Continue buttons
+    pub buttons: bool,
+}
+
+impl OutputConfig {
+    /// Read the output settings from `STREAM_EDIT_INTERVAL_MS`, `REPLY_DOCUMENT_THRESHOLD` and
+    /// `ANSWER_BUTTONS`
+    pub fn from_env() -> Self {
+        let edit_interval = env::var("STREAM_EDIT_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1000);
+        let document_threshold = env::var("REPLY_DOCUMENT_THRESHOLD")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&threshold| threshold > 0);
+        let buttons = env::var("ANSWER_BUTTONS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(true);
+
+        Self {
+            edit_interval: Duration::from_millis(edit_interval),
+            document_threshold,
+            buttons,
+        }
+    }
+
+    /// Check if an answer is long enough to be sent as a document
+    pub fn wants_document(&self, text: &str) -> bool {
+        self.document_threshold
+            .is_some_and(|threshold| text.chars().count() > threshold)
+    }
+}
+
+/// The length of a text as Telegram counts it
+pub fn text_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Split a text into parts of at most `limit` characters each
+///
+/// Texts that fit are returned unchanged. Otherwise the text is split between paragraphs
+/// and code blocks first, then between lines, words and finally characters, and code
+/// blocks that have to be split are closed and re-opened around each part.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    if text_len(text) <= limit {
+        return vec![text.to_string()];
+    }
+
+    let parts = blocks(text)
+        .into_iter()
+        .flat_map(|block| split_block(&block, limit));
+    pack(parts, "\n\n", limit)
+}
+
+/// Break a text into paragraphs and fenced code blocks
+fn blocks(text: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current = String::new();
+    let mut in_code = false;
+
+    for line in text.lines() {
+        let is_fence = line.trim_start().starts_with("```");
+        if in_code {
+            current.push('\n');
+            current.push_str(line);
+            if is_fence {
+                in_code = false;
+                blocks.push(std::mem::take(&mut current));
+            }
+        } else if is_fence {
+            if !current.is_empty() {
+                blocks.push(std::mem::take(&mut current));
+            }
+            in_code = true;
+            current.push_str(line);
+        } else if line.trim().is_empty() {
+            if !current.is_empty() {
+                blocks.push(std::mem::take(&mut current));
+            }
+        } else {
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(line);
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+
+    blocks
+}
+
+/// Split a single paragraph or code block into parts that fit the limit
+fn split_block(block: &str, limit: usize) -> Vec<String> {
+    if text_len(block) <= limit {
+        return vec![block.to_string()];
+    }
+
+    if block.trim_start().starts_with("```") {
+        // Every part of a code block is wrapped into the block's own fences.
+        let (opening, body) = block.split_once('\n').unwrap_or((block, ""));
+        let body = body.trim_end();
+        let body = body
+            .strip_suffix("```")
+            .unwrap_or(body)
+            .trim_end_matches('\n');
+        let budget = limit.saturating_sub(text_len(opening) + 5).max(1);
+        let lines = body.split('\n').flat_map(|line| hard_split(line, budget));
+        pack(lines, "\n", budget)
+            .into_iter()
+            .map(|part| format!("{opening}\n{part}\n```"))
+            .collect()
+    } else {
+        let lines = block.split('\n').flat_map(|line| {
+            if text_len(line) <= limit {
+                vec![line.to_string()]
+            } else {
+                let words = line.split(' ').flat_map(|word| hard_split(word, limit));
+                pack(words, " ", limit)
+            }
+        });
+        pack(lines, "\n", limit)
+    }
+}
+
+/// Greedily join parts (each within the limit) into as few pieces as possible
+fn pack(parts: impl IntoIterator<Item = String>, separator: &str, limit: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current: Option<String> = None;
+
+    for part in parts {
+        current = Some(match current.take() {
+            Some(mut piece)
+                if text_len(&piece) + text_len(separator) + text_len(&part) <= limit =>
+            {
+                piece.push_str(separator);
+                piece.push_str(&part);
+                piece
+            }
+            Some(piece) => {
+                pieces.push(piece);
+                part
+            }
+            None => part,
+        });
+    }
+    pieces.extend(current);
+
+    pieces
+}
+
+/// Cut a text into pieces of at most `limit` characters, regardless of its content
+fn hard_split(text: &str, limit: usize) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut len = 0;
+
+    for c in text.chars() {
+        if len + c.len_utf16() > limit {
+            pieces.push(String::new());
+            len = 0;
+        }
+        pieces.last_mut().unwrap().push(c);
+        len += c.len_utf16();
+    }
+
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::to_telegram_html;
+
+    /// Check that every opened HTML tag is closed again, innermost first
+    fn assert_balanced(html: &str) {
+        let mut open = Vec::new();
+        for tag in html.split('<').skip(1) {
+            let name: String = tag
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            if tag.starts_with('/') {
+                assert_eq!(open.pop(), Some(name), "unbalanced tags in {html}");
+            } else {
+                open.push(name);
+            }
+        }
+        assert!(open.is_empty(), "unclosed tags in {html}");
+    }
+
+    #[test]
+    fn keeps_short_texts_whole() {
+        assert_eq!(split_message("hello", 10), vec!["hello"]);
+    }
+
+    #[test]
+    fn splits_between_paragraphs_first() {
+        assert_eq!(
+            split_message("first part\n\nsecond part", 15),
+            vec!["first part", "second part"]
+        );
+    }
+
+    #[test]
+    fn reopens_code_blocks_in_every_part() {
+        let code = (0..20)
+            .map(|i| format!("line {i}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = format!("```rust\n{code}\n```");
+        let parts = split_message(&text, 60);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(text_len(part) <= 60);
+            assert!(part.starts_with("```rust\n"));
+            assert!(part.ends_with("\n```"));
+            assert_balanced(&to_telegram_html(part));
+        }
+    }
+
+    #[test]
+    fn formatting_split_across_parts_renders_balanced() {
+        // Italic nested in bold, both cut in the middle
+        let text = format!(
+            "**{}_{}_{}**",
+            "bold ".repeat(15),
+            "bold italic ".repeat(15),
+            " bold".repeat(15)
+        );
+        let parts = split_message(&text, 100);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(text_len(part) <= 100);
+            assert_balanced(&to_telegram_html(part));
+        }
+    }
+
+    #[test]
+    fn counts_surrogate_pairs_as_two_units() {
+        // Each emoji takes two UTF-16 units, so 2048 of them fill a message exactly.
+        let full = "😀".repeat(MAX_MESSAGE_LEN / 2);
+        assert_eq!(text_len(&full), MAX_MESSAGE_LEN);
+        assert_eq!(split_message(&full, MAX_MESSAGE_LEN), vec![full.clone()]);
+
+        let over = format!("{full}😀");
+        let parts = split_message(&over, MAX_MESSAGE_LEN);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(text_len(&parts[0]), MAX_MESSAGE_LEN);
+        assert_eq!(parts[1], "😀");
+    }
+
+    #[test]
+    fn never_splits_a_surrogate_pair() {
+        // An odd limit would fall into the middle of an emoji.
+        let text = format!("a{}", "😀".repeat(MAX_MESSAGE_LEN / 2));
+        let parts = split_message(&text, MAX_MESSAGE_LEN);
+        assert_eq!(parts.concat(), text);
+        for part in &parts {
+            assert!(text_len(part) <= MAX_MESSAGE_LEN);
+        }
+        assert_eq!(text_len(&parts[0]), MAX_MESSAGE_LEN - 1);
+    }
+
+    #[test]
+    fn sends_long_answers_as_documents_above_the_threshold() {
+        let output = OutputConfig {
+            edit_interval: Duration::from_secs(1),
+            document_threshold: Some(5),
+            buttons: false,
+        };
+        assert!(!output.wants_document("12345"));
+        assert!(output.wants_document("123456"));
+    }
+}
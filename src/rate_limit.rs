@@ -0,0 +1,409 @@
+//! Limits on how often users and chats ask the model
+//!
+//! Every user and every chat has a token bucket holding as many requests as they may make per
+//! minute, refilled evenly over the minute, and a limit on the requests answered at the same
+//! time. The user limits depend on the user's role: `RATE_LIMIT_MEMBER_PER_MINUTE`,
+//! `RATE_LIMIT_CHAT_ADMIN_PER_MINUTE` and `RATE_LIMIT_BOT_ADMIN_PER_MINUTE`, each with a
+//! matching `_CONCURRENT` variable. The chat limits, `RATE_LIMIT_CHAT_PER_MINUTE` and
+//! `RATE_LIMIT_CHAT_CONCURRENT`, apply to everyone in the chat. A limit of 0 means none.
+//! Answers, generated images and transcribed recordings all count as requests.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use teloxide::types::{ChatId, UserId};
+
+use crate::access::Role;
+
+/// The number of buckets above which full ones are dropped, as they hold no information
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// How many requests may be made per minute and at the same time
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Requests per minute, unlimited if 0
+    pub per_minute: u32,
+    /// Requests answered at the same time, unlimited if 0
+    pub concurrent: u32,
+}
+
+impl Limits {
+    /// Read the limits from `<prefix>_PER_MINUTE` and `<prefix>_CONCURRENT`
+    fn from_env(prefix: &str, per_minute: u32, concurrent: u32) -> Self {
+        let read = |suffix: &str, default: u32| {
+            env::var(format!("{}_{}", prefix, suffix))
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            per_minute: read("PER_MINUTE", per_minute),
+            concurrent: read("CONCURRENT", concurrent),
+        }
+    }
+}
+
+/// Why a request was turned down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exceeded {
+    /// Too many requests in the last minute; the next one is possible after the duration
+    Rate(Duration),
+    /// Too many requests are being answered already
+    Concurrency,
+}
+
+/// Whose requests are counted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    User(UserId),
+    Chat(ChatId),
+}
+
+/// A token bucket, refilled continuously
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// The requests per minute the bucket was last counted against
+    per_minute: u32,
+}
+
+impl Bucket {
+    /// The tokens in the bucket now, given its capacity
+    fn refilled(&self, per_minute: u32, now: Instant) -> f64 {
+        let refill = now.duration_since(self.updated).as_secs_f64() * f64::from(per_minute) / 60.0;
+        (self.tokens + refill).min(f64::from(per_minute))
+    }
+}
+
+/// The buckets and the requests in flight
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<Key, Bucket>,
+    in_flight: HashMap<Key, u32>,
+}
+
+/// Counts requests per user and chat against their limits
+#[derive(Debug)]
+pub struct RateLimiter {
+    member: Limits,
+    chat_admin: Limits,
+    bot_admin: Limits,
+    chat: Limits,
+    state: Mutex<State>,
+}
+
+/// A request let through, counted as in flight until dropped
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<RateLimiter>,
+    keys: Vec<Key>,
+}
+
+impl RateLimiter {
+    /// Read the limits of each role and of chats from the environment
+    pub fn from_env() -> Self {
+        Self {
+            member: Limits::from_env("RATE_LIMIT_MEMBER", 10, 2),
+            chat_admin: Limits::from_env("RATE_LIMIT_CHAT_ADMIN", 20, 3),
+            bot_admin: Limits::from_env("RATE_LIMIT_BOT_ADMIN", 0, 0),
+            chat: Limits::from_env("RATE_LIMIT_CHAT", 30, 5),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The limits of a user with a role
+    fn for_role(&self, role: Role) -> Limits {
+        match role {
+            Role::BotAdmin => self.bot_admin,
+            Role::ChatAdmin => self.chat_admin,
+            Role::Member => self.member,
+        }
+    }
+
+    /// Count a request of a user in a chat, if the limits of both allow it
+    ///
+    /// Nothing is counted for requests that are turned down.
+    pub fn acquire(
+        self: &Arc<Self>,
+        chat_id: ChatId,
+        user: Option<(UserId, Role)>,
+    ) -> Result<Permit, Exceeded> {
+        self.acquire_at(chat_id, user, Instant::now())
+    }
+
+    /// Count a request made at `now`
+    fn acquire_at(
+        self: &Arc<Self>,
+        chat_id: ChatId,
+        user: Option<(UserId, Role)>,
+        now: Instant,
+    ) -> Result<Permit, Exceeded> {
+        let mut checks = vec![(Key::Chat(chat_id), self.chat)];
+        if let Some((user_id, role)) = user {
+            checks.push((Key::User(user_id), self.for_role(role)));
+        }
+
+        let mut state = self.state.lock().expect("rate limiter lock poisoned");
+
+        // Check every limit before counting against any of them
+        let mut longest_wait = None;
+        for &(key, limits) in &checks {
+            let in_flight = state.in_flight.get(&key).copied().unwrap_or_default();
+            if limits.concurrent > 0 && in_flight >= limits.concurrent {
+                return Err(Exceeded::Concurrency);
+            }
+            if limits.per_minute > 0 {
+                let tokens = state
+                    .buckets
+                    .get(&key)
+                    .map_or(f64::from(limits.per_minute), |bucket| {
+                        bucket.refilled(limits.per_minute, now)
+                    });
+                if tokens < 1.0 {
+                    let wait = Duration::from_secs_f64(
+                        (1.0 - tokens) * 60.0 / f64::from(limits.per_minute),
+                    );
+                    longest_wait = longest_wait.max(Some(wait));
+                }
+            }
+        }
+        if let Some(wait) = longest_wait {
+            return Err(Exceeded::Rate(wait));
+        }
+
+        if state.buckets.len() > PRUNE_THRESHOLD {
+            // Buckets that refilled completely are the same as missing ones.
+            state.buckets.retain(|_, bucket| {
+                bucket.refilled(bucket.per_minute, now) < f64::from(bucket.per_minute)
+            });
+        }
+
+        for &(key, limits) in &checks {
+            if limits.per_minute > 0 {
+                let tokens = state
+                    .buckets
+                    .get(&key)
+                    .map_or(f64::from(limits.per_minute), |bucket| {
+                        bucket.refilled(limits.per_minute, now)
+                    });
+                state.buckets.insert(
+                    key,
+                    Bucket {
+                        tokens: tokens - 1.0,
+                        updated: now,
+                        per_minute: limits.per_minute,
+                    },
+                );
+            }
+            *state.in_flight.entry(key).or_default() += 1;
+        }
+
+        Ok(Permit {
+            limiter: Arc::clone(self),
+            keys: checks.into_iter().map(|(key, _)| key).collect(),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self
+            .limiter
+            .state
+            .lock()
+            .expect("rate limiter lock poisoned");
+        for key in &self.keys {
+            if let Some(count) = state.in_flight.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    state.in_flight.remove(key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT: ChatId = ChatId(-100);
+    const ALICE: UserId = UserId(1);
+    const BOB: UserId = UserId(2);
+
+    /// A limiter with the given member and chat limits, and none for administrators
+    fn limiter(member: Limits, chat: Limits) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter {
+            member,
+            chat_admin: unlimited(),
+            bot_admin: unlimited(),
+            chat,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    fn limits(per_minute: u32, concurrent: u32) -> Limits {
+        Limits {
+            per_minute,
+            concurrent,
+        }
+    }
+
+    fn unlimited() -> Limits {
+        limits(0, 0)
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter(limits(2, 0), unlimited());
+        let start = Instant::now();
+        let alice = Some((ALICE, Role::Member));
+
+        assert!(limiter.acquire_at(CHAT, alice, start).is_ok());
+        assert!(limiter.acquire_at(CHAT, alice, start).is_ok());
+        assert!(limiter.acquire_at(CHAT, alice, start).is_err());
+
+        // Two requests per minute refill one every 30 seconds.
+        let later = start + Duration::from_secs(29);
+        assert!(limiter.acquire_at(CHAT, alice, later).is_err());
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.acquire_at(CHAT, alice, later).is_ok());
+        assert!(limiter.acquire_at(CHAT, alice, later).is_err());
+    }
+
+    #[test]
+    fn refills_no_more_than_the_limit() {
+        let limiter = limiter(limits(2, 0), unlimited());
+        let start = Instant::now();
+        let alice = Some((ALICE, Role::Member));
+        assert!(limiter.acquire_at(CHAT, alice, start).is_ok());
+
+        let later = start + Duration::from_secs(3600);
+        assert!(limiter.acquire_at(CHAT, alice, later).is_ok());
+        assert!(limiter.acquire_at(CHAT, alice, later).is_ok());
+        assert!(limiter.acquire_at(CHAT, alice, later).is_err());
+    }
+
+    #[test]
+    fn reports_the_wait_until_the_next_request() {
+        let limiter = limiter(limits(2, 0), unlimited());
+        let start = Instant::now();
+        let alice = Some((ALICE, Role::Member));
+        for _ in 0..2 {
+            assert!(limiter.acquire_at(CHAT, alice, start).is_ok());
+        }
+
+        assert_eq!(
+            limiter.acquire_at(CHAT, alice, start).unwrap_err(),
+            Exceeded::Rate(Duration::from_secs(30))
+        );
+        let later = start + Duration::from_secs(15);
+        assert_eq!(
+            limiter.acquire_at(CHAT, alice, later).unwrap_err(),
+            Exceeded::Rate(Duration::from_secs(15))
+        );
+    }
+
+    #[test]
+    fn reports_the_longest_wait_of_user_and_chat() {
+        let limiter = limiter(limits(2, 0), limits(1, 0));
+        let start = Instant::now();
+        assert!(
+            limiter
+                .acquire_at(CHAT, Some((ALICE, Role::Member)), start)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .acquire_at(CHAT, Some((ALICE, Role::Member)), start)
+                .is_err()
+        );
+
+        // The user has a request left, the chat's one refills after a minute.
+        assert_eq!(
+            limiter
+                .acquire_at(CHAT, Some((BOB, Role::Member)), start)
+                .unwrap_err(),
+            Exceeded::Rate(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn counts_nothing_for_requests_turned_down() {
+        let limiter = limiter(limits(1, 0), limits(2, 0));
+        let start = Instant::now();
+        let alice = Some((ALICE, Role::Member));
+        assert!(limiter.acquire_at(CHAT, alice, start).is_ok());
+        for _ in 0..5 {
+            assert!(limiter.acquire_at(CHAT, alice, start).is_err());
+        }
+
+        // Alice's refused requests did not use up the chat's second request.
+        assert!(
+            limiter
+                .acquire_at(CHAT, Some((BOB, Role::Member)), start)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn releases_permits_on_drop() {
+        let limiter = limiter(limits(0, 1), unlimited());
+        let start = Instant::now();
+        let alice = Some((ALICE, Role::Member));
+
+        let permit = limiter.acquire_at(CHAT, alice, start).unwrap();
+        assert_eq!(
+            limiter.acquire_at(CHAT, alice, start).unwrap_err(),
+            Exceeded::Concurrency
+        );
+        // Other users are not affected by Alice's request in flight.
+        assert!(
+            limiter
+                .acquire_at(CHAT, Some((BOB, Role::Member)), start)
+                .is_ok()
+        );
+
+        drop(permit);
+        assert!(limiter.state.lock().unwrap().in_flight.is_empty());
+        assert!(limiter.acquire_at(CHAT, alice, start).is_ok());
+    }
+
+    #[test]
+    fn limits_concurrent_requests_per_chat() {
+        let limiter = limiter(unlimited(), limits(0, 1));
+        let start = Instant::now();
+
+        let permit = limiter
+            .acquire_at(CHAT, Some((ALICE, Role::Member)), start)
+            .unwrap();
+        assert_eq!(
+            limiter
+                .acquire_at(CHAT, Some((BOB, Role::Member)), start)
+                .unwrap_err(),
+            Exceeded::Concurrency
+        );
+        drop(permit);
+        assert!(
+            limiter
+                .acquire_at(CHAT, Some((BOB, Role::Member)), start)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn applies_the_limits_of_the_role() {
+        let limiter = limiter(limits(1, 0), unlimited());
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert!(
+                limiter
+                    .acquire_at(CHAT, Some((ALICE, Role::BotAdmin)), start)
+                    .is_ok()
+            );
+        }
+    }
+}
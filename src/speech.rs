@@ -0,0 +1,210 @@
+//! Speech recognition and synthesis
+//!
+//! Voice notes, audio files and video notes are transcribed through an OpenAI-compatible
+//! `/audio/transcriptions` endpoint, such as OpenAI's own or a local whisper server. The
+//! transcript then stands in for the message text: it is answered by the model when the
+//! bot is addressed. Recordings not addressed to the bot are left alone, unless
+//! `VOICE_TRANSCRIPT_REPLIES` asks for their transcript to be sent back as a reply.
+//!
+//! Answers can also be read out through an OpenAI-compatible `/audio/speech` endpoint and
+//! sent as voice messages, for chats that turned this on with `/voice` and for users who
+//! sent a voice note themselves.
+
+use std::env;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+use teloxide::types::ChatId;
+
+use crate::llm::{self, LlmError};
+use crate::media::Audio;
+use crate::storage::{Storage, StorageResult};
+
+/// The name transcription errors are reported under
+const TRANSCRIPTION_PROVIDER: &str = "Transcription";
+
+/// The name speech synthesis errors are reported under
+const SPEECH_PROVIDER: &str = "Speech";
+
+/// How long a transcription or speech synthesis may take
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The longest text the speech endpoint reads out, in characters
+pub const MAX_SPEECH_LEN: usize = 4096;
+
+/// The chat setting turning voice replies on
+const VOICE_SETTING_KEY: &str = "voice_replies";
+
+/// A client of a speech-to-text API
+#[derive(Clone, Debug)]
+pub struct Transcriber {
+    http: reqwest::Client,
+    api_base: String,
+    api_key: Option<String>,
+    model: String,
+    /// Whether recordings not addressed to the bot are answered with their transcript
+    pub reply_unaddressed: bool,
+}
+
+/// The response of the transcription endpoint
+#[derive(Debug, Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+impl Transcriber {
+    /// Create the client from `TRANSCRIPTION_MODEL`, `TRANSCRIPTION_API_BASE`,
+    /// `TRANSCRIPTION_API_KEY` and `VOICE_TRANSCRIPT_REPLIES`
+    ///
+    /// Returns `None` if no transcription model is configured. The API base and key default
+    /// to `OPENAI_API_BASE` and `OPENAI_API_KEY`.
+    pub fn from_env() -> Option<Self> {
+        let model = env::var("TRANSCRIPTION_MODEL").ok()?;
+        let api_base = env::var("TRANSCRIPTION_API_BASE")
+            .or_else(|_| env::var("OPENAI_API_BASE"))
+            .expect("TRANSCRIPTION_API_BASE must be set");
+        let api_key = env::var("TRANSCRIPTION_API_KEY")
+            .or_else(|_| env::var("OPENAI_API_KEY"))
+            .ok();
+        let reply_unaddressed = env::var("VOICE_TRANSCRIPT_REPLIES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(false);
+
+        Some(Self {
+            http: reqwest::Client::new(),
+            api_base,
+            api_key,
+            model,
+            reply_unaddressed,
+        })
+    }
+
+    /// Transcribe a recording
+    pub async fn transcribe(&self, audio: Audio) -> Result<String, LlmError> {
+        let file = reqwest::multipart::Part::bytes(audio.data)
+            .file_name(audio.file_name)
+            .mime_str(&audio.mime_type)
+            .map_err(|e| LlmError::invalid_response(TRANSCRIPTION_PROVIDER, e))?;
+        let form = reqwest::multipart::Form::new()
+            .text("model", self.model.clone())
+            .part("file", file);
+
+        let mut request = self
+            .http
+            .post(format!("{}/audio/transcriptions", self.api_base))
+            .timeout(TIMEOUT)
+            .multipart(form);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| request_error(TRANSCRIPTION_PROVIDER, e))?;
+
+        let response: TranscriptionResponse = llm::check_response(TRANSCRIPTION_PROVIDER, response)
+            .await?
+            .json()
+            .await
+            .map_err(|e| LlmError::invalid_response(TRANSCRIPTION_PROVIDER, e))?;
+        Ok(response.text.trim().to_string())
+    }
+}
+
+/// A client of a text-to-speech API
+#[derive(Clone, Debug)]
+pub struct Speaker {
+    http: reqwest::Client,
+    api_base: String,
+    api_key: Option<String>,
+    model: String,
+    voice: String,
+}
+
+impl Speaker {
+    /// Create the client from `SPEECH_MODEL`, `SPEECH_VOICE`, `SPEECH_API_BASE` and
+    /// `SPEECH_API_KEY`
+    ///
+    /// Returns `None` if no speech model is configured. The API base and key default to
+    /// `OPENAI_API_BASE` and `OPENAI_API_KEY`.
+    pub fn from_env() -> Option<Self> {
+        let model = env::var("SPEECH_MODEL").ok()?;
+        let api_base = env::var("SPEECH_API_BASE")
+            .or_else(|_| env::var("OPENAI_API_BASE"))
+            .expect("SPEECH_API_BASE must be set");
+        let api_key = env::var("SPEECH_API_KEY")
+            .or_else(|_| env::var("OPENAI_API_KEY"))
+            .ok();
+
+        Some(Self {
+            http: reqwest::Client::new(),
+            api_base,
+            api_key,
+            model,
+            voice: env::var("SPEECH_VOICE").unwrap_or_else(|_| "alloy".to_string()),
+        })
+    }
+
+    /// Read out a text, returning the recording as OGG/Opus, which Telegram plays inline
+    pub async fn synthesize(&self, text: &str) -> Result<Vec<u8>, LlmError> {
+        let body = json!({
+            "model": self.model,
+            "voice": self.voice,
+            "input": text,
+            "response_format": "opus",
+        });
+
+        let mut request = self
+            .http
+            .post(format!("{}/audio/speech", self.api_base))
+            .timeout(TIMEOUT)
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| request_error(SPEECH_PROVIDER, e))?;
+
+        let audio = llm::check_response(SPEECH_PROVIDER, response)
+            .await?
+            .bytes()
+            .await
+            .map_err(|e| LlmError::network(SPEECH_PROVIDER, e))?;
+        if audio.is_empty() {
+            return Err(LlmError::EmptyResponse);
+        }
+        Ok(audio.to_vec())
+    }
+}
+
+/// Build the error for a failed HTTP request
+fn request_error(provider: &'static str, error: reqwest::Error) -> LlmError {
+    if error.is_timeout() {
+        LlmError::Timeout
+    } else {
+        LlmError::network(provider, error)
+    }
+}
+
+/// Check if a chat has turned voice replies on
+pub fn voice_replies(storage: &dyn Storage, chat_id: ChatId) -> StorageResult<bool> {
+    let value = storage.chat_setting(chat_id, VOICE_SETTING_KEY)?;
+    Ok(value.as_deref() == Some("on"))
+}
+
+/// Turn voice replies of a chat on or off
+pub fn set_voice_replies(
+    storage: &dyn Storage,
+    chat_id: ChatId,
+    enabled: bool,
+) -> StorageResult<()> {
+    if enabled {
+        storage.set_chat_setting(chat_id, VOICE_SETTING_KEY, "on")
+    } else {
+        storage.delete_chat_setting(chat_id, VOICE_SETTING_KEY)
+    }
+}
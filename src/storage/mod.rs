@@ -0,0 +1,150 @@
+//! Persistent storage for chats, messages, settings and usage
+//!
+//! Everything the bot needs to survive a restart goes through the [`Storage`] trait, so the
+//! SQLite backend can be swapped for another one without touching the handlers.
+
+mod sqlite;
+
+use std::fmt;
+
+use teloxide::types::{Chat, ChatId, MessageId, UserId};
+
+use crate::history::{ConversationKey, Turn};
+
+pub use sqlite::SqliteStorage;
+
+/// Error raised by a storage backend
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Result type of all storage operations
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Token usage of a single LLM request
+#[derive(Clone, Debug)]
+pub struct UsageRecord {
+    /// The chat the request was made in
+    pub chat_id: ChatId,
+    /// The user who triggered the request, if known
+    pub user_id: Option<UserId>,
+    /// The model that served the request
+    pub model: String,
+    /// Tokens consumed by the prompt
+    pub prompt_tokens: u32,
+    /// Tokens generated in the answer
+    pub completion_tokens: u32,
+    /// The price of the tokens, by the model's configured prices
+    pub cost: f64,
+}
+
+/// Whose usage is summed up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsageScope {
+    /// A user's requests, across all chats
+    User(UserId),
+    /// All requests made in a chat
+    Chat(ChatId),
+}
+
+/// The time usage is summed up over, in UTC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsagePeriod {
+    Today,
+    ThisMonth,
+}
+
+/// The usage of one model, summed up
+#[derive(Clone, Debug, Default)]
+pub struct UsageTotals {
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+/// A storage backend for everything the bot persists
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Record (or refresh) the metadata of a chat the bot is active in
+    fn save_chat(&self, chat: &Chat) -> StorageResult<()>;
+
+    /// Persist a conversation turn
+    fn save_message(&self, key: ConversationKey, turn: &Turn) -> StorageResult<()>;
+
+    /// Get up to `limit` of the most recent turns of a conversation, newest first
+    fn recent_messages(&self, key: ConversationKey, limit: usize) -> StorageResult<Vec<Turn>>;
+
+    /// Look up a single turn by its Telegram message id
+    fn message(&self, chat_id: ChatId, message_id: MessageId) -> StorageResult<Option<Turn>>;
+
+    /// Look up the answer given to a prompt
+    fn answer(&self, chat_id: ChatId, prompt_id: MessageId) -> StorageResult<Option<Turn>>;
+
+    /// Put a turn in place of a stored one, keeping its place in the conversation
+    ///
+    /// Returns whether `previous` was stored.
+    fn replace_message(
+        &self,
+        chat_id: ChatId,
+        previous: MessageId,
+        turn: &Turn,
+    ) -> StorageResult<bool>;
+
+    /// Delete a single turn, returning whether there was one
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> StorageResult<bool>;
+
+    /// Delete all turns of a conversation, returning how many there were
+    fn delete_messages(&self, key: ConversationKey) -> StorageResult<usize>;
+
+    /// Find up to `limit` turns of a chat containing `query`, ignoring case, newest first
+    fn search_messages(
+        &self,
+        chat_id: ChatId,
+        query: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<Turn>>;
+
+    /// Read a per-chat setting
+    fn chat_setting(&self, chat_id: ChatId, key: &str) -> StorageResult<Option<String>>;
+
+    /// Store a per-chat setting, replacing any previous value
+    fn set_chat_setting(&self, chat_id: ChatId, key: &str, value: &str) -> StorageResult<()>;
+
+    /// Remove a per-chat setting
+    fn delete_chat_setting(&self, chat_id: ChatId, key: &str) -> StorageResult<()>;
+
+    /// Read a per-user setting
+    fn user_setting(&self, user_id: UserId, key: &str) -> StorageResult<Option<String>>;
+
+    /// Store a per-user setting, replacing any previous value
+    fn set_user_setting(&self, user_id: UserId, key: &str, value: &str) -> StorageResult<()>;
+
+    /// Add the token usage of a request to the daily counters
+    fn record_usage(&self, usage: &UsageRecord) -> StorageResult<()>;
+
+    /// Sum up the usage of a user or chat over a period, per model
+    fn usage(&self, scope: UsageScope, period: UsagePeriod) -> StorageResult<Vec<UsageTotals>>;
+
+    /// Count an image generated for a user, unless they have generated `quota` images today
+    /// across all chats
+    ///
+    /// Checking and counting is one step, so requests at the same time can't both take the
+    /// last image. Returns whether the image was counted.
+    fn record_image_generation(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        quota: Option<u32>,
+    ) -> StorageResult<bool>;
+
+    /// Take back an image counted for a user that could not be generated
+    fn release_image_generation(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<()>;
+}
@@ -0,0 +1,788 @@
+//! SQLite storage backend
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{Connection, OptionalExtension, Row, TransactionBehavior, params};
+use teloxide::types::{Chat, ChatId, MessageId, ThreadId, UserId};
+
+use super::{
+    Storage, StorageError, StorageResult, UsagePeriod, UsageRecord, UsageScope, UsageTotals,
+};
+use crate::history::{ConversationKey, Turn};
+use crate::llm::Role;
+
+/// Schema migrations, applied in order; the schema version is tracked in `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    // 1: chats, messages, chat settings and daily usage counters
+    "
+    CREATE TABLE chats (
+        chat_id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        title TEXT,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE messages (
+        chat_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        thread_id INTEGER,
+        reply_to INTEGER,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (chat_id, message_id)
+    );
+    CREATE INDEX messages_conversation ON messages (chat_id, thread_id);
+    CREATE TABLE chat_settings (
+        chat_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (chat_id, key)
+    );
+    CREATE TABLE usage (
+        day TEXT NOT NULL,
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        model TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        PRIMARY KEY (day, chat_id, user_id, model)
+    );
+    ",
+    // 2: per-user settings
+    "
+    CREATE TABLE user_settings (
+        user_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (user_id, key)
+    );
+    ",
+    // 3: daily image generation counters
+    "
+    CREATE TABLE image_generations (
+        day TEXT NOT NULL,
+        chat_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        images INTEGER NOT NULL,
+        PRIMARY KEY (day, chat_id, user_id)
+    );
+    ",
+    // 4: the price of the tokens counted in usage
+    "
+    ALTER TABLE usage ADD COLUMN cost REAL NOT NULL DEFAULT 0;
+    CREATE INDEX usage_user ON usage (user_id, day);
+    ",
+];
+
+/// Storage backed by a local SQLite database file
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Open (or create) the database at `path` and bring its schema up to date
+    ///
+    /// The special path `:memory:` opens a throwaway in-memory database.
+    pub fn open(path: impl AsRef<Path>) -> StorageResult<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+/// Apply all migrations newer than the database's schema version
+fn migrate(conn: &mut Connection) -> StorageResult<()> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Applying storage migration {}", index + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index as i64 + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self(error.to_string())
+    }
+}
+
+/// The name of a role as stored in the database
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::User => "user",
+        Role::Assistant => "assistant",
+    }
+}
+
+/// Build a turn from a `messages` row selected with [`TURN_COLUMNS`]
+fn turn_from_row(row: &Row<'_>) -> rusqlite::Result<Turn> {
+    let role: String = row.get(0)?;
+    Ok(Turn {
+        role: if role == "assistant" {
+            Role::Assistant
+        } else {
+            Role::User
+        },
+        content: row.get(1)?,
+        message_id: MessageId(row.get(2)?),
+        reply_to: row.get::<_, Option<i32>>(3)?.map(MessageId),
+    })
+}
+
+/// The `messages` columns read by [`turn_from_row`]
+const TURN_COLUMNS: &str = "role, content, message_id, reply_to";
+
+impl Storage for SqliteStorage {
+    fn save_chat(&self, chat: &Chat) -> StorageResult<()> {
+        let kind = if chat.is_private() {
+            "private"
+        } else if chat.is_group() {
+            "group"
+        } else if chat.is_supergroup() {
+            "supergroup"
+        } else {
+            "channel"
+        };
+        let title = chat.title().or(chat.username()).or(chat.first_name());
+
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO chats (chat_id, kind, title, updated_at) VALUES (?1, ?2, ?3, unixepoch())
+             ON CONFLICT (chat_id) DO UPDATE SET
+                 kind = excluded.kind, title = excluded.title, updated_at = excluded.updated_at",
+            params![chat.id.0, kind, title],
+        )?;
+        Ok(())
+    }
+
+    fn save_message(&self, key: ConversationKey, turn: &Turn) -> StorageResult<()> {
+        // Updating in place keeps the rowid, so a message saved again keeps its place in the
+        // conversation.
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO messages
+                 (chat_id, message_id, thread_id, reply_to, role, content, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, unixepoch())
+             ON CONFLICT (chat_id, message_id) DO UPDATE SET
+                 thread_id = excluded.thread_id,
+                 reply_to = excluded.reply_to,
+                 role = excluded.role,
+                 content = excluded.content",
+            params![
+                key.chat_id.0,
+                turn.message_id.0,
+                key.thread_id.map(|ThreadId(id)| id.0),
+                turn.reply_to.map(|id| id.0),
+                role_name(turn.role),
+                turn.content,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn recent_messages(&self, key: ConversationKey, limit: usize) -> StorageResult<Vec<Turn>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {TURN_COLUMNS} FROM messages
+             WHERE chat_id = ?1 AND thread_id IS ?2
+             ORDER BY rowid DESC LIMIT ?3"
+        ))?;
+        let turns = stmt
+            .query_map(
+                params![
+                    key.chat_id.0,
+                    key.thread_id.map(|ThreadId(id)| id.0),
+                    limit as i64
+                ],
+                turn_from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(turns)
+    }
+
+    fn message(&self, chat_id: ChatId, message_id: MessageId) -> StorageResult<Option<Turn>> {
+        let conn = self.conn.lock().unwrap();
+        let turn = conn
+            .query_row(
+                &format!(
+                    "SELECT {TURN_COLUMNS} FROM messages WHERE chat_id = ?1 AND message_id = ?2"
+                ),
+                params![chat_id.0, message_id.0],
+                turn_from_row,
+            )
+            .optional()?;
+        Ok(turn)
+    }
+
+    fn answer(&self, chat_id: ChatId, prompt_id: MessageId) -> StorageResult<Option<Turn>> {
+        let conn = self.conn.lock().unwrap();
+        let turn = conn
+            .query_row(
+                &format!(
+                    "SELECT {TURN_COLUMNS} FROM messages
+                     WHERE chat_id = ?1 AND reply_to = ?2 AND role = 'assistant'
+                     ORDER BY rowid LIMIT 1"
+                ),
+                params![chat_id.0, prompt_id.0],
+                turn_from_row,
+            )
+            .optional()?;
+        Ok(turn)
+    }
+
+    fn replace_message(
+        &self,
+        chat_id: ChatId,
+        previous: MessageId,
+        turn: &Turn,
+    ) -> StorageResult<bool> {
+        // The row keeps its rowid, which orders the conversation.
+        let replaced = self.conn.lock().unwrap().execute(
+            "UPDATE messages SET message_id = ?3, reply_to = ?4, role = ?5, content = ?6
+             WHERE chat_id = ?1 AND message_id = ?2",
+            params![
+                chat_id.0,
+                previous.0,
+                turn.message_id.0,
+                turn.reply_to.map(|id| id.0),
+                role_name(turn.role),
+                turn.content,
+            ],
+        )?;
+        Ok(replaced > 0)
+    }
+
+    fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> StorageResult<bool> {
+        let deleted = self.conn.lock().unwrap().execute(
+            "DELETE FROM messages WHERE chat_id = ?1 AND message_id = ?2",
+            params![chat_id.0, message_id.0],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn delete_messages(&self, key: ConversationKey) -> StorageResult<usize> {
+        let deleted = self.conn.lock().unwrap().execute(
+            "DELETE FROM messages WHERE chat_id = ?1 AND thread_id IS ?2",
+            params![key.chat_id.0, key.thread_id.map(|ThreadId(id)| id.0)],
+        )?;
+        Ok(deleted)
+    }
+
+    fn search_messages(
+        &self,
+        chat_id: ChatId,
+        query: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<Turn>> {
+        // Match the query literally, not as a LIKE pattern
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {TURN_COLUMNS} FROM messages
+             WHERE chat_id = ?1 AND content LIKE ?2 ESCAPE '\\'
+             ORDER BY rowid DESC LIMIT ?3"
+        ))?;
+        let turns = stmt
+            .query_map(params![chat_id.0, pattern, limit as i64], turn_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(turns)
+    }
+
+    fn chat_setting(&self, chat_id: ChatId, key: &str) -> StorageResult<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let value = conn
+            .query_row(
+                "SELECT value FROM chat_settings WHERE chat_id = ?1 AND key = ?2",
+                params![chat_id.0, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn set_chat_setting(&self, chat_id: ChatId, key: &str, value: &str) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO chat_settings (chat_id, key, value) VALUES (?1, ?2, ?3)",
+            params![chat_id.0, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn delete_chat_setting(&self, chat_id: ChatId, key: &str) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM chat_settings WHERE chat_id = ?1 AND key = ?2",
+            params![chat_id.0, key],
+        )?;
+        Ok(())
+    }
+
+    fn user_setting(&self, user_id: UserId, key: &str) -> StorageResult<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let value = conn
+            .query_row(
+                "SELECT value FROM user_settings WHERE user_id = ?1 AND key = ?2",
+                params![user_id.0 as i64, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn set_user_setting(&self, user_id: UserId, key: &str, value: &str) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO user_settings (user_id, key, value) VALUES (?1, ?2, ?3)",
+            params![user_id.0 as i64, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn record_usage(&self, usage: &UsageRecord) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO usage
+                 (day, chat_id, user_id, model, requests, prompt_tokens, completion_tokens, cost)
+             VALUES (date('now'), ?1, ?2, ?3, 1, ?4, ?5, ?6)
+             ON CONFLICT (day, chat_id, user_id, model) DO UPDATE SET
+                 requests = requests + 1,
+                 prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                 completion_tokens = completion_tokens + excluded.completion_tokens,
+                 cost = cost + excluded.cost",
+            params![
+                usage.chat_id.0,
+                usage.user_id.map_or(0, |id| id.0 as i64),
+                usage.model,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.cost,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn usage(&self, scope: UsageScope, period: UsagePeriod) -> StorageResult<Vec<UsageTotals>> {
+        let (column, id) = match scope {
+            UsageScope::User(user_id) => ("user_id", user_id.0 as i64),
+            UsageScope::Chat(chat_id) => ("chat_id", chat_id.0),
+        };
+        // Days are stored as YYYY-MM-DD, so they compare in order as text.
+        let since = match period {
+            UsagePeriod::Today => "date('now')",
+            UsagePeriod::ThisMonth => "date('now', 'start of month')",
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT model, SUM(requests), SUM(prompt_tokens), SUM(completion_tokens), SUM(cost)
+             FROM usage WHERE {} = ?1 AND day >= {}
+             GROUP BY model ORDER BY SUM(cost) DESC, model",
+            column, since
+        ))?;
+        let totals = stmt
+            .query_map(params![id], |row| {
+                Ok(UsageTotals {
+                    model: row.get(0)?,
+                    requests: row.get::<_, i64>(1)? as u64,
+                    prompt_tokens: row.get::<_, i64>(2)? as u64,
+                    completion_tokens: row.get::<_, i64>(3)? as u64,
+                    cost: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(totals)
+    }
+
+    fn record_image_generation(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        quota: Option<u32>,
+    ) -> StorageResult<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if let Some(quota) = quota {
+            let images: u32 = tx.query_row(
+                "SELECT COALESCE(SUM(images), 0) FROM image_generations
+                 WHERE day = date('now') AND user_id = ?1",
+                params![user_id.0 as i64],
+                |row| row.get(0),
+            )?;
+            if images >= quota {
+                return Ok(false);
+            }
+        }
+        tx.execute(
+            "INSERT INTO image_generations (day, chat_id, user_id, images)
+             VALUES (date('now'), ?1, ?2, 1)
+             ON CONFLICT (day, chat_id, user_id) DO UPDATE SET images = images + 1",
+            params![chat_id.0, user_id.0 as i64],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn release_image_generation(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE image_generations SET images = images - 1
+             WHERE day = date('now') AND chat_id = ?1 AND user_id = ?2 AND images > 0",
+            params![chat_id.0, user_id.0 as i64],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT: ChatId = ChatId(-100);
+    const CONVERSATION: ConversationKey = ConversationKey {
+        chat_id: CHAT,
+        thread_id: None,
+    };
+
+    /// A storage backed by a fresh in-memory database
+    fn storage() -> SqliteStorage {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        SqliteStorage {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    fn turn(role: Role, message_id: i32, reply_to: Option<i32>, content: &str) -> Turn {
+        Turn {
+            role,
+            content: content.to_string(),
+            message_id: MessageId(message_id),
+            reply_to: reply_to.map(MessageId),
+        }
+    }
+
+    /// The message ids of the recent turns of the conversation, oldest first
+    fn recent_ids(storage: &SqliteStorage) -> Vec<i32> {
+        let mut turns = storage.recent_messages(CONVERSATION, 10).unwrap();
+        turns.reverse();
+        turns.iter().map(|turn| turn.message_id.0).collect()
+    }
+
+    #[test]
+    fn regenerated_answers_keep_their_place() {
+        let storage = storage();
+        for turn in [
+            turn(Role::User, 1, None, "Q1"),
+            turn(Role::Assistant, 2, Some(1), "A1"),
+            turn(Role::User, 3, None, "Q2"),
+            turn(Role::Assistant, 4, Some(3), "A2"),
+        ] {
+            storage.save_message(CONVERSATION, &turn).unwrap();
+        }
+
+        let answer = turn(Role::Assistant, 5, Some(1), "A1'");
+        assert!(
+            storage
+                .replace_message(CHAT, MessageId(2), &answer)
+                .unwrap()
+        );
+        assert_eq!(recent_ids(&storage), [1, 5, 3, 4]);
+        assert_eq!(
+            storage.answer(CHAT, MessageId(1)).unwrap().unwrap().content,
+            "A1'"
+        );
+        assert!(storage.message(CHAT, MessageId(2)).unwrap().is_none());
+    }
+
+    #[test]
+    fn replacing_an_unknown_message_changes_nothing() {
+        let storage = storage();
+        storage
+            .save_message(CONVERSATION, &turn(Role::User, 1, None, "Q1"))
+            .unwrap();
+        let answer = turn(Role::Assistant, 3, Some(1), "A1");
+        assert!(
+            !storage
+                .replace_message(CHAT, MessageId(2), &answer)
+                .unwrap()
+        );
+        assert_eq!(recent_ids(&storage), [1]);
+    }
+
+    fn usage_record(user: u64, model: &str, prompt_tokens: u32, cost: f64) -> UsageRecord {
+        UsageRecord {
+            chat_id: CHAT,
+            user_id: Some(UserId(user)),
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens: 10,
+            cost,
+        }
+    }
+
+    #[test]
+    fn migrates_to_the_latest_version() {
+        let storage = storage();
+        let mut conn = storage.conn.lock().unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        // Migrating again changes nothing.
+        migrate(&mut conn).unwrap();
+    }
+
+    #[test]
+    fn migrates_from_an_older_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO usage VALUES (date('now'), 1, 2, 'model', 1, 100, 10)",
+            [],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+        // Rows from before a column was added get its default.
+        let cost: f64 = conn
+            .query_row("SELECT cost FROM usage", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cost, 0.0);
+    }
+
+    #[test]
+    fn returns_recent_messages_newest_first() {
+        let storage = storage();
+        for id in 1..=5 {
+            storage
+                .save_message(CONVERSATION, &turn(Role::User, id, None, "Q"))
+                .unwrap();
+        }
+        let topic = ConversationKey {
+            chat_id: CHAT,
+            thread_id: Some(ThreadId(MessageId(7))),
+        };
+        storage
+            .save_message(topic, &turn(Role::User, 6, None, "In a topic"))
+            .unwrap();
+
+        let recent = storage.recent_messages(CONVERSATION, 3).unwrap();
+        let ids: Vec<i32> = recent.iter().map(|turn| turn.message_id.0).collect();
+        assert_eq!(ids, [5, 4, 3]);
+        let in_topic = storage.recent_messages(topic, 10).unwrap();
+        assert_eq!(in_topic.len(), 1);
+        assert_eq!(in_topic[0].content, "In a topic");
+    }
+
+    #[test]
+    fn updates_messages_saved_again_in_place() {
+        let storage = storage();
+        for turn in [
+            turn(Role::User, 1, None, "Q1"),
+            turn(Role::Assistant, 2, Some(1), "A1"),
+            turn(Role::User, 3, None, "Q2"),
+        ] {
+            storage.save_message(CONVERSATION, &turn).unwrap();
+        }
+        storage
+            .save_message(
+                CONVERSATION,
+                &turn(Role::Assistant, 2, Some(1), "A1, edited"),
+            )
+            .unwrap();
+
+        assert_eq!(recent_ids(&storage), [1, 2, 3]);
+        let answer = storage.message(CHAT, MessageId(2)).unwrap().unwrap();
+        assert_eq!(answer.content, "A1, edited");
+        assert_eq!(answer.role, Role::Assistant);
+        assert_eq!(answer.reply_to, Some(MessageId(1)));
+    }
+
+    #[test]
+    fn finds_the_answer_to_a_prompt() {
+        let storage = storage();
+        storage
+            .save_message(CONVERSATION, &turn(Role::User, 1, None, "Q1"))
+            .unwrap();
+        assert!(storage.answer(CHAT, MessageId(1)).unwrap().is_none());
+
+        storage
+            .save_message(CONVERSATION, &turn(Role::Assistant, 2, Some(1), "A1"))
+            .unwrap();
+        let answer = storage.answer(CHAT, MessageId(1)).unwrap().unwrap();
+        assert_eq!(answer.message_id, MessageId(2));
+        assert!(storage.message(ChatId(1), MessageId(2)).unwrap().is_none());
+    }
+
+    #[test]
+    fn deletes_messages() {
+        let storage = storage();
+        for id in 1..=3 {
+            storage
+                .save_message(CONVERSATION, &turn(Role::User, id, None, "Q"))
+                .unwrap();
+        }
+        assert!(storage.delete_message(CHAT, MessageId(2)).unwrap());
+        assert!(!storage.delete_message(CHAT, MessageId(2)).unwrap());
+        assert_eq!(recent_ids(&storage), [1, 3]);
+        assert_eq!(storage.delete_messages(CONVERSATION).unwrap(), 2);
+        assert!(recent_ids(&storage).is_empty());
+    }
+
+    #[test]
+    fn searches_messages_literally() {
+        let storage = storage();
+        for (id, content) in [
+            (1, "Up 100% today"),
+            (2, "Up 1000 today"),
+            (3, "snake_case"),
+        ] {
+            storage
+                .save_message(CONVERSATION, &turn(Role::User, id, None, content))
+                .unwrap();
+        }
+        let search = |query| -> Vec<i32> {
+            let turns = storage.search_messages(CHAT, query, 10).unwrap();
+            turns.iter().map(|turn| turn.message_id.0).collect()
+        };
+        assert_eq!(search("100%"), [1]);
+        assert_eq!(search("UP 100"), [2, 1]);
+        assert_eq!(search("e_c"), [3]);
+        assert!(search("a_e").is_empty());
+    }
+
+    #[test]
+    fn stores_settings() {
+        let storage = storage();
+        assert_eq!(storage.chat_setting(CHAT, "model").unwrap(), None);
+        storage.set_chat_setting(CHAT, "model", "a").unwrap();
+        storage.set_chat_setting(CHAT, "model", "b").unwrap();
+        assert_eq!(
+            storage.chat_setting(CHAT, "model").unwrap().as_deref(),
+            Some("b")
+        );
+        assert_eq!(storage.chat_setting(ChatId(1), "model").unwrap(), None);
+        storage.delete_chat_setting(CHAT, "model").unwrap();
+        assert_eq!(storage.chat_setting(CHAT, "model").unwrap(), None);
+
+        storage.set_user_setting(UserId(1), "model", "a").unwrap();
+        storage.set_user_setting(UserId(1), "model", "c").unwrap();
+        assert_eq!(
+            storage.user_setting(UserId(1), "model").unwrap().as_deref(),
+            Some("c")
+        );
+        assert_eq!(storage.user_setting(UserId(2), "model").unwrap(), None);
+    }
+
+    #[test]
+    fn adds_up_usage() {
+        let storage = storage();
+        storage
+            .record_usage(&usage_record(1, "small", 100, 0.5))
+            .unwrap();
+        storage
+            .record_usage(&usage_record(1, "small", 50, 0.25))
+            .unwrap();
+        storage
+            .record_usage(&usage_record(1, "large", 10, 1.0))
+            .unwrap();
+        storage
+            .record_usage(&usage_record(2, "small", 1, 0.125))
+            .unwrap();
+
+        let user = storage
+            .usage(UsageScope::User(UserId(1)), UsagePeriod::Today)
+            .unwrap();
+        let totals: Vec<_> = user
+            .iter()
+            .map(|totals| (totals.model.as_str(), totals.requests, totals.prompt_tokens))
+            .collect();
+        // The most expensive model comes first.
+        assert_eq!(totals, [("large", 1, 10), ("small", 2, 150)]);
+        assert_eq!(user[1].completion_tokens, 20);
+        assert_eq!(user[1].cost, 0.75);
+
+        let chat = storage
+            .usage(UsageScope::Chat(CHAT), UsagePeriod::ThisMonth)
+            .unwrap();
+        let requests: u64 = chat.iter().map(|totals| totals.requests).sum();
+        assert_eq!(requests, 4);
+    }
+
+    #[test]
+    fn counts_images_up_to_the_quota() {
+        let storage = storage();
+        let user = UserId(1);
+        assert!(
+            storage
+                .record_image_generation(CHAT, user, Some(2))
+                .unwrap()
+        );
+        // The quota counts images across chats.
+        assert!(
+            storage
+                .record_image_generation(ChatId(1), user, Some(2))
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .record_image_generation(CHAT, user, Some(2))
+                .unwrap()
+        );
+        assert!(
+            storage
+                .record_image_generation(CHAT, UserId(2), Some(2))
+                .unwrap()
+        );
+
+        // Images released can be generated again.
+        storage.release_image_generation(CHAT, user).unwrap();
+        assert!(
+            storage
+                .record_image_generation(CHAT, user, Some(2))
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .record_image_generation(CHAT, user, Some(2))
+                .unwrap()
+        );
+
+        // Without a quota there is no limit.
+        assert!(storage.record_image_generation(CHAT, user, None).unwrap());
+    }
+
+    #[test]
+    fn releases_no_images_never_counted() {
+        let storage = storage();
+        let user = UserId(1);
+        storage.release_image_generation(CHAT, user).unwrap();
+        assert!(
+            storage
+                .record_image_generation(CHAT, user, Some(1))
+                .unwrap()
+        );
+        assert!(
+            !storage
+                .record_image_generation(CHAT, user, Some(1))
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Progressive delivery of streamed answers
+//!
+//! A placeholder reply is sent as soon as a request starts and is then edited with the text
+//! received so far. Edits are throttled, since Telegram rate-limits how often a chat's
+//! messages can be changed. Once complete, the answer is rendered from Markdown and split
+//! over as many messages as it needs, or attached as a document when it is very long.
+//! Buttons can be shown under the reply while it streams in, and under the final answer.
+
+use teloxide::{
+    ApiError, RequestError,
+    prelude::*,
+    types::{InlineKeyboardMarkup, InputFile, MessageId, ParseMode, ReplyParameters},
+};
+use tokio::time::Instant;
+
+use crate::markdown::to_telegram_html;
+use crate::output::{MAX_MESSAGE_LEN, OutputConfig, split_message};
+
+/// The text shown before the first part of the answer arrives
+const PLACEHOLDER_TEXT: &str = "…";
+
+/// The file name of answers sent as a document
+const DOCUMENT_FILE_NAME: &str = "answer.md";
+
+/// A reply message that is edited as the answer streams in
+pub struct StreamingReply {
+    bot: Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    /// The message being answered
+    reply_to: MessageId,
+    /// How the complete answer is delivered
+    output: OutputConfig,
+    /// The answer received so far
+    text: String,
+    /// The text currently displayed in the message
+    shown: String,
+    /// The earliest time the next edit may be made
+    next_edit: Instant,
+    /// The buttons shown while the answer streams in
+    buttons: Option<InlineKeyboardMarkup>,
+}
+
+impl StreamingReply {
+    /// Send the placeholder reply to `msg`, with the buttons to show while streaming
+    pub async fn start(
+        bot: &Bot,
+        msg: &Message,
+        output: OutputConfig,
+        buttons: Option<InlineKeyboardMarkup>,
+    ) -> ResponseResult<Self> {
+        let mut request = bot
+            .send_message(msg.chat.id, PLACEHOLDER_TEXT)
+            .reply_parameters(ReplyParameters::new(msg.id));
+        if let Some(buttons) = &buttons {
+            request = request.reply_markup(buttons.clone());
+        }
+        let placeholder = request.await?;
+
+        Ok(Self {
+            bot: bot.clone(),
+            chat_id: msg.chat.id,
+            message_id: placeholder.id,
+            reply_to: msg.id,
+            output,
+            text: String::new(),
+            shown: PLACEHOLDER_TEXT.to_string(),
+            next_edit: Instant::now() + output.edit_interval,
+            buttons,
+        })
+    }
+
+    /// The reply the answer streams into
+    pub fn message_id(&self) -> MessageId {
+        self.message_id
+    }
+
+    /// The answer received so far
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Append a piece of the answer, updating the message if the last edit is long enough ago
+    pub async fn push(&mut self, delta: &str) {
+        self.text.push_str(delta);
+        if Instant::now() >= self.next_edit {
+            self.show(preview(&self.text)).await;
+        }
+    }
+
+    /// Deliver the final text, regardless of the edit throttle, with buttons under its last
+    /// message
+    ///
+    /// Returns the id of the last message the text was delivered in.
+    pub async fn finish(
+        &mut self,
+        text: &str,
+        buttons: Option<InlineKeyboardMarkup>,
+    ) -> ResponseResult<MessageId> {
+        if self.output.wants_document(text) {
+            return self.finish_as_document(text, buttons).await;
+        }
+
+        // The first part goes into the placeholder, the rest into follow-up replies.
+        let mut parts = split_message(text, MAX_MESSAGE_LEN).into_iter().peekable();
+        let first = parts.next().unwrap_or_default();
+        let first_buttons = if parts.peek().is_none() {
+            buttons.as_ref()
+        } else {
+            None
+        };
+        self.edit_formatted(&first, first_buttons).await?;
+        self.shown = first;
+
+        let mut last_id = self.message_id;
+        while let Some(part) = parts.next() {
+            let part_buttons = if parts.peek().is_none() {
+                buttons.as_ref()
+            } else {
+                None
+            };
+            last_id = self.send_formatted(&part, part_buttons).await?;
+        }
+        Ok(last_id)
+    }
+
+    /// Edit the reply to show a Markdown text, falling back to plain text if it is rejected
+    ///
+    /// Buttons shown while streaming are replaced by the given ones, or removed.
+    async fn edit_formatted(
+        &self,
+        text: &str,
+        buttons: Option<&InlineKeyboardMarkup>,
+    ) -> ResponseResult<()> {
+        let mut request = self
+            .bot
+            .edit_message_text(self.chat_id, self.message_id, to_telegram_html(text))
+            .parse_mode(ParseMode::Html);
+        if let Some(buttons) = buttons {
+            request = request.reply_markup(buttons.clone());
+        }
+        let result = match request.await {
+            Err(RequestError::Api(ApiError::CantParseEntities(error))) => {
+                log::warn!("Telegram rejected the formatted reply: {}", error);
+                let mut request = self
+                    .bot
+                    .edit_message_text(self.chat_id, self.message_id, text);
+                if let Some(buttons) = buttons {
+                    request = request.reply_markup(buttons.clone());
+                }
+                request.await
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Send a Markdown text as a follow-up reply, falling back to plain text if it is rejected
+    async fn send_formatted(
+        &self,
+        text: &str,
+        buttons: Option<&InlineKeyboardMarkup>,
+    ) -> ResponseResult<MessageId> {
+        let mut request = self
+            .bot
+            .send_message(self.chat_id, to_telegram_html(text))
+            .parse_mode(ParseMode::Html)
+            .reply_parameters(ReplyParameters::new(self.reply_to));
+        if let Some(buttons) = buttons {
+            request = request.reply_markup(buttons.clone());
+        }
+        let sent = match request.await {
+            Err(RequestError::Api(ApiError::CantParseEntities(error))) => {
+                log::warn!("Telegram rejected the formatted reply: {}", error);
+                let mut request = self
+                    .bot
+                    .send_message(self.chat_id, text)
+                    .reply_parameters(ReplyParameters::new(self.reply_to));
+                if let Some(buttons) = buttons {
+                    request = request.reply_markup(buttons.clone());
+                }
+                request.await?
+            }
+            result => result?,
+        };
+        Ok(sent.id)
+    }
+
+    /// Replace the placeholder with a Markdown document holding the text
+    async fn finish_as_document(
+        &mut self,
+        text: &str,
+        buttons: Option<InlineKeyboardMarkup>,
+    ) -> ResponseResult<MessageId> {
+        let document = InputFile::memory(text.to_string()).file_name(DOCUMENT_FILE_NAME);
+        let mut request = self
+            .bot
+            .send_document(self.chat_id, document)
+            .caption("The answer is too long for a message, so here it is as a file.")
+            .reply_parameters(ReplyParameters::new(self.reply_to));
+        if let Some(buttons) = buttons {
+            request = request.reply_markup(buttons);
+        }
+        let sent = request.await?;
+
+        if let Err(error) = self.bot.delete_message(self.chat_id, self.message_id).await {
+            log::warn!("Failed to delete the placeholder reply: {}", error);
+        }
+        Ok(sent.id)
+    }
+
+    /// Edit the message to show `text`, logging rather than failing on errors
+    async fn show(&mut self, text: String) {
+        if text.trim().is_empty() || text == self.shown {
+            return;
+        }
+
+        self.next_edit = Instant::now() + self.output.edit_interval;
+        let mut request = self
+            .bot
+            .edit_message_text(self.chat_id, self.message_id, &text);
+        if let Some(buttons) = &self.buttons {
+            request = request.reply_markup(buttons.clone());
+        }
+        match request.await {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => self.shown = text,
+            Err(RequestError::RetryAfter(delay)) => {
+                // Back off for as long as Telegram asks before the next progress update.
+                log::warn!("Message edits are rate limited for {:?}", delay.duration());
+                self.next_edit = Instant::now() + delay.duration();
+            }
+            Err(error) => log::warn!("Failed to update streamed reply: {}", error),
+        }
+    }
+}
+
+/// Cut an in-progress answer down to the part that goes into the first message
+fn preview(text: &str) -> String {
+    let mut parts = split_message(text, MAX_MESSAGE_LEN - 1).into_iter();
+    let first = parts.next().unwrap_or_default();
+    if parts.next().is_some() {
+        format!("{first}…")
+    } else {
+        first
+    }
+}
@@ -0,0 +1,99 @@
+//! System prompts
+//!
+//! Every request can start with a system prompt giving the bot its role and house rules.
+//! `SYSTEM_PROMPT` sets the default for all chats, which a chat can replace with its own
+//! through `/system`. Prompts are templates: `{chat_title}`, `{user_name}`, `{bot_name}` and
+//! `{date}` are filled in for every request.
+
+use std::env;
+
+use teloxide::types::{ChatId, Me, Message};
+
+use crate::storage::{Storage, StorageResult};
+
+/// The chat setting holding a chat's own system prompt
+const SETTING_KEY: &str = "system_prompt";
+
+/// The template variables, as listed to users
+pub const TEMPLATE_VARIABLES: &str = "{chat_title}, {user_name}, {bot_name}, {date}";
+
+/// Where the system prompt of a chat comes from
+#[derive(Clone, Debug)]
+pub struct SystemPrompts {
+    /// The prompt of chats without their own, if any
+    default: Option<String>,
+}
+
+impl SystemPrompts {
+    /// Read the default system prompt from `SYSTEM_PROMPT`
+    pub fn from_env() -> Self {
+        Self {
+            default: env::var("SYSTEM_PROMPT")
+                .ok()
+                .filter(|prompt| !prompt.trim().is_empty()),
+        }
+    }
+
+    /// The default system prompt template
+    pub fn default_template(&self) -> Option<&str> {
+        self.default.as_deref()
+    }
+
+    /// Get the system prompt template of a chat, falling back to the default
+    pub fn template(&self, storage: &dyn Storage, chat_id: ChatId) -> Option<String> {
+        match chat_template(storage, chat_id) {
+            Ok(Some(template)) => Some(template),
+            Ok(None) => self.default.clone(),
+            Err(error) => {
+                log::error!("Failed to load system prompt: {}", error);
+                self.default.clone()
+            }
+        }
+    }
+
+    /// Get the rendered system prompt for a message
+    pub fn for_message(&self, storage: &dyn Storage, msg: &Message, me: &Me) -> Option<String> {
+        self.template(storage, msg.chat.id)
+            .map(|template| render(&template, msg, me))
+    }
+}
+
+/// Get the system prompt template a chat has set for itself
+pub fn chat_template(storage: &dyn Storage, chat_id: ChatId) -> StorageResult<Option<String>> {
+    storage.chat_setting(chat_id, SETTING_KEY)
+}
+
+/// Set the system prompt template of a chat
+pub fn set_chat_template(
+    storage: &dyn Storage,
+    chat_id: ChatId,
+    template: &str,
+) -> StorageResult<()> {
+    storage.set_chat_setting(chat_id, SETTING_KEY, template)
+}
+
+/// Remove the system prompt template of a chat, reverting to the default
+pub fn clear_chat_template(storage: &dyn Storage, chat_id: ChatId) -> StorageResult<()> {
+    storage.delete_chat_setting(chat_id, SETTING_KEY)
+}
+
+/// Fill in the template variables for a message
+fn render(template: &str, msg: &Message, me: &Me) -> String {
+    let chat_title = msg
+        .chat
+        .title()
+        .or(msg.chat.first_name())
+        .unwrap_or_default();
+    let user_name = msg
+        .from
+        .as_ref()
+        .map(|user| user.full_name())
+        .unwrap_or_default();
+    let date = chrono::Local::now().format("%A, %Y-%m-%d").to_string();
+
+    template
+        .replace("{chat_title}", chat_title)
+        .replace("{user_name}", &user_name)
+        .replace("{bot_name}", &me.user.full_name())
+        .replace("{date}", &date)
+}
@@ -0,0 +1,377 @@
+//! Evaluating arithmetic expressions
+//!
+//! Models are unreliable at arithmetic, so they can hand expressions to a small parser
+//! instead. It knows `+ - * / % ^`, parentheses, the constants `pi` and `e` and the usual
+//! functions of one argument.
+
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+use super::{Tool, ToolContext, string_argument};
+use crate::llm::ToolSpec;
+
+/// Evaluates arithmetic expressions
+pub struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "calculator".to_string(),
+            description: "Evaluate an arithmetic expression. Supports + - * / % ^, \
+                          parentheses, pi, e and the functions sqrt, cbrt, abs, exp, ln, log \
+                          (base 10), log2, sin, cos, tan, asin, acos, atan (radians), round, \
+                          floor and ceil."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "expression": {
+                        "type": "string",
+                        "description": "The expression, such as (2 + 3) * sqrt(16) / 7",
+                    },
+                },
+                "required": ["expression"],
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> Result<String, String> {
+        let expression = string_argument(&arguments, "expression")?;
+        let value = evaluate(expression)?;
+        if !value.is_finite() {
+            return Err(format!(
+                "the result of {} is not a finite number",
+                expression
+            ));
+        }
+        Ok(format_number(value))
+    }
+}
+
+/// Evaluate an expression
+fn evaluate(expression: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+    };
+    let value = parser.expression()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(token) => Err(format!("unexpected {}", token)),
+    }
+}
+
+/// Show a number without a fractional part as an integer
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+/// A token of an expression
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "number {}", number),
+            Self::Name(name) => write!(f, "name `{}`", name),
+            Self::Operator(operator) => write!(f, "operator `{}`", operator),
+            Self::Open => write!(f, "`(`"),
+            Self::Close => write!(f, "`)`"),
+        }
+    }
+}
+
+/// Split an expression into tokens
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '0'..='9' | '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    let exponent_sign = (c == '+' || c == '-') && number.ends_with(['e', 'E']);
+                    if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let value = number
+                    .parse()
+                    .map_err(|_| format!("invalid number `{}`", number))?;
+                tokens.push(Token::Number(value));
+            }
+            c if c.is_alphabetic() => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Name(name.to_ascii_lowercase()));
+            }
+            '*' => {
+                chars.next();
+                // `**` is another way of writing powers
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    tokens.push(Token::Operator('^'));
+                } else {
+                    tokens.push(Token::Operator('*'));
+                }
+            }
+            '+' | '-' | '/' | '%' | '^' => {
+                chars.next();
+                tokens.push(Token::Operator(c));
+            }
+            '×' | '·' => {
+                chars.next();
+                tokens.push(Token::Operator('*'));
+            }
+            '÷' => {
+                chars.next();
+                tokens.push(Token::Operator('/'));
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            _ => return Err(format!("unexpected character `{}`", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser evaluating as it goes
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Check for an operator, consuming it if present
+    fn eat_operator(&mut self, operators: &[char]) -> Option<char> {
+        match self.peek() {
+            Some(Token::Operator(operator)) if operators.contains(operator) => {
+                let operator = *operator;
+                self.position += 1;
+                Some(operator)
+            }
+            _ => None,
+        }
+    }
+
+    /// expression = term (("+" | "-") term)*
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        while let Some(operator) = self.eat_operator(&['+', '-']) {
+            let right = self.term()?;
+            value = if operator == '+' {
+                value + right
+            } else {
+                value - right
+            };
+        }
+        Ok(value)
+    }
+
+    /// term = unary (("*" | "/" | "%") unary)*
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.eat_operator(&['*', '/', '%']) {
+            let right = self.unary()?;
+            value = match operator {
+                '*' => value * right,
+                _ if right == 0.0 => return Err("division by zero".to_string()),
+                '/' => value / right,
+                _ => value % right,
+            };
+        }
+        Ok(value)
+    }
+
+    /// unary = ("+" | "-") unary | power
+    fn unary(&mut self) -> Result<f64, String> {
+        match self.eat_operator(&['+', '-']) {
+            Some('-') => Ok(-self.unary()?),
+            Some(_) => self.unary(),
+            None => self.power(),
+        }
+    }
+
+    /// power = primary ("^" unary)?, binding to the right
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.primary()?;
+        if self.eat_operator(&['^']).is_some() {
+            let exponent = self.unary()?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    /// primary = number | constant | function "(" expression ")" | "(" expression ")"
+    fn primary(&mut self) -> Result<f64, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Open) => {
+                let value = self.expression()?;
+                self.close()?;
+                Ok(value)
+            }
+            Some(Token::Name(name)) => match name.as_str() {
+                "pi" | "π" => Ok(std::f64::consts::PI),
+                "e" => Ok(std::f64::consts::E),
+                _ => {
+                    let function = function(&name)?;
+                    if self.next() != Some(Token::Open) {
+                        return Err(format!("`{}` must be followed by `(`", name));
+                    }
+                    let argument = self.expression()?;
+                    self.close()?;
+                    Ok(function(argument))
+                }
+            },
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("unexpected end of the expression".to_string()),
+        }
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => Err("missing `)`".to_string()),
+        }
+    }
+}
+
+/// Look up a function of one argument
+fn function(name: &str) -> Result<fn(f64) -> f64, String> {
+    Ok(match name {
+        "sqrt" => f64::sqrt,
+        "cbrt" => f64::cbrt,
+        "abs" => f64::abs,
+        "exp" => f64::exp,
+        "ln" => f64::ln,
+        "log" | "log10" => f64::log10,
+        "log2" => f64::log2,
+        "sin" => f64::sin,
+        "cos" => f64::cos,
+        "tan" => f64::tan,
+        "asin" => f64::asin,
+        "acos" => f64::acos,
+        "atan" => f64::atan,
+        "round" => f64::round,
+        "floor" => f64::floor,
+        "ceil" => f64::ceil,
+        _ => return Err(format!("unknown function or constant `{}`", name)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respects_operator_precedence() {
+        assert_eq!(evaluate("2 + 3 * 4"), Ok(14.0));
+        assert_eq!(evaluate("(2 + 3) * 4"), Ok(20.0));
+        assert_eq!(evaluate("2 * 3 ^ 2"), Ok(18.0));
+        assert_eq!(evaluate("7 % 4 + 1"), Ok(4.0));
+    }
+
+    #[test]
+    fn evaluates_left_to_right() {
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("24 / 4 / 2"), Ok(3.0));
+    }
+
+    #[test]
+    fn raises_to_powers_from_the_right() {
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("2 ** 3 ** 2"), Ok(512.0));
+        assert_eq!(evaluate("(2 ^ 3) ^ 2"), Ok(64.0));
+    }
+
+    #[test]
+    fn applies_unary_signs() {
+        assert_eq!(evaluate("-3 + 5"), Ok(2.0));
+        assert_eq!(evaluate("3 - -2"), Ok(5.0));
+        assert_eq!(evaluate("--3"), Ok(3.0));
+        assert_eq!(evaluate("+4"), Ok(4.0));
+        // The sign applies to the power, as in mathematical notation
+        assert_eq!(evaluate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(evaluate("2 ^ -1"), Ok(0.5));
+    }
+
+    #[test]
+    fn knows_numbers_constants_and_functions() {
+        assert_eq!(evaluate("1.5e3 + 2E-1"), Ok(1500.2));
+        assert_eq!(evaluate("sqrt(16) + abs(-2)"), Ok(6.0));
+        assert_eq!(evaluate("2 × 3 ÷ 4"), Ok(1.5));
+        assert_eq!(evaluate("PI"), Ok(std::f64::consts::PI));
+        assert_eq!(evaluate("ln(e)"), Ok(1.0));
+        assert_eq!(evaluate("log(1000)"), Ok(3.0));
+    }
+
+    #[test]
+    fn rejects_division_by_zero() {
+        assert_eq!(evaluate("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(evaluate("5 % (2 - 2)"), Err("division by zero".to_string()));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        let error = |expression| evaluate(expression).unwrap_err();
+        assert_eq!(error(""), "unexpected end of the expression");
+        assert_eq!(error("2 +"), "unexpected end of the expression");
+        assert_eq!(error("(1 + 2"), "missing `)`");
+        assert_eq!(error("1 2"), "unexpected number 2");
+        assert_eq!(error("2 * )"), "unexpected `)`");
+        assert_eq!(error("1.2.3"), "invalid number `1.2.3`");
+        assert_eq!(error("2 $ 3"), "unexpected character `$`");
+        assert_eq!(error("sqrt 4"), "`sqrt` must be followed by `(`");
+        assert_eq!(error("foo(1)"), "unknown function or constant `foo`");
+    }
+
+    #[test]
+    fn shows_whole_numbers_without_a_fraction() {
+        assert_eq!(format_number(4.0), "4");
+        assert_eq!(format_number(-12.0), "-12");
+        assert_eq!(format_number(0.5), "0.5");
+        assert_eq!(format_number(1e20), "100000000000000000000");
+    }
+}
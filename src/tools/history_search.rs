@@ -0,0 +1,78 @@
+//! Searching the chat's stored history
+
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+use super::{Tool, ToolContext, string_argument};
+use crate::llm::{Role, ToolSpec};
+
+/// The most messages a search returns
+const MAX_RESULTS: u64 = 20;
+
+/// The longest excerpt of a message shown in the results, in characters
+const MAX_EXCERPT_LEN: usize = 500;
+
+/// Finds earlier messages of the current chat containing some text
+pub struct HistorySearch;
+
+#[async_trait]
+impl Tool for HistorySearch {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "search_history".to_string(),
+            description: "Search the earlier messages of this chat for a word or phrase. \
+                          Returns the most recent matches first."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "The text to look for, case-insensitive",
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "The most messages to return, 5 if omitted",
+                    },
+                },
+                "required": ["query"],
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value, context: &ToolContext) -> Result<String, String> {
+        let query = string_argument(&arguments, "query")?.trim();
+        if query.is_empty() {
+            return Err("the query is empty".to_string());
+        }
+        let limit = arguments
+            .get("limit")
+            .and_then(Value::as_u64)
+            .unwrap_or(5)
+            .clamp(1, MAX_RESULTS);
+
+        let turns = context
+            .storage
+            .search_messages(context.chat_id, query, limit as usize)
+            .map_err(|error| error.to_string())?;
+        if turns.is_empty() {
+            return Ok(format!("No messages contain \"{}\".", query));
+        }
+
+        let results: Vec<String> = turns
+            .into_iter()
+            .map(|turn| {
+                let author = match turn.role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                };
+                let mut excerpt: String = turn.content.chars().take(MAX_EXCERPT_LEN).collect();
+                if excerpt.len() < turn.content.len() {
+                    excerpt.push('…');
+                }
+                format!("[{}] {}", author, excerpt)
+            })
+            .collect();
+        Ok(results.join("\n\n"))
+    }
+}
@@ -0,0 +1,161 @@
+//! Tools the model can call
+//!
+//! Models that support function calling get a set of tools with each request. When the
+//! answer asks for tool calls, they are run (in parallel when there are several), their
+//! results are sent back, and the model is asked again, for at most `LLM_MAX_TOOL_ROUNDS`
+//! rounds. Built in are the current time, a calculator, unit conversion and a search of
+//! the chat's stored history; `TOOLS` selects which of them are offered. Chats can add the
+//! tools of MCP servers, see [`crate::mcp`].
+
+mod calculator;
+mod history_search;
+mod time;
+mod units;
+
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use teloxide::types::ChatId;
+
+use crate::llm::{ToolCall, ToolResult, ToolSpec};
+use crate::storage::Storage;
+
+pub use calculator::Calculator;
+pub use history_search::HistorySearch;
+pub use time::CurrentTime;
+pub use units::UnitConversion;
+
+/// How long a single tool call may take
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a tool call may access besides its arguments
+#[derive(Clone, Debug)]
+pub struct ToolContext {
+    /// The chat the request comes from
+    pub chat_id: ChatId,
+    /// The bot's persistent storage
+    pub storage: Arc<dyn Storage>,
+}
+
+/// A tool the model can call
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// The name, description and argument schema of the tool
+    fn spec(&self) -> ToolSpec;
+
+    /// Run the tool, returning its output or a description of what went wrong
+    async fn call(&self, arguments: Value, context: &ToolContext) -> Result<String, String>;
+}
+
+/// The tools offered to the model
+#[derive(Clone)]
+pub struct Tools {
+    tools: Vec<Arc<dyn Tool>>,
+    /// The most rounds of tool calls answering a single message may take
+    pub max_rounds: usize,
+}
+
+impl Tools {
+    /// Set up the built-in tools named in `TOOLS` (all by default, `none` for none), with
+    /// `LLM_MAX_TOOL_ROUNDS` rounds of calls
+    pub fn from_env() -> Self {
+        let names = env::var("TOOLS")
+            .unwrap_or_else(|_| "time,calculator,convert_units,search_history".to_string());
+        let tools = names
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty() && name != "none")
+            .map(|name| -> Arc<dyn Tool> {
+                match name.as_str() {
+                    "time" => Arc::new(CurrentTime),
+                    "calculator" => Arc::new(Calculator),
+                    "convert_units" => Arc::new(UnitConversion),
+                    "search_history" => Arc::new(HistorySearch),
+                    _ => panic!(
+                        "Unknown tool `{}` in TOOLS, expected time, calculator, \
+                         convert_units or search_history",
+                        name
+                    ),
+                }
+            })
+            .collect();
+
+        Self {
+            tools,
+            max_rounds: env::var("LLM_MAX_TOOL_ROUNDS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(5),
+        }
+    }
+
+    /// Check if there are no tools to offer
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty() || self.max_rounds == 0
+    }
+
+    /// These tools and some more, such as those of MCP servers
+    pub fn with(&self, extra: Vec<Arc<dyn Tool>>) -> Self {
+        let mut tools = self.clone();
+        tools.tools.extend(extra);
+        tools
+    }
+
+    /// The specs of all tools, as sent to the model
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools.iter().map(|tool| tool.spec()).collect()
+    }
+
+    /// Run the calls of one answer in parallel, returning their results in the same order
+    pub async fn call_all(&self, calls: &[ToolCall], context: &ToolContext) -> Vec<ToolResult> {
+        let results = calls.iter().map(|call| self.call(call, context));
+        futures::future::join_all(results).await
+    }
+
+    /// Run a single call
+    async fn call(&self, call: &ToolCall, context: &ToolContext) -> ToolResult {
+        let tool = self.tools.iter().find(|tool| tool.spec().name == call.name);
+        let output = match tool {
+            Some(tool) => {
+                log::info!("Calling tool {} with {}", call.name, call.arguments);
+                tokio::time::timeout(CALL_TIMEOUT, tool.call(call.arguments.clone(), context))
+                    .await
+                    .unwrap_or_else(|_| Err("the tool took too long".to_string()))
+            }
+            None => Err(format!("there is no tool called {}", call.name)),
+        };
+        if let Err(error) = &output {
+            log::warn!("Tool {} failed: {}", call.name, error);
+        }
+
+        let is_error = output.is_err();
+        ToolResult {
+            call_id: call.id.clone(),
+            name: call.name.clone(),
+            content: output.unwrap_or_else(|error| format!("Error: {}", error)),
+            is_error,
+        }
+    }
+}
+
+impl fmt::Debug for Tools {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.tools.iter().map(|tool| tool.spec().name).collect();
+        f.debug_struct("Tools")
+            .field("tools", &names)
+            .field("max_rounds", &self.max_rounds)
+            .finish()
+    }
+}
+
+/// Read a required string argument
+pub fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, String> {
+    arguments
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("the argument `{}` is missing or not a string", name))
+}
@@ -0,0 +1,51 @@
+//! The current date and time
+
+use async_trait::async_trait;
+use chrono::Utc;
+use chrono_tz::Tz;
+use serde_json::{Value, json};
+
+use super::{Tool, ToolContext};
+use crate::llm::ToolSpec;
+
+/// Tells the current date and time, in the server's or a given time zone
+pub struct CurrentTime;
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "current_time".to_string(),
+            description: "Get the current date and time, optionally in a given time zone."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "timezone": {
+                        "type": "string",
+                        "description": "IANA time zone such as Europe/Berlin or America/New_York; \
+                                        the server's time zone if omitted",
+                    },
+                },
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> Result<String, String> {
+        const FORMAT: &str = "%A, %Y-%m-%d %H:%M:%S %Z (UTC%:z)";
+
+        match arguments.get("timezone").and_then(Value::as_str) {
+            Some(name) if !name.trim().is_empty() => {
+                let timezone: Tz = name
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("unknown time zone `{}`", name))?;
+                Ok(Utc::now()
+                    .with_timezone(&timezone)
+                    .format(FORMAT)
+                    .to_string())
+            }
+            _ => Ok(chrono::Local::now().format(FORMAT).to_string()),
+        }
+    }
+}
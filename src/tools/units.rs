@@ -0,0 +1,405 @@
+//! Converting between units of measurement
+
+use async_trait::async_trait;
+use serde_json::{Value, json};
+
+use super::{Tool, ToolContext, string_argument};
+use crate::llm::ToolSpec;
+
+/// What a unit measures; only units of the same dimension convert into each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dimension {
+    Length,
+    Mass,
+    Volume,
+    Area,
+    Time,
+    Speed,
+    Data,
+    Temperature,
+}
+
+/// The units, with their names and their size in the dimension's base unit
+///
+/// Temperatures are converted separately, since their scales do not share a zero.
+const UNITS: &[(Dimension, &[&str], f64)] = &[
+    (
+        Dimension::Length,
+        &["m", "meter", "meters", "metre", "metres"],
+        1.0,
+    ),
+    (
+        Dimension::Length,
+        &["km", "kilometer", "kilometers", "kilometre", "kilometres"],
+        1000.0,
+    ),
+    (
+        Dimension::Length,
+        &[
+            "cm",
+            "centimeter",
+            "centimeters",
+            "centimetre",
+            "centimetres",
+        ],
+        0.01,
+    ),
+    (
+        Dimension::Length,
+        &[
+            "mm",
+            "millimeter",
+            "millimeters",
+            "millimetre",
+            "millimetres",
+        ],
+        0.001,
+    ),
+    (Dimension::Length, &["mi", "mile", "miles"], 1609.344),
+    (Dimension::Length, &["yd", "yard", "yards"], 0.9144),
+    (Dimension::Length, &["ft", "foot", "feet"], 0.3048),
+    (Dimension::Length, &["in", "inch", "inches"], 0.0254),
+    (
+        Dimension::Length,
+        &["nmi", "nautical mile", "nautical miles"],
+        1852.0,
+    ),
+    (Dimension::Mass, &["kg", "kilogram", "kilograms"], 1.0),
+    (Dimension::Mass, &["g", "gram", "grams"], 0.001),
+    (
+        Dimension::Mass,
+        &["mg", "milligram", "milligrams"],
+        0.000_001,
+    ),
+    (
+        Dimension::Mass,
+        &["t", "tonne", "tonnes", "metric ton", "metric tons"],
+        1000.0,
+    ),
+    (
+        Dimension::Mass,
+        &["lb", "lbs", "pound", "pounds"],
+        0.453_592_37,
+    ),
+    (
+        Dimension::Mass,
+        &["oz", "ounce", "ounces"],
+        0.028_349_523_125,
+    ),
+    (Dimension::Mass, &["st", "stone", "stones"], 6.350_293_18),
+    (
+        Dimension::Volume,
+        &["l", "liter", "liters", "litre", "litres"],
+        1.0,
+    ),
+    (
+        Dimension::Volume,
+        &[
+            "ml",
+            "milliliter",
+            "milliliters",
+            "millilitre",
+            "millilitres",
+        ],
+        0.001,
+    ),
+    (
+        Dimension::Volume,
+        &["m3", "m³", "cubic meter", "cubic meters"],
+        1000.0,
+    ),
+    (
+        Dimension::Volume,
+        &["gal", "gallon", "gallons"],
+        3.785_411_784,
+    ),
+    (Dimension::Volume, &["qt", "quart", "quarts"], 0.946_352_946),
+    (Dimension::Volume, &["pt", "pint", "pints"], 0.473_176_473),
+    (Dimension::Volume, &["cup", "cups"], 0.236_588_236_5),
+    (
+        Dimension::Volume,
+        &["fl oz", "fluid ounce", "fluid ounces"],
+        0.029_573_529_562_5,
+    ),
+    (
+        Dimension::Area,
+        &["m2", "m²", "square meter", "square meters"],
+        1.0,
+    ),
+    (
+        Dimension::Area,
+        &["km2", "km²", "square kilometer", "square kilometers"],
+        1_000_000.0,
+    ),
+    (Dimension::Area, &["ha", "hectare", "hectares"], 10_000.0),
+    (Dimension::Area, &["acre", "acres"], 4_046.856_422_4),
+    (
+        Dimension::Area,
+        &["ft2", "ft²", "square foot", "square feet"],
+        0.092_903_04,
+    ),
+    (
+        Dimension::Area,
+        &["mi2", "mi²", "square mile", "square miles"],
+        2_589_988.110_336,
+    ),
+    (Dimension::Time, &["s", "sec", "second", "seconds"], 1.0),
+    (
+        Dimension::Time,
+        &["ms", "millisecond", "milliseconds"],
+        0.001,
+    ),
+    (Dimension::Time, &["min", "minute", "minutes"], 60.0),
+    (Dimension::Time, &["h", "hr", "hour", "hours"], 3600.0),
+    (Dimension::Time, &["d", "day", "days"], 86_400.0),
+    (Dimension::Time, &["wk", "week", "weeks"], 604_800.0),
+    (Dimension::Time, &["yr", "year", "years"], 31_557_600.0),
+    (Dimension::Speed, &["m/s", "meters per second"], 1.0),
+    (
+        Dimension::Speed,
+        &["km/h", "kmh", "kph", "kilometers per hour"],
+        1.0 / 3.6,
+    ),
+    (Dimension::Speed, &["mph", "miles per hour"], 0.447_04),
+    (
+        Dimension::Speed,
+        &["kn", "kt", "knot", "knots"],
+        1852.0 / 3600.0,
+    ),
+    (Dimension::Data, &["byte", "bytes"], 1.0),
+    (Dimension::Data, &["kilobyte", "kilobytes"], 1e3),
+    (Dimension::Data, &["megabyte", "megabytes"], 1e6),
+    (Dimension::Data, &["gigabyte", "gigabytes"], 1e9),
+    (Dimension::Data, &["terabyte", "terabytes"], 1e12),
+    (Dimension::Data, &["kibibyte", "kibibytes"], 1024.0),
+    (Dimension::Data, &["mebibyte", "mebibytes"], 1_048_576.0),
+    (Dimension::Data, &["gibibyte", "gibibytes"], 1_073_741_824.0),
+    (
+        Dimension::Data,
+        &["tebibyte", "tebibytes"],
+        1_099_511_627_776.0,
+    ),
+    (Dimension::Data, &["bit", "bits"], 0.125),
+    (Dimension::Data, &["kilobit", "kilobits"], 125.0),
+    (Dimension::Data, &["megabit", "megabits"], 125_000.0),
+    (Dimension::Data, &["gigabit", "gigabits"], 1.25e8),
+    (Dimension::Data, &["terabit", "terabits"], 1.25e11),
+    (Dimension::Temperature, &["c", "°c", "celsius"], 1.0),
+    (Dimension::Temperature, &["f", "°f", "fahrenheit"], 1.0),
+    (Dimension::Temperature, &["k", "kelvin"], 1.0),
+];
+
+/// The symbols of data sizes in bytes, which tell bytes from bits by case
+const DATA_SYMBOLS: &[(&str, f64)] = &[
+    ("B", 1.0),
+    ("kB", 1e3),
+    ("KB", 1e3),
+    ("MB", 1e6),
+    ("GB", 1e9),
+    ("TB", 1e12),
+    ("KiB", 1024.0),
+    ("MiB", 1_048_576.0),
+    ("GiB", 1_073_741_824.0),
+    ("TiB", 1_099_511_627_776.0),
+    ("b", 0.125),
+    ("kb", 125.0),
+    ("Kb", 125.0),
+    ("kbit", 125.0),
+    ("Mb", 125_000.0),
+    ("Mbit", 125_000.0),
+    ("Gb", 1.25e8),
+    ("Gbit", 1.25e8),
+    ("Tb", 1.25e11),
+    ("Tbit", 1.25e11),
+];
+
+/// Converts values between units of length, mass, volume, area, time, speed, data size and
+/// temperature
+pub struct UnitConversion;
+
+#[async_trait]
+impl Tool for UnitConversion {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "convert_units".to_string(),
+            description: "Convert a value between units of length, mass, volume, area, time, \
+                          speed, data size or temperature, such as km to mi or °F to °C. Data size \
+                          symbols are case-sensitive: MB are megabytes, Mb megabits."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "value": { "type": "number", "description": "The value to convert" },
+                    "from": { "type": "string", "description": "The unit of the value, such as kg" },
+                    "to": { "type": "string", "description": "The unit to convert to, such as lb" },
+                },
+                "required": ["value", "from", "to"],
+            }),
+        }
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> Result<String, String> {
+        let value = arguments
+            .get("value")
+            .and_then(Value::as_f64)
+            .ok_or("the argument `value` is missing or not a number")?;
+        let from_name = string_argument(&arguments, "from")?;
+        let to_name = string_argument(&arguments, "to")?;
+        let converted = convert(value, from_name, to_name)?;
+        Ok(format!(
+            "{} {} = {} {}",
+            value, from_name, converted, to_name
+        ))
+    }
+}
+
+/// Convert a value between units, rounded to 10 significant digits
+fn convert(value: f64, from_name: &str, to_name: &str) -> Result<f64, String> {
+    let (from_dimension, from_factor) = unit(from_name)?;
+    let (to_dimension, to_factor) = unit(to_name)?;
+    if from_dimension != to_dimension {
+        return Err(format!(
+            "cannot convert {} to {}, they measure different things",
+            from_name, to_name
+        ));
+    }
+
+    let converted = if from_dimension == Dimension::Temperature {
+        from_kelvin(to_kelvin(value, from_name), to_name)
+    } else {
+        value * from_factor / to_factor
+    };
+    Ok(round(converted))
+}
+
+/// Bring a unit name into the form the unit table uses
+fn normalize(name: &str) -> String {
+    let normalized = name.trim().to_lowercase();
+    match normalized.strip_prefix("degrees ") {
+        Some(rest) => rest.to_string(),
+        None => normalized,
+    }
+}
+
+/// Look up a unit by any of its names
+///
+/// Data size symbols are case-sensitive, other names are not.
+fn unit(name: &str) -> Result<(Dimension, f64), String> {
+    let symbol = name.trim();
+    if let Some(&(_, factor)) = DATA_SYMBOLS.iter().find(|&&(other, _)| other == symbol) {
+        return Ok((Dimension::Data, factor));
+    }
+
+    let normalized = normalize(name);
+    UNITS
+        .iter()
+        .find(|(_, names, _)| names.contains(&normalized.as_str()))
+        .map(|&(dimension, _, factor)| (dimension, factor))
+        .ok_or_else(|| {
+            if DATA_SYMBOLS
+                .iter()
+                .any(|(other, _)| other.eq_ignore_ascii_case(symbol))
+            {
+                format!(
+                    "unknown unit `{}`, data size symbols are case-sensitive: MB are \
+                     megabytes, Mb megabits",
+                    name
+                )
+            } else {
+                format!("unknown unit `{}`", name)
+            }
+        })
+}
+
+/// The first letter of a temperature unit: `c`, `f` or `k`
+fn temperature_scale(name: &str) -> char {
+    normalize(name)
+        .trim_start_matches('°')
+        .chars()
+        .next()
+        .unwrap_or_default()
+}
+
+fn to_kelvin(value: f64, unit: &str) -> f64 {
+    match temperature_scale(unit) {
+        'c' => value + 273.15,
+        'f' => (value - 32.0) * 5.0 / 9.0 + 273.15,
+        _ => value,
+    }
+}
+
+fn from_kelvin(value: f64, unit: &str) -> f64 {
+    match temperature_scale(unit) {
+        'c' => value - 273.15,
+        'f' => (value - 273.15) * 9.0 / 5.0 + 32.0,
+        _ => value,
+    }
+}
+
+/// Round away the floating point noise of conversions
+fn round(value: f64) -> f64 {
+    if value == 0.0 || !value.is_finite() {
+        return value;
+    }
+    // Keep 10 significant digits
+    let magnitude = 10f64.powi(9 - value.abs().log10().floor() as i32);
+    (value * magnitude).round() / magnitude
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_by_factor() {
+        assert_eq!(convert(1.0, "km", "m"), Ok(1000.0));
+        assert_eq!(convert(1.0, "mi", "km"), Ok(1.609344));
+        assert_eq!(convert(2.0, "Pounds", "kg"), Ok(0.90718474));
+        assert_eq!(convert(90.0, "min", "h"), Ok(1.5));
+    }
+
+    #[test]
+    fn converts_temperatures_with_their_offsets() {
+        assert_eq!(convert(100.0, "°C", "°F"), Ok(212.0));
+        assert_eq!(convert(32.0, "F", "C"), Ok(0.0));
+        assert_eq!(convert(-40.0, "fahrenheit", "celsius"), Ok(-40.0));
+        assert_eq!(convert(0.0, "K", "C"), Ok(-273.15));
+        assert_eq!(convert(25.0, "Degrees Celsius", "kelvin"), Ok(298.15));
+    }
+
+    #[test]
+    fn tells_bytes_from_bits_by_case() {
+        assert_eq!(convert(1.0, "B", "b"), Ok(8.0));
+        assert_eq!(convert(1.0, "MB", "Mb"), Ok(8.0));
+        assert_eq!(convert(100.0, "Mbit", "MB"), Ok(12.5));
+        assert_eq!(convert(1.0, "KiB", "B"), Ok(1024.0));
+        assert_eq!(convert(1.0, "Megabyte", "kilobytes"), Ok(1000.0));
+    }
+
+    #[test]
+    fn rejects_ambiguous_data_symbols() {
+        let error = convert(1.0, "mb", "kB").unwrap_err();
+        assert!(error.contains("case-sensitive"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unknown_units() {
+        assert_eq!(
+            convert(1.0, "parsec", "m"),
+            Err("unknown unit `parsec`".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_units_of_different_dimensions() {
+        assert!(convert(1.0, "kg", "m").is_err());
+        assert!(convert(1.0, "C", "m").is_err());
+    }
+
+    #[test]
+    fn rounds_away_floating_point_noise() {
+        assert_eq!(round(0.1 + 0.2), 0.3);
+        assert_eq!(round(0.0), 0.0);
+        assert_eq!(round(123_456_789_012.0), 123_456_789_000.0);
+    }
+}
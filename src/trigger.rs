@@ -0,0 +1,75 @@
+//! When the bot answers a message
+//!
+//! Whether a message is passed to the LLM depends on the type of chat it was sent in: in
+//! one-to-one chats every message is meant for the bot, while in groups it should only speak
+//! when addressed.
+
+use std::env;
+use std::str::FromStr;
+
+use teloxide::types::Chat;
+
+/// The kind of messages the bot answers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Every text message that is not a command
+    Always,
+    /// Messages that mention the bot or reply to one of its messages
+    MentionOrReply,
+    /// Only messages that mention the bot
+    Mention,
+    /// No messages at all
+    Never,
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "mention_or_reply" => Ok(Self::MentionOrReply),
+            "mention" => Ok(Self::Mention),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown trigger `{}`, expected always, mention_or_reply, mention or never",
+                value
+            )),
+        }
+    }
+}
+
+/// The trigger used for each type of chat
+#[derive(Clone, Copy, Debug)]
+pub struct TriggerPolicy {
+    /// Trigger for one-to-one chats with the bot
+    pub private: Trigger,
+    /// Trigger for groups and supergroups
+    pub group: Trigger,
+}
+
+impl TriggerPolicy {
+    /// Read the policy from `PRIVATE_CHAT_TRIGGER` and `GROUP_CHAT_TRIGGER`
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: Trigger| match env::var(name) {
+            Ok(value) => value
+                .parse()
+                .unwrap_or_else(|error| panic!("Invalid {}: {}", name, error)),
+            Err(_) => default,
+        };
+
+        Self {
+            private: read("PRIVATE_CHAT_TRIGGER", Trigger::Always),
+            group: read("GROUP_CHAT_TRIGGER", Trigger::MentionOrReply),
+        }
+    }
+
+    /// Get the trigger for a chat
+    pub fn for_chat(&self, chat: &Chat) -> Trigger {
+        if chat.is_private() {
+            self.private
+        } else {
+            self.group
+        }
+    }
+}
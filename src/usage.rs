@@ -0,0 +1,174 @@
+//! Spending limits and usage reports
+//!
+//! The tokens of every request are counted per day, chat, user and model, along with their
+//! price by the model's `prompt_price` and `completion_price`. Budgets cap the tokens or the
+//! cost a user, across all chats, or a chat, across all its users, may spend per day and per
+//! month (in UTC): `USER_DAILY_TOKEN_BUDGET`, `USER_MONTHLY_TOKEN_BUDGET`,
+//! `USER_DAILY_COST_BUDGET` and `USER_MONTHLY_COST_BUDGET`, and the same with `CHAT_` for
+//! chats. Requests beyond a budget are turned down until it renews; the bot's administrators
+//! have no budget. `/usage` shows what was spent.
+
+use std::env;
+
+use teloxide::types::{ChatId, UserId};
+
+use crate::storage::{Storage, StorageResult, UsagePeriod, UsageScope, UsageTotals};
+
+/// The most tokens and the highest cost allowed in a period, unlimited where not set
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub tokens: Option<u64>,
+    pub cost: Option<f64>,
+}
+
+impl Budget {
+    /// Read the budget from `<prefix>_TOKEN_BUDGET` and `<prefix>_COST_BUDGET`
+    fn from_env(prefix: &str) -> Self {
+        Self {
+            tokens: env::var(format!("{}_TOKEN_BUDGET", prefix))
+                .ok()
+                .and_then(|value| value.parse().ok()),
+            cost: env::var(format!("{}_COST_BUDGET", prefix))
+                .ok()
+                .and_then(|value| value.parse().ok()),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.tokens.is_none() && self.cost.is_none()
+    }
+
+    /// Check if usage has used up the budget
+    fn is_spent(&self, usage: &UsageTotals) -> bool {
+        self.tokens
+            .is_some_and(|tokens| usage.prompt_tokens + usage.completion_tokens >= tokens)
+            || self.cost.is_some_and(|cost| usage.cost >= cost)
+    }
+}
+
+/// A budget that is used up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    /// Whether it is the budget of the user, rather than of the chat
+    pub user: bool,
+    pub period: UsagePeriod,
+}
+
+/// The daily and monthly budgets of users and chats
+#[derive(Clone, Copy, Debug)]
+pub struct Budgets {
+    pub user_daily: Budget,
+    pub user_monthly: Budget,
+    pub chat_daily: Budget,
+    pub chat_monthly: Budget,
+}
+
+impl Budgets {
+    /// Read the budgets from the environment
+    pub fn from_env() -> Self {
+        Self {
+            user_daily: Budget::from_env("USER_DAILY"),
+            user_monthly: Budget::from_env("USER_MONTHLY"),
+            chat_daily: Budget::from_env("CHAT_DAILY"),
+            chat_monthly: Budget::from_env("CHAT_MONTHLY"),
+        }
+    }
+
+    /// The budget of a user or chat for a period
+    pub fn budget(&self, scope: UsageScope, period: UsagePeriod) -> Budget {
+        match (scope, period) {
+            (UsageScope::User(_), UsagePeriod::Today) => self.user_daily,
+            (UsageScope::User(_), UsagePeriod::ThisMonth) => self.user_monthly,
+            (UsageScope::Chat(_), UsagePeriod::Today) => self.chat_daily,
+            (UsageScope::Chat(_), UsagePeriod::ThisMonth) => self.chat_monthly,
+        }
+    }
+
+    /// Find a budget of the user or the chat that is used up, if any
+    pub fn check(
+        &self,
+        storage: &dyn Storage,
+        chat_id: ChatId,
+        user_id: Option<UserId>,
+    ) -> StorageResult<Option<BudgetExceeded>> {
+        let scopes = user_id
+            .map(UsageScope::User)
+            .into_iter()
+            .chain([UsageScope::Chat(chat_id)]);
+        for scope in scopes {
+            for period in [UsagePeriod::Today, UsagePeriod::ThisMonth] {
+                let budget = self.budget(scope, period);
+                if budget.is_unlimited() {
+                    continue;
+                }
+                if budget.is_spent(&total(&storage.usage(scope, period)?)) {
+                    return Ok(Some(BudgetExceeded {
+                        user: matches!(scope, UsageScope::User(_)),
+                        period,
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Sum up the usage of several models
+pub fn total(usage: &[UsageTotals]) -> UsageTotals {
+    usage
+        .iter()
+        .fold(UsageTotals::default(), |total, model| UsageTotals {
+            model: String::new(),
+            requests: total.requests + model.requests,
+            prompt_tokens: total.prompt_tokens + model.prompt_tokens,
+            completion_tokens: total.completion_tokens + model.completion_tokens,
+            cost: total.cost + model.cost,
+        })
+}
+
+/// Describe the usage of a user or chat over a period, with what is left of its budget
+pub fn report(
+    storage: &dyn Storage,
+    budgets: &Budgets,
+    scope: UsageScope,
+    period: UsagePeriod,
+) -> StorageResult<String> {
+    let usage = storage.usage(scope, period)?;
+    let sum = total(&usage);
+    let mut lines = vec![format!(
+        "{} requests, {} tokens, ${:.4}",
+        sum.requests,
+        sum.prompt_tokens + sum.completion_tokens,
+        sum.cost
+    )];
+
+    let budget = budgets.budget(scope, period);
+    if let Some(tokens) = budget.tokens {
+        let used = sum.prompt_tokens + sum.completion_tokens;
+        lines.push(format!(
+            "  {} of {} tokens left",
+            tokens.saturating_sub(used),
+            tokens
+        ));
+    }
+    if let Some(cost) = budget.cost {
+        lines.push(format!(
+            "  ${:.4} of ${:.4} left",
+            (cost - sum.cost).max(0.0),
+            cost
+        ));
+    }
+    // Break the usage down by model where there are several
+    if usage.len() > 1 {
+        for model in &usage {
+            lines.push(format!(
+                "  • {}: {} requests, {} tokens, ${:.4}",
+                model.model,
+                model.requests,
+                model.prompt_tokens + model.completion_tokens,
+                model.cost
+            ));
+        }
+    }
+    Ok(lines.join("\n"))
+}